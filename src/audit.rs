@@ -0,0 +1,338 @@
+// Copyright 2017-2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Every item that remove_file() removes (or moves to the trash) is appended to
+// $CARGO_HOME/.cargo-cache-audit.log, one tab separated line per item:
+// time (rfc3339, utc), action, size, component, crate name, version, path, command line
+// "cargo cache log" prints the log, optionally filtered by date or crate.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+use crate::date::parse_date;
+use crate::library::{CargoCachePaths, Error};
+use crate::remove::parse_version;
+use crate::tables::format_table;
+
+use chrono::prelude::*;
+use humansize::{file_size_opts, FileSize};
+
+/// what happened to a removed item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+    Remove, // deleted for good
+    Trash,  // moved to the trash (--trash)
+}
+
+impl Action {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Remove => "remove",
+            Self::Trash => "trash",
+        }
+    }
+}
+
+/// a single line of the audit log
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LogEntry {
+    time: DateTime<Utc>,
+    action: String,
+    size: u64,
+    component: String,
+    name: String,
+    version: String,
+    path: PathBuf,
+    command: String,
+}
+
+fn log_path(cargo_home: &Path) -> PathBuf {
+    cargo_home.join(".cargo-cache-audit.log")
+}
+
+// tabs and newlines would break the format
+fn sanitize(field: &str) -> String {
+    field.replace(&['\t', '\n', '\r'][..], " ")
+}
+
+// the cache component ("remove-dir" names) an item inside the CARGO_HOME belongs to
+fn component_of(relative: &Path) -> &'static str {
+    let mut components = relative.components().filter_map(|c| match c {
+        Component::Normal(name) => name.to_str(),
+        _ => None,
+    });
+    match (components.next(), components.next()) {
+        (Some("bin"), _) => "bin",
+        (Some("git"), Some("db")) => "git-db",
+        (Some("git"), Some("checkouts")) => "git-repos",
+        (Some("registry"), Some("cache")) => "registry-crate-cache",
+        (Some("registry"), Some("src")) => "registry-sources",
+        (Some("registry"), Some("index")) => "registry-index",
+        _ => "",
+    }
+}
+
+// name and version of the crate (or name and revision of the git checkout) if the item is one
+fn crate_of(relative: &Path, component: &str) -> (String, String) {
+    let parts: Vec<String> = relative
+        .iter()
+        .map(|part| part.to_string_lossy().to_string())
+        .collect();
+    match (component, parts.len()) {
+        // registry/cache/<registry>/<crate>-<version>.crate
+        ("registry-crate-cache" | "registry-sources", 4) => {
+            parse_version(relative).unwrap_or_default()
+        }
+        // git/checkouts/<repo>-<hash>/<revision>, git/db/<repo>-<hash>
+        ("git-repos" | "git-db", 3..=4) => {
+            let repo = &parts[2];
+            let name = repo
+                .rsplit_once('-')
+                .map_or(repo.as_str(), |(name, _)| name);
+            (name.to_string(), parts.get(3).cloned().unwrap_or_default())
+        }
+        _ => (String::new(), String::new()),
+    }
+}
+
+impl LogEntry {
+    fn new(cargo_home: &Path, path: &Path, action: Action, size: u64) -> Self {
+        let relative = path.strip_prefix(cargo_home).unwrap_or(path);
+        let component = component_of(relative);
+        let (name, version) = crate_of(relative, component);
+        Self {
+            time: Utc::now(),
+            action: action.name().to_string(),
+            size,
+            component: component.to_string(),
+            name,
+            version,
+            path: path.to_path_buf(),
+            command: std::env::args().collect::<Vec<String>>().join(" "),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        let fields = [
+            self.time.to_rfc3339_opts(SecondsFormat::Secs, true),
+            self.action.clone(),
+            self.size.to_string(),
+            self.component.clone(),
+            self.name.clone(),
+            self.version.clone(),
+            self.path.display().to_string(),
+            self.command.clone(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| sanitize(field)).collect();
+        fields.join("\t")
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.split('\t');
+        let mut next = || fields.next().map(ToString::to_string);
+        Some(Self {
+            time: DateTime::parse_from_rfc3339(&next()?).ok()?.into(),
+            action: next()?,
+            size: next()?.parse().ok()?,
+            component: next()?,
+            name: next()?,
+            version: next()?,
+            path: PathBuf::from(next()?),
+            command: next()?,
+        })
+    }
+}
+
+/// append a removed item to the audit log, only warns if the log can't be written
+pub(crate) fn record(cargo_home: &Path, path: &Path, action: Action, size: u64) {
+    let entry = LogEntry::new(cargo_home, path, action, size);
+    let log = log_path(cargo_home);
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log)
+        .and_then(|mut file| writeln!(file, "{}", entry.to_line()));
+
+    if let Err(error) = written {
+        eprintln!(
+            "Warning: failed to write to the audit log \"{}\": {}",
+            log.display(),
+            error
+        );
+    }
+}
+
+// entries between "since" and "until" (local time) of the crate (if given)
+fn filter_entries<'a>(
+    entries: &'a [LogEntry],
+    since: Option<NaiveDateTime>,
+    until: Option<NaiveDateTime>,
+    krate: Option<&str>,
+) -> Vec<&'a LogEntry> {
+    entries
+        .iter()
+        .filter(|entry| {
+            let time = entry.time.with_timezone(&Local).naive_local();
+            since.map_or(true, |since| time >= since)
+                && until.map_or(true, |until| time <= until)
+                && krate.map_or(true, |krate| entry.name == krate)
+        })
+        .collect()
+}
+
+fn entries_to_string(entries: &[&LogEntry]) -> String {
+    let mut table_matrix: Vec<Vec<String>> = Vec::with_capacity(entries.len() + 1);
+    table_matrix.push(
+        [
+            "Time",
+            "Action",
+            "Component",
+            "Crate",
+            "Version",
+            "Size",
+            "Path",
+            "Command",
+        ]
+        .iter()
+        .map(|title| (*title).to_string())
+        .collect(),
+    );
+
+    for entry in entries {
+        table_matrix.push(vec![
+            entry
+                .time
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            entry.action.clone(),
+            entry.component.clone(),
+            entry.name.clone(),
+            entry.version.clone(),
+            entry.size.file_size(file_size_opts::DECIMAL).unwrap(),
+            entry.path.display().to_string(),
+            entry.command.clone(),
+        ]);
+    }
+
+    let total_size: u64 = entries.iter().map(|entry| entry.size).sum();
+    format!(
+        "{}\n{} items, {} total\n",
+        format_table(&table_matrix, 0),
+        entries.len(),
+        total_size.file_size(file_size_opts::DECIMAL).unwrap()
+    )
+}
+
+/// print the audit log ("cargo cache log")
+pub(crate) fn print_log(
+    ccd: &CargoCachePaths,
+    since: Option<&str>,
+    until: Option<&str>,
+    krate: Option<&str>,
+) -> Result<(), Error> {
+    let since = since.map(parse_date).transpose()?;
+    let until = until.map(parse_date).transpose()?;
+
+    let log = log_path(&ccd.cargo_home);
+    // nothing was removed yet
+    let content = fs::read_to_string(&log).unwrap_or_default();
+    let entries: Vec<LogEntry> = content.lines().filter_map(LogEntry::from_line).collect();
+
+    let matches = filter_entries(&entries, since, until, krate);
+    if matches.is_empty() {
+        println!("Nothing matching was removed.");
+    } else {
+        print!("{}", entries_to_string(&matches));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod audittests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn entry(path: &str, time: DateTime<Utc>) -> LogEntry {
+        let mut entry = LogEntry::new(Path::new("/cargo"), Path::new(path), Action::Remove, 42);
+        entry.time = time;
+        entry.command = String::from("cargo-cache trim --limit 1G");
+        entry
+    }
+
+    #[test]
+    fn audit_entry_fields() {
+        let time = Utc.ymd(2020, 6, 15).and_hms(12, 0, 0);
+
+        let archive = entry(
+            "/cargo/registry/cache/github.com-1ecc6299db9ec823/foo-bar-0.10.0.crate",
+            time,
+        );
+        assert_eq!(archive.component, "registry-crate-cache");
+        assert_eq!(archive.name, "foo-bar");
+        assert_eq!(archive.version, "0.10.0");
+
+        let checkout = entry(
+            "/cargo/git/checkouts/cargo-cache-16826c8e13331adc/ae9ea4a",
+            time,
+        );
+        assert_eq!(checkout.component, "git-repos");
+        assert_eq!(checkout.name, "cargo-cache");
+        assert_eq!(checkout.version, "ae9ea4a");
+
+        // whole directories (--autoclean, --remove-dir) have no crate
+        let sources = entry("/cargo/registry/src", time);
+        assert_eq!(sources.component, "registry-sources");
+        assert_eq!(sources.name, "");
+
+        let line = archive.to_line();
+        assert_eq!(
+            line,
+            "2020-06-15T12:00:00Z\tremove\t42\tregistry-crate-cache\tfoo-bar\t0.10.0\t\
+            /cargo/registry/cache/github.com-1ecc6299db9ec823/foo-bar-0.10.0.crate\t\
+            cargo-cache trim --limit 1G"
+        );
+        assert_eq!(LogEntry::from_line(&line), Some(archive));
+        assert_eq!(LogEntry::from_line("garbage"), None);
+    }
+
+    #[test]
+    fn audit_filter_entries() {
+        let old = Utc.ymd(2019, 3, 14).and_hms(12, 0, 0);
+        let new = Utc.ymd(2020, 6, 15).and_hms(12, 0, 0);
+        let entries = vec![
+            entry(
+                "/cargo/registry/cache/github.com-1ecc6299db9ec823/foo-1.0.0.crate",
+                old,
+            ),
+            entry(
+                "/cargo/registry/src/github.com-1ecc6299db9ec823/foo-1.0.0",
+                new,
+            ),
+            entry(
+                "/cargo/registry/cache/github.com-1ecc6299db9ec823/bar-1.0.0.crate",
+                new,
+            ),
+        ];
+        let between = |from: DateTime<Utc>| Some(from.with_timezone(&Local).naive_local());
+
+        assert_eq!(filter_entries(&entries, None, None, None).len(), 3);
+        assert_eq!(filter_entries(&entries, None, None, Some("foo")).len(), 2);
+        assert_eq!(filter_entries(&entries, between(new), None, None).len(), 2);
+        assert_eq!(
+            filter_entries(&entries, between(new), None, Some("foo")),
+            vec![&entries[1]]
+        );
+        assert_eq!(
+            filter_entries(&entries, None, between(old), None),
+            vec![&entries[0]]
+        );
+    }
+}
@@ -7,10 +7,10 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fs;
 use std::path::PathBuf;
 
 use crate::cache::caches::Cache;
+use crate::library::{is_file, read_dir_paths, size_of_file};
 
 use rayon::iter::*;
 
@@ -67,15 +67,7 @@ impl Cache for BinaryCache {
         if let Some(total_size) = self.total_size {
             total_size
         } else if self.path().is_dir() {
-            let total_size = self
-                .files()
-                .par_iter()
-                .map(|f| {
-                    fs::metadata(f)
-                        .unwrap_or_else(|_| panic!("Failed to get size of file: '{:?}'", f))
-                        .len()
-                })
-                .sum();
+            let total_size = self.files().par_iter().map(|f| size_of_file(f)).sum();
             self.total_size = Some(total_size);
             total_size
         } else {
@@ -88,10 +80,9 @@ impl Cache for BinaryCache {
         if self.files_calculated {
             // do nothing and return
         } else {
-            self.files = fs::read_dir(&self.path())
-                .unwrap_or_else(|_| panic!("Failed to read directory: '{:?}'", &self.path))
-                .map(|f| f.unwrap().path())
-                .filter(|f| f.is_file())
+            self.files = read_dir_paths(self.path())
+                .into_iter()
+                .filter(|f| is_file(f))
                 .collect::<Vec<PathBuf>>();
             self.files_calculated = true;
         }
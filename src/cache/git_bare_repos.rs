@@ -7,13 +7,12 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fs;
 use std::path::PathBuf;
 
 use crate::cache::caches::Cache;
+use crate::library::{read_dir_paths, size_of_file, walk_files};
 
 use rayon::prelude::*;
-use walkdir::WalkDir;
 
 pub(crate) struct GitRepoCache {
     path: PathBuf,
@@ -67,12 +66,7 @@ impl Cache for GitRepoCache {
             total_size
         } else if self.path.is_dir() {
             // get the size of all files in path dir
-            let total_size = self
-                .files()
-                .par_iter()
-                .filter(|f| f.is_file())
-                .map(|f| fs::metadata(f).unwrap().len())
-                .sum();
+            let total_size = self.files().par_iter().map(|f| size_of_file(f)).sum();
             self.total_size = Some(total_size);
             total_size
         } else {
@@ -86,13 +80,7 @@ impl Cache for GitRepoCache {
         if self.files_calculated {
             // do nothing and return
         } else if self.path_exists() {
-            let walkdir = WalkDir::new(self.path.display().to_string());
-            let v = walkdir
-                .into_iter()
-                .map(|d| d.unwrap().into_path())
-                .filter(|d| d.is_file())
-                .collect::<Vec<PathBuf>>();
-            self.files = v;
+            self.files = walk_files(&self.path);
         } else {
             self.known_to_be_empty();
         }
@@ -110,9 +98,8 @@ impl Cache for GitRepoCache {
         if self.items_calculated {
             // do nothing and return
         } else if self.path_exists() {
-            let repo_list = fs::read_dir(&self.path)
-                .unwrap_or_else(|_| panic!("Failed to read directory: '{:?}'", &self.path))
-                .map(|cratepath| cratepath.unwrap().path())
+            let repo_list = read_dir_paths(&self.path)
+                .into_iter()
                 .filter(|p| p.is_dir())
                 .collect::<Vec<PathBuf>>();
 
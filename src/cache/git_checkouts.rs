@@ -7,13 +7,12 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fs;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 use crate::cache::caches::Cache;
-
-use rayon::prelude::*;
-use walkdir::WalkDir;
+use crate::cache::inventory;
+use crate::library::{cumulative_dir_size, read_dir_paths, walk_dir};
 
 pub(crate) struct GitCheckoutCache {
     path: PathBuf,
@@ -48,6 +47,8 @@ impl Cache for GitCheckoutCache {
         self.files_calculated = false;
         self.items_calculated = false;
         self.number_of_items = None;
+        // our removals updated the size index, write it out
+        inventory::save_index();
     }
 
     fn known_to_be_empty(&mut self) {
@@ -55,6 +56,7 @@ impl Cache for GitCheckoutCache {
         self.files_calculated = true;
         self.number_of_items = Some(0);
         self.items_calculated = true;
+        inventory::save_index();
     }
 
     fn total_size(&mut self) -> u64 {
@@ -66,15 +68,7 @@ impl Cache for GitCheckoutCache {
             total_size
         } else if self.path.is_dir() {
             // get the size of all files in path dir
-            let total_size = self
-                .files()
-                .par_iter()
-                .map(|f| {
-                    fs::metadata(f)
-                        .unwrap_or_else(|_| panic!("Failed to read size of file: '{:?}'", f))
-                        .len()
-                })
-                .sum();
+            let total_size = cumulative_dir_size(&self.path).dir_size;
             self.total_size = Some(total_size);
             total_size
         } else {
@@ -87,13 +81,7 @@ impl Cache for GitCheckoutCache {
         if self.files_calculated {
             // do nothing and return
         } else if self.path_exists() {
-            let walkdir = WalkDir::new(self.path.display().to_string());
-            let v = walkdir
-                .into_iter()
-                .map(|d| d.unwrap().into_path())
-                .filter(|f| f.exists())
-                .collect::<Vec<PathBuf>>();
-            self.files = v;
+            self.files = walk_dir(&self.path);
         } else {
             // if there is no such directory, we know the cache is empty
             self.total_size = Some(0);
@@ -119,19 +107,14 @@ impl Cache for GitCheckoutCache {
         } else if self.path_exists() {
             let mut collection = Vec::new();
 
-            let crate_list = fs::read_dir(&self.path)
-                .unwrap_or_else(|_| panic!("Failed to read directory: '{:?}'", &self.path))
-                .map(|cratepath| cratepath.unwrap().path())
+            let crate_list = read_dir_paths(&self.path)
+                .into_iter()
                 .filter(|p| p.is_dir())
                 .collect::<Vec<PathBuf>>();
             // need to take 2 levels into account
             let mut both_levels_vec: Vec<PathBuf> = Vec::new();
             for repo in crate_list {
-                for i in fs::read_dir(&repo)
-                    .unwrap_or_else(|_| panic!("Failed to read directory: '{:?}'", &repo))
-                    .map(|cratepath| cratepath.unwrap().path())
-                    .filter(|f| f.is_dir())
-                {
+                for i in read_dir_paths(&repo).into_iter().filter(|f| f.is_dir()) {
                     both_levels_vec.push(i);
                 }
             }
@@ -162,4 +145,15 @@ impl GitCheckoutCache {
         self.items.sort();
         &self.items
     }
+
+    /// the checked out revisions grouped by the repository they belong to
+    /// checkouts/cargo-e7ff1db891893a9e => [checkouts/cargo-e7ff1db891893a9e/258c896, ...]
+    pub(crate) fn revisions_by_repo(&mut self) -> BTreeMap<PathBuf, Vec<PathBuf>> {
+        let mut repos: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        for revision in self.items() {
+            let repo = revision.parent().unwrap().to_path_buf();
+            repos.entry(repo).or_default().push(revision.clone());
+        }
+        repos
+    }
 }
@@ -0,0 +1,524 @@
+// Copyright 2017-2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The inventory is the result of one parallel scan of the CARGO_HOME: every file and directory
+// together with its metadata. The caches, the top items, trim, query etc. all read sizes and
+// access times through the helpers in library.rs (size_of_file(), walk_dir(), last_access() ...)
+// which answer from the inventory if the path is in it and only go to the disk otherwise.
+// Anything that changes the CARGO_HOME (removing, trashing, git gc) has to rescan() the changed path.
+// Items that did not change since the last run are not scanned at all, only their sizes are taken
+// from the size index (see size_index.rs), everything below them is read from the disk when needed.
+
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use crate::cache::size_index::SizeIndex;
+use crate::library::record_scan_error;
+
+use lazy_static::lazy_static;
+use rayon::prelude::*;
+
+/// metadata of a file or directory we scanned
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FileInfo {
+    /// size in bytes, symlinks are followed
+    pub(crate) size: u64,
+    pub(crate) is_dir: bool,
+    pub(crate) modified: Option<SystemTime>,
+    pub(crate) accessed: Option<SystemTime>,
+    pub(crate) inode: u64,
+}
+
+impl FileInfo {
+    fn new(metadata: &Metadata) -> Self {
+        Self {
+            size: metadata.len(),
+            is_dir: metadata.is_dir(),
+            modified: metadata.modified().ok(),
+            accessed: metadata.accessed().ok(),
+            inode: inode(metadata),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> u64 {
+    0
+}
+
+/// the sizes of a directory and everything below it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Totals {
+    /// size of all files and directories, including the directory itself
+    pub(crate) size: u64,
+    /// size of the files only
+    pub(crate) file_size: u64,
+}
+
+impl Totals {
+    fn add(&mut self, other: Self) {
+        self.size += other.size;
+        self.file_size += other.file_size;
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    info: FileInfo,
+    /// the paths directly inside this directory, sorted
+    children: Vec<PathBuf>,
+    /// the sizes from the size index if the directory was not scanned because it didn't change
+    summary: Option<Totals>,
+}
+
+impl Node {
+    fn new(info: FileInfo, children: Vec<PathBuf>) -> Self {
+        Self {
+            info,
+            children,
+            summary: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inventory {
+    /// the directory that was scanned
+    root: Option<PathBuf>,
+    /// the root and everything below it
+    nodes: HashMap<PathBuf, Node>,
+    /// the sizes of the items below the root
+    index: SizeIndex,
+}
+
+impl Inventory {
+    // collect path and everything below it, depth first
+    // false if some directory below was not scanned
+    fn walk(&self, path: &Path, entries: &mut Vec<(PathBuf, FileInfo)>) -> bool {
+        match self.nodes.get(path) {
+            Some(node) if node.summary.is_some() => false,
+            Some(node) => {
+                entries.push((path.to_path_buf(), node.info));
+                node.children.iter().all(|child| self.walk(child, entries))
+            }
+            None => true,
+        }
+    }
+
+    fn totals(&self, path: &Path) -> Totals {
+        match self.nodes.get(path) {
+            Some(Node {
+                summary: Some(summary),
+                ..
+            }) => *summary,
+            Some(node) => {
+                let mut totals = Totals {
+                    size: node.info.size,
+                    file_size: if node.info.is_dir { 0 } else { node.info.size },
+                };
+                for child in &node.children {
+                    totals.add(self.totals(child));
+                }
+                totals
+            }
+            None => Totals::default(),
+        }
+    }
+
+    // the extracted crate sources and git checkouts: registry/src/*/* and git/checkouts/*/*
+    fn items(&self, root: &Path) -> Vec<PathBuf> {
+        let children = |dir: &Path| {
+            self.nodes
+                .get(dir)
+                .map_or_else(Vec::new, |node| node.children.clone())
+        };
+        let mut items = Vec::new();
+        for base in &["registry/src", "git/checkouts"] {
+            for dir in children(&root.join(base)) {
+                items.extend(children(&dir));
+            }
+        }
+        items
+    }
+
+    // put the sizes of the (finished) items at or below path into the size index
+    fn index_items(&mut self, root: &Path, below: &Path) {
+        for item in self.items(root) {
+            if !item.starts_with(below) {
+                continue;
+            }
+            let node = &self.nodes[&item];
+            // cargo writes .cargo-ok once the item is completely extracted / checked out
+            let finished = node.summary.is_some()
+                || node
+                    .children
+                    .iter()
+                    .any(|child| child.file_name() == Some(".cargo-ok".as_ref()));
+            if node.info.is_dir && finished {
+                let info = node.info;
+                let totals = self.totals(&item);
+                self.index.insert(item, &info, totals);
+            }
+        }
+    }
+
+    // forget path and everything below it
+    fn remove(&mut self, path: &Path) {
+        if let Some(node) = self.nodes.remove(path) {
+            for child in node.children {
+                self.remove(&child);
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref INVENTORY: RwLock<Inventory> = RwLock::new(Inventory::default());
+}
+
+// metadata of a path, symlinks are followed but we remember that it was one
+// None if it vanished or is a broken symlink
+fn stat(path: &Path, metadata: io::Result<Metadata>) -> Option<(FileInfo, bool)> {
+    match metadata {
+        Ok(metadata) if metadata.file_type().is_symlink() => fs::metadata(path)
+            .ok()
+            .map(|target| (FileInfo::new(&target), true)),
+        Ok(metadata) => Some((FileInfo::new(&metadata), false)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+        Err(error) => {
+            record_scan_error(path, error);
+            None
+        }
+    }
+}
+
+// everything inside a directory, the subdirectories are scanned in parallel
+// like WalkDir, we don't descend into symlinked directories or items that are in the size index
+// returns the sorted direct children and the entries of everything below
+fn scan_dir(dir: &Path, index: &SizeIndex) -> (Vec<PathBuf>, Vec<(PathBuf, Node)>) {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(error) => {
+            record_scan_error(dir, error);
+            return (Vec::new(), Vec::new());
+        }
+    };
+
+    let mut children = Vec::new();
+    let mut entries = Vec::new();
+    let mut subdirs = Vec::new();
+    for entry in read_dir {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                record_scan_error(dir, error);
+                continue;
+            }
+        };
+        let path = entry.path();
+        let (info, is_symlink) = match stat(&path, entry.metadata()) {
+            Some(stat) => stat,
+            None => continue,
+        };
+        children.push((entry.file_name(), path.clone()));
+        if info.is_dir && !is_symlink {
+            subdirs.push((path, info));
+        } else {
+            entries.push((path, Node::new(info, Vec::new())));
+        }
+    }
+    // all children have the same parent, comparing the names is a lot faster than comparing paths
+    children.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+    let children = children.into_iter().map(|(_, path)| path).collect();
+
+    entries.par_extend(subdirs.into_par_iter().flat_map(|(path, info)| {
+        if let Some(totals) = index.lookup(&path, &info) {
+            let node = Node {
+                info,
+                children: Vec::new(),
+                summary: Some(totals),
+            };
+            return vec![(path, node)];
+        }
+        let (subdir_children, mut below) = scan_dir(&path, index);
+        below.push((path, Node::new(info, subdir_children)));
+        below
+    }));
+    (children, entries)
+}
+
+// a path and everything below it
+fn scan_path(path: &Path, index: &SizeIndex) -> Vec<(PathBuf, Node)> {
+    let (info, is_symlink) = match stat(path, fs::symlink_metadata(path)) {
+        Some(stat) => stat,
+        None => return Vec::new(),
+    };
+    let (children, mut entries) = if info.is_dir && !is_symlink {
+        scan_dir(path, index)
+    } else {
+        (Vec::new(), Vec::new())
+    };
+    entries.push((path.to_path_buf(), Node::new(info, children)));
+    entries
+}
+
+/// scan `root` (the `CARGO_HOME`) once, afterwards everything below it is read from the inventory
+pub(crate) fn scan(root: &Path) {
+    let previous = SizeIndex::load(root);
+    let nodes = scan_path(root, &previous).into_iter().collect();
+    let mut inventory = Inventory {
+        root: Some(root.to_path_buf()),
+        nodes,
+        index: SizeIndex::default(),
+    };
+    // items that vanished are dropped from the index, new or changed ones are added
+    inventory.index_items(root, root);
+    inventory.index.compare(&previous);
+    inventory.index.save(root);
+    *INVENTORY.write().unwrap() = inventory;
+}
+
+/// forget what we know about `path` and scan it again, call this after changing anything inside the root
+pub(crate) fn rescan(path: &Path) {
+    let (root, path) = {
+        let inventory = INVENTORY.read().unwrap();
+        let root = match &inventory.root {
+            Some(root) if path.starts_with(root) => root.clone(),
+            _ => return,
+        };
+        // we don't know anything below an item that was not scanned, rescan all of it
+        let path = path
+            .ancestors()
+            .take_while(|dir| dir.starts_with(&root))
+            .find(|dir| {
+                inventory
+                    .nodes
+                    .get(*dir)
+                    .map_or(false, |node| node.summary.is_some())
+            })
+            .unwrap_or(path)
+            .to_path_buf();
+        (root, path)
+    };
+    let path = path.as_path();
+
+    let entries = scan_path(path, &SizeIndex::default());
+    // the directories above have changed as well or were just created
+    let mut parents = Vec::new();
+    for dir in path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(&root))
+    {
+        match fs::metadata(dir) {
+            Ok(metadata) => parents.push((dir.to_path_buf(), FileInfo::new(&metadata))),
+            Err(_) => break,
+        }
+    }
+
+    let mut inventory = INVENTORY.write().unwrap();
+    inventory.remove(path);
+    let mut child_exists = !entries.is_empty();
+    inventory.nodes.extend(entries);
+
+    let mut child = path.to_path_buf();
+    for (dir, info) in parents {
+        let node = inventory
+            .nodes
+            .entry(dir.clone())
+            .or_insert_with(|| Node::new(info, Vec::new()));
+        node.info = info;
+        match node
+            .children
+            .binary_search_by(|other| other.file_name().cmp(&child.file_name()))
+        {
+            Ok(index) if !child_exists => {
+                let _ = node.children.remove(index);
+            }
+            Err(index) if child_exists => node.children.insert(index, child),
+            _ => {}
+        }
+        child = dir;
+        child_exists = true;
+    }
+
+    // the sizes of the changed items are outdated
+    inventory.index.forget(path);
+    inventory.index_items(&root, path);
+}
+
+/// write the size index if our own changes made it outdated
+pub(crate) fn save_index() {
+    let mut inventory = INVENTORY.write().unwrap();
+    if let Some(root) = inventory.root.clone() {
+        inventory.index.save(&root);
+    }
+}
+
+/// the metadata of `path`, None if it is not in the inventory
+pub(crate) fn get(path: &Path) -> Option<FileInfo> {
+    INVENTORY
+        .read()
+        .unwrap()
+        .nodes
+        .get(path)
+        .map(|node| node.info)
+}
+
+/// `dir` and everything below it, None if `dir` is not in the inventory or only partially
+pub(crate) fn walk(dir: &Path) -> Option<Vec<(PathBuf, FileInfo)>> {
+    let inventory = INVENTORY.read().unwrap();
+    if !inventory.nodes.contains_key(dir) {
+        return None;
+    }
+    let mut entries = Vec::new();
+    if inventory.walk(dir, &mut entries) {
+        Some(entries)
+    } else {
+        None
+    }
+}
+
+/// the direct children of `dir`, None if `dir` is not in the inventory or was not scanned
+pub(crate) fn children(dir: &Path) -> Option<Vec<PathBuf>> {
+    INVENTORY
+        .read()
+        .unwrap()
+        .nodes
+        .get(dir)
+        .filter(|node| node.summary.is_none())
+        .map(|node| node.children.clone())
+}
+
+/// the sizes of `dir` and everything below it, None if `dir` is not in the inventory
+pub(crate) fn totals(dir: &Path) -> Option<Totals> {
+    let inventory = INVENTORY.read().unwrap();
+    if inventory.nodes.contains_key(dir) {
+        Some(inventory.totals(dir))
+    } else {
+        None
+    }
+}
+
+// the inventory is global, the tests must not scan at the same time
+#[cfg(test)]
+lazy_static! {
+    pub(crate) static ref SCANNING: std::sync::Mutex<()> = std::sync::Mutex::new(());
+}
+
+#[cfg(test)]
+mod inventory_tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn inventory_scan_and_rescan() {
+        let _scanning = SCANNING.lock().unwrap();
+        let root = PathBuf::from("target/inventory_test");
+        let _ = fs::remove_dir_all(&root);
+        let src = root.join("registry/src/github.com-1ecc6299db9ec823");
+        let foo = src.join("foo-1.0.0");
+        fs::create_dir_all(&foo).unwrap();
+        fs::write(foo.join("lib.rs"), "fn foo() {}").unwrap();
+        // not inside of foo-1.0.0 although the name starts with it
+        fs::write(src.join("foo-1.0.0-lib.rs"), "").unwrap();
+
+        scan(&root);
+
+        let lib = get(&foo.join("lib.rs")).unwrap();
+        assert_eq!(lib.size, 11);
+        assert!(!lib.is_dir);
+        assert!(get(&src).unwrap().is_dir);
+        assert_eq!(walk(&root).unwrap().len(), 7);
+        let below_foo: Vec<PathBuf> = walk(&foo).unwrap().into_iter().map(|(p, _)| p).collect();
+        assert_eq!(below_foo, vec![foo.clone(), foo.join("lib.rs")]);
+        assert_eq!(
+            children(&src).unwrap(),
+            vec![foo.clone(), src.join("foo-1.0.0-lib.rs")]
+        );
+        assert!(walk(&root.join("not/scanned")).is_none());
+
+        // nothing changes until the path is rescanned
+        fs::remove_dir_all(&foo).unwrap();
+        assert!(get(&foo).is_some());
+        rescan(&foo);
+        assert!(get(&foo).is_none());
+        assert!(get(&foo.join("lib.rs")).is_none());
+        assert_eq!(children(&src).unwrap(), vec![src.join("foo-1.0.0-lib.rs")]);
+
+        // new directories are added together with their parents
+        let bar = root.join("git/checkouts/bar-123456/abcdef");
+        fs::create_dir_all(&bar).unwrap();
+        rescan(&bar);
+        assert!(get(&root.join("git/checkouts")).unwrap().is_dir);
+        assert_eq!(
+            children(&root.join("git")).unwrap(),
+            vec![root.join("git/checkouts")]
+        );
+    }
+
+    #[test]
+    fn inventory_size_index() {
+        let _scanning = SCANNING.lock().unwrap();
+        let root = PathBuf::from("target/inventory_size_index_test");
+        let _ = fs::remove_dir_all(&root);
+        let src = root.join("registry/src/github.com-1ecc6299db9ec823");
+        let foo = src.join("foo-1.0.0");
+        let unfinished = src.join("bar-1.0.0");
+        fs::create_dir_all(foo.join("src")).unwrap();
+        fs::create_dir_all(&unfinished).unwrap();
+        fs::write(foo.join("src/lib.rs"), "fn foo() {}").unwrap();
+        fs::write(foo.join(".cargo-ok"), "").unwrap();
+        let dir_sizes =
+            fs::metadata(&foo).unwrap().len() + fs::metadata(foo.join("src")).unwrap().len();
+
+        scan(&root);
+        let index = SizeIndex::load(&root);
+        assert!(index.lookup(&foo, &get(&foo).unwrap()).is_some());
+        // still being extracted
+        assert!(index
+            .lookup(&unfinished, &get(&unfinished).unwrap())
+            .is_none());
+
+        // a change below the item directory goes unnoticed, only the sizes from the index are used
+        fs::write(foo.join("src/lib.rs"), "fn foo() { bar() }").unwrap();
+        scan(&root);
+        assert_eq!(totals(&foo).unwrap().file_size, 11);
+        assert_eq!(totals(&foo).unwrap().size, dir_sizes + 11);
+        assert!(walk(&foo).is_none());
+        assert!(children(&foo).is_none());
+        assert!(get(&foo.join("src/lib.rs")).is_none());
+
+        // rescanning the item (after we changed it) updates the sizes and the index
+        rescan(&foo.join("src/lib.rs"));
+        assert_eq!(totals(&foo).unwrap().file_size, 18);
+        assert_eq!(walk(&foo).unwrap().len(), 4);
+        save_index();
+        let updated = SizeIndex::load(&root);
+        assert_eq!(
+            updated.lookup(&foo, &get(&foo).unwrap()).unwrap().file_size,
+            18
+        );
+
+        fs::remove_dir_all(&foo).unwrap();
+        rescan(&foo);
+        save_index();
+        assert_eq!(SizeIndex::load(&root), SizeIndex::default());
+    }
+}
@@ -11,9 +11,11 @@ pub(crate) mod bin;
 pub(crate) mod caches;
 pub(crate) mod git_bare_repos;
 pub(crate) mod git_checkouts;
+pub(crate) mod inventory;
 pub(crate) mod registry_index;
 pub(crate) mod registry_pkg_cache;
 pub(crate) mod registry_sources;
+pub(crate) mod size_index;
 
 // The idea of this module is to be a sort of cache
 // once a value is first asked for, we calculate the value, save it and return it
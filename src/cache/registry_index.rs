@@ -7,13 +7,12 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fs;
 use std::path::PathBuf;
 
 use crate::cache::caches::{get_cache_name, RegistrySubCache, RegistrySuperCache};
+use crate::library::{is_file, read_dir_paths, size_of_file, walk_dir};
 
 use rayon::iter::*;
-use walkdir::WalkDir;
 
 /// describes a single index of a crate registry index
 pub(crate) struct RegistryIndex {
@@ -84,12 +83,8 @@ impl RegistrySubCache for RegistryIndex {
                     let total_size = self
                         .files()
                         .par_iter()
-                        .filter(|f| f.is_file())
-                        .map(|f| {
-                            fs::metadata(f)
-                                .unwrap_or_else(|_| panic!("Failed to get size of file: '{:?}'", f))
-                                .len()
-                        })
+                        .filter(|f| is_file(f))
+                        .map(|f| size_of_file(f))
                         .sum();
                     self.size = Some(total_size);
                     total_size
@@ -106,11 +101,7 @@ impl RegistrySubCache for RegistryIndex {
         if self.files_calculated {
             // do nothing and return
         } else if self.path_exists() {
-            let walkdir = WalkDir::new(self.path.display().to_string());
-            let vec = walkdir
-                .into_iter()
-                .map(|direntry| direntry.unwrap().into_path())
-                .collect::<Vec<PathBuf>>();
+            let vec = walk_dir(&self.path);
 
             self.number_of_files = Some(vec.len());
 
@@ -193,12 +184,11 @@ impl RegistrySuperCache for RegistryIndicesCache {
             };
         }
 
-        let indices_dirs = std::fs::read_dir(&path)
-            .unwrap_or_else(|_| panic!("failed to read directory {}", path.display()));
+        let indices_dirs = read_dir_paths(&path);
         // map the dirs to RegistryIndexCaches and return them as vector
         #[allow(clippy::manual_filter_map)]
         let indices = indices_dirs
-            .map(|direntry| direntry.unwrap().path())
+            .into_iter()
             .filter(|p| p.is_dir() && p.file_name().unwrap().to_str().unwrap().contains('-'))
             //.inspect(|p| println!("p: {:?}", p))
             .map(RegistryIndex::new)
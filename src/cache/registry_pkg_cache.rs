@@ -7,10 +7,10 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fs;
 use std::path::PathBuf;
 
 use crate::cache::caches::{get_cache_name, RegistrySubCache, RegistrySuperCache};
+use crate::library::{is_file, read_dir_paths, size_of_file};
 
 use rayon::prelude::*;
 
@@ -77,12 +77,8 @@ impl RegistrySubCache for RegistryPkgCache {
                     let total_size = self
                         .files()
                         .par_iter()
-                        .filter(|f| f.is_file())
-                        .map(|f| {
-                            fs::metadata(f)
-                                .unwrap_or_else(|_| panic!("Failed to get size of file: '{:?}'", f))
-                                .len()
-                        })
+                        .filter(|f| is_file(f))
+                        .map(|f| size_of_file(f))
                         .sum();
                     self.size = Some(total_size);
                     total_size
@@ -99,10 +95,7 @@ impl RegistrySubCache for RegistryPkgCache {
         if self.files_calculated {
             // just return
         } else if self.path_exists() {
-            let collection = fs::read_dir(&self.path)
-                .unwrap_or_else(|_| panic!("Failed to read directory (repo): '{:?}'", &self.path))
-                .map(|cratepath| cratepath.unwrap().path())
-                .collect::<Vec<_>>();
+            let collection = read_dir_paths(&self.path);
 
             self.files_calculated = true;
             self.number_of_files = Some(collection.len());
@@ -183,12 +176,11 @@ impl RegistrySuperCache for RegistryPkgCaches {
             };
         }
 
-        let cache_dirs = std::fs::read_dir(&path)
-            .unwrap_or_else(|_| panic!("failed to read directory {}", path.display()));
+        let cache_dirs = read_dir_paths(&path);
         // map the dirs to RegistryIndexCaches and return them as vector
         #[allow(clippy::manual_filter_map)]
         let caches = cache_dirs
-            .map(|direntry| direntry.unwrap().path())
+            .into_iter()
             .filter(|p| p.is_dir() && p.file_name().unwrap().to_str().unwrap().contains('-'))
             //.inspect(|p| println!("p: {:?}", p))
             .map(RegistryPkgCache::new)
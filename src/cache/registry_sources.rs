@@ -7,13 +7,11 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fs;
 use std::path::PathBuf;
 
 use crate::cache::caches::{get_cache_name, RegistrySubCache, RegistrySuperCache};
-
-use rayon::prelude::*;
-use walkdir::WalkDir;
+use crate::cache::inventory;
+use crate::library::{read_dir_paths, size_of_files, walk_files};
 
 #[derive(Debug, Clone)]
 /// describes one registry source cache (extracted .crates)
@@ -68,6 +66,8 @@ impl RegistrySubCache for RegistrySourceCache {
         self.files = vec![];
         self.items_calculated = false;
         self.items = vec![];
+        // our removals updated the size index, write it out
+        inventory::save_index();
     }
 
     fn known_to_be_empty(&mut self) {
@@ -77,6 +77,7 @@ impl RegistrySubCache for RegistrySourceCache {
         self.files = Vec::new();
         self.items_calculated = true;
         self.items = Vec::new();
+        inventory::save_index();
     }
 
     fn files(&mut self) -> &[PathBuf] {
@@ -84,13 +85,7 @@ impl RegistrySubCache for RegistrySourceCache {
             // do nothing as everything is already calculated
         }
         if self.path_exists() {
-            let walkdir = WalkDir::new(self.path.display().to_string());
-            let v = walkdir
-                .into_iter()
-                .map(|d| d.unwrap().into_path())
-                .filter(|d| d.is_file())
-                .collect::<Vec<PathBuf>>();
-            self.files = v;
+            self.files = walk_files(&self.path);
         } else {
             self.known_to_be_empty();
         }
@@ -102,12 +97,7 @@ impl RegistrySubCache for RegistrySourceCache {
             return size;
         } else if self.path.is_dir() {
             // get the size of all files in path dir
-            let size = self
-                .files()
-                .par_iter()
-                .filter(|f| f.is_file())
-                .map(|f| fs::metadata(f).unwrap().len())
-                .sum();
+            let size = size_of_files(&self.path);
             self.size = Some(size);
         } else {
             self.known_to_be_empty();
@@ -147,9 +137,8 @@ impl RegistrySubCache for RegistrySourceCache {
             self.items_calculated = true;
         } else {
             // calculate the items
-            let folders = std::fs::read_dir(&self.path)
-                .unwrap_or_else(|_| panic!("Failed to read {:?}", self.path.display()))
-                .map(|direntry| direntry.unwrap().path())
+            let folders = read_dir_paths(&self.path)
+                .into_iter()
                 .filter(|p| p.is_dir() && p.file_name().unwrap().to_str().unwrap().contains('-'))
                 .collect::<Vec<PathBuf>>();
             self.items = folders;
@@ -206,11 +195,10 @@ impl RegistrySuperCache for RegistrySourceCaches {
             };
         }
 
-        let registries = std::fs::read_dir(&path)
-            .unwrap_or_else(|_| panic!("failed to read directory {}", path.display()));
+        let registries = read_dir_paths(&path);
         #[allow(clippy::manual_filter_map)]
         let registry_folders = registries
-            .map(|direntry| direntry.unwrap().path())
+            .into_iter()
             .filter(|p| p.is_dir() && p.file_name().unwrap().to_str().unwrap().contains('-'))
             .map(RegistrySourceCache::new)
             .collect::<Vec<RegistrySourceCache>>();
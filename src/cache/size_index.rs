@@ -0,0 +1,195 @@
+// Copyright 2017-2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The size index remembers the sizes of the extracted crate sources and git checkouts
+// ($CARGO_HOME/registry/src/<registry>/<crate> and $CARGO_HOME/git/checkouts/<repo>/<revision>)
+// in $CARGO_HOME/.cargo-cache-index, keyed by the mtime and inode of the item directory.
+// Cargo never touches these items again once it has written their .cargo-ok file, so if the key
+// still matches, the inventory scan takes the sizes from the index instead of descending into the item.
+// One tab separated line per item: mtime (seconds.nanoseconds), inode, size, size of the files, path
+// relative to the CARGO_HOME.
+// The index is only a cache: if it can't be read or written, we simply scan everything.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cache::inventory::{FileInfo, Totals};
+
+const HEADER: &str = "cargo-cache size index 1";
+
+/// the sizes of an item together with the key they are valid for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Record {
+    modified: SystemTime,
+    inode: u64,
+    totals: Totals,
+}
+
+impl Record {
+    fn to_line(self, relative: &Path) -> Option<String> {
+        let modified = self.modified.duration_since(UNIX_EPOCH).ok()?;
+        Some(format!(
+            "{}.{:09}\t{}\t{}\t{}\t{}",
+            modified.as_secs(),
+            modified.subsec_nanos(),
+            self.inode,
+            self.totals.size,
+            self.totals.file_size,
+            relative.to_str()?
+        ))
+    }
+
+    fn from_line(line: &str) -> Option<(PathBuf, Self)> {
+        let mut fields = line.splitn(5, '\t');
+        let (secs, nanos) = fields.next()?.split_once('.')?;
+        let modified = UNIX_EPOCH + Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+        let inode = fields.next()?.parse().ok()?;
+        let size = fields.next()?.parse().ok()?;
+        let file_size = fields.next()?.parse().ok()?;
+        let relative = PathBuf::from(fields.next()?);
+        Some((
+            relative,
+            Self {
+                modified,
+                inode,
+                totals: Totals { size, file_size },
+            },
+        ))
+    }
+}
+
+/// the recorded sizes of the items, by path
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct SizeIndex {
+    records: HashMap<PathBuf, Record>,
+    /// the records changed since the index was read or written
+    dirty: bool,
+}
+
+fn index_path(root: &Path) -> PathBuf {
+    root.join(".cargo-cache-index")
+}
+
+impl SizeIndex {
+    /// read the index of the `CARGO_HOME` `root`, empty if there is none (yet)
+    pub(crate) fn load(root: &Path) -> Self {
+        let content = fs::read_to_string(index_path(root)).unwrap_or_default();
+        let mut lines = content.lines();
+        let records = if lines.next() == Some(HEADER) {
+            lines
+                .filter_map(Record::from_line)
+                .map(|(relative, record)| (root.join(relative), record))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        Self {
+            records,
+            dirty: false,
+        }
+    }
+
+    /// write the index if it changed, a read-only `CARGO_HOME` just doesn't get one
+    pub(crate) fn save(&mut self, root: &Path) {
+        if !self.dirty {
+            return;
+        }
+        let mut lines: Vec<String> = self
+            .records
+            .iter()
+            .filter_map(|(path, record)| record.to_line(path.strip_prefix(root).ok()?))
+            .collect();
+        lines.sort();
+        let mut content = String::from(HEADER);
+        content.push('\n');
+        for line in lines {
+            content.push_str(&line);
+            content.push('\n');
+        }
+        // write a temporary file first so that nobody reads half an index
+        let index = index_path(root);
+        let temporary = index.with_extension("tmp");
+        if fs::write(&temporary, content)
+            .and_then(|()| fs::rename(&temporary, &index))
+            .is_ok()
+        {
+            self.dirty = false;
+        } else {
+            let _ = fs::remove_file(&temporary);
+        }
+    }
+
+    /// the index only needs to be written if it differs from the `previous` one
+    pub(crate) fn compare(&mut self, previous: &Self) {
+        self.dirty = self.records != previous.records;
+    }
+
+    /// the sizes of the item at `path` if it didn't change since they were recorded
+    pub(crate) fn lookup(&self, path: &Path, info: &FileInfo) -> Option<Totals> {
+        self.records
+            .get(path)
+            .filter(|record| Some(record.modified) == info.modified && record.inode == info.inode)
+            .map(|record| record.totals)
+    }
+
+    /// record the sizes of the item at `path`
+    pub(crate) fn insert(&mut self, path: PathBuf, info: &FileInfo, totals: Totals) {
+        if let Some(modified) = info.modified {
+            let record = Record {
+                modified,
+                inode: info.inode,
+                totals,
+            };
+            if self.records.insert(path, record) != Some(record) {
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// forget the items at or below `path` and the item `path` is inside of
+    pub(crate) fn forget(&mut self, path: &Path) {
+        let before = self.records.len();
+        self.records
+            .retain(|item, _| !item.starts_with(path) && !path.starts_with(item));
+        if self.records.len() != before {
+            self.dirty = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod size_index_tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn size_index_record_line() {
+        let record = Record {
+            modified: UNIX_EPOCH + Duration::new(1_600_000_000, 42),
+            inode: 1234,
+            totals: Totals {
+                size: 4096 + 11,
+                file_size: 11,
+            },
+        };
+        let relative = Path::new("registry/src/github.com-1ecc6299db9ec823/foo-1.0.0");
+        let line = record.to_line(relative).unwrap();
+        assert_eq!(
+            line,
+            "1600000000.000000042\t1234\t4107\t11\tregistry/src/github.com-1ecc6299db9ec823/foo-1.0.0"
+        );
+        assert_eq!(
+            Record::from_line(&line),
+            Some((relative.to_path_buf(), record))
+        );
+        assert_eq!(Record::from_line("garbage"), None);
+    }
+}
@@ -0,0 +1,233 @@
+// Copyright 2017-2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// the "ci-autoclean" feature
+// this is a very stripped-down version of cargo-cache which has minimal external dependencies and should
+// compile within a couple of seconds in order to be used on CI to clean the cargo-home for caching on CI-cache (travis/azure etc)
+// It removes the extracted sources and git checkouts like "cargo cache --autoclean" and, if there is a Cargo.lock
+// in the current directory or above, also the .crate archives and git repos the lockfile does not reference.
+// We have neither cargo_metadata nor a toml parser here, so the lockfile is parsed by the hand-written parser
+// of the lockfile module.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::lockfile;
+
+#[derive(Debug, Clone)]
+struct CargoCachePaths {
+    /// the root of the cache
+    cargo_home: PathBuf,
+
+    /// path where registry sources (.rs files / extracted .crate archives) are stored
+    registry_sources: PathBuf,
+
+    /// path where the .crate archives are stored
+    registry_pkg_cache: PathBuf,
+
+    /// bare git repositories are stored here
+    git_repos_bare: PathBuf,
+
+    /// git repository checkouts are stored here
+    git_checkouts: PathBuf,
+}
+
+impl CargoCachePaths {
+    /// returns `CargoCachePaths` object which makes all the subpaths accessible to the crate
+    pub(crate) fn default() -> Self {
+        let cargo_home = if let Ok(cargo_home) = home::cargo_home() {
+            cargo_home
+        } else {
+            std::process::exit(1);
+        };
+
+        if !cargo_home.is_dir() {
+            std::process::exit(1);
+        }
+        // get the paths to the relevant directories
+        let registry = cargo_home.join("registry");
+        let reg_src = registry.join("src");
+        let reg_cache = registry.join("cache");
+        let git_repos_bare = cargo_home.join("git").join("db");
+        let git_checkouts = cargo_home.join("git").join("checkouts");
+
+        Self {
+            cargo_home,
+            registry_sources: reg_src,
+            registry_pkg_cache: reg_cache,
+            git_repos_bare,
+            git_checkouts,
+        }
+    }
+} // impl CargoCachePaths
+
+fn remove_file(path: &Path) {
+    if path.is_file() && fs::remove_file(path).is_err() {
+        eprintln!("Warning: failed to remove file \"{}\".", path.display());
+    }
+
+    if path.is_dir() && remove_dir_all::remove_dir_all(path).is_err() {
+        eprintln!(
+            "Warning: failed to recursively remove directory \"{}\".",
+            path.display()
+        );
+    }
+}
+
+/// the registry and git packages of a Cargo.lock
+#[derive(Debug, Default, PartialEq, Eq)]
+struct LockedPackages {
+    /// "name-version.crate", the file name of the archive inside registry/cache/<registry>/
+    crate_archives: HashSet<String>,
+    /// the (lowercase) name of the repo, git/db/<name>-<hash>
+    git_repos: HashSet<String>,
+}
+
+impl LockedPackages {
+    fn from_lockfile(lockfile: &str) -> Self {
+        let mut locked = Self::default();
+        for package in lockfile::packages(lockfile) {
+            if package.is_registry() {
+                let _ = locked.crate_archives.insert(package.archive_name());
+            } else if let Some(repo) = package.git_repo_name() {
+                let _ = locked.git_repos.insert(repo);
+            }
+        }
+        locked
+    }
+}
+
+fn read_dir_paths(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// remove the .crate archives and git repos that are not referenced by the lockfile
+fn remove_unreferenced(cargo_cache: &CargoCachePaths, locked: &LockedPackages) {
+    let archives = read_dir_paths(&cargo_cache.registry_pkg_cache)
+        .into_iter()
+        .flat_map(|registry| read_dir_paths(&registry))
+        .filter(|archive| archive.extension().map_or(false, |ext| ext == "crate"))
+        .filter(|archive| {
+            archive
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map_or(false, |name| !locked.crate_archives.contains(name))
+        });
+
+    // git/db/cargo-e7ff1db891893a9e
+    let repos = read_dir_paths(&cargo_cache.git_repos_bare)
+        .into_iter()
+        .filter(|repo| {
+            repo.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.rsplit_once('-'))
+                .map_or(false, |(name, _hash)| {
+                    !locked.git_repos.contains(&name.to_lowercase())
+                })
+        });
+
+    for path in archives.chain(repos) {
+        remove_file(&path);
+    }
+}
+
+/// the size of all files below `path`
+fn dir_size(path: &Path) -> u64 {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => read_dir_paths(path)
+            .iter()
+            .map(|child| dir_size(child))
+            .sum(),
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    }
+}
+
+// we don't have humansize either
+#[allow(clippy::cast_precision_loss)]
+fn human_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < units.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", size, units[unit])
+    }
+}
+
+pub(crate) fn run() {
+    let cargo_cache = CargoCachePaths::default();
+
+    // don't remove anything while cargo is extracting crates, same lock as the full cargo-cache
+    let package_cache_lock = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(cargo_cache.cargo_home.join(".package-cache"));
+    if let Ok(lock) = &package_cache_lock {
+        if fs2::FileExt::try_lock_exclusive(lock).is_err() {
+            eprintln!("Blocking waiting for file lock on package cache");
+            let _ = fs2::FileExt::lock_exclusive(lock);
+        }
+    }
+
+    let size_before = dir_size(&cargo_cache.cargo_home);
+
+    println!("cargo-cache: running \"cargo cache --autoclean\"");
+
+    let reg_srcs = &cargo_cache.registry_sources;
+    let git_checkouts = &cargo_cache.git_checkouts;
+    for dir in &[reg_srcs, git_checkouts] {
+        if dir.is_dir() {
+            remove_file(dir);
+        }
+    }
+
+    match std::env::current_dir()
+        .ok()
+        .and_then(|cwd| lockfile::lockfile_above(&cwd))
+    {
+        Some(lockfile) => match fs::read_to_string(&lockfile) {
+            Ok(content) => {
+                println!(
+                    "cargo-cache: removing crates and git repos not referenced by \"{}\"",
+                    lockfile.display()
+                );
+                remove_unreferenced(&cargo_cache, &LockedPackages::from_lockfile(&content));
+            }
+            Err(error) => eprintln!(
+                "Warning: failed to read \"{}\": {}, keeping all crates and git repos",
+                lockfile.display(),
+                error
+            ),
+        },
+        None => println!("cargo-cache: no Cargo.lock found, keeping all crates and git repos"),
+    }
+
+    println!(
+        "cargo-cache: size of \"{}\": {} => {}",
+        cargo_cache.cargo_home.display(),
+        human_size(size_before),
+        human_size(dir_size(&cargo_cache.cargo_home))
+    );
+}
@@ -19,8 +19,9 @@ use crate::cache::caches::*;
 use crate::cache::*;
 use crate::library::*;
 use crate::library::{CargoCachePaths, Error};
+use crate::lock::{LockMode, PackageCacheLock};
 use crate::remove::*;
-use cargo_metadata::{CargoOpt, MetadataCommand};
+use cargo_metadata::{CargoOpt, MetadataCommand, Package};
 
 // the source of a crate inside the cargo cache can be represented in form of
 // an extracted .crate or a checked out git repository
@@ -74,11 +75,23 @@ fn find_crate_name_crate(toml_path: &Path, cargo_home: &Path) -> Option<SourceKi
     Some(SourceKind::Crate(path))
 }
 
+/// all packages the crate of the `manifest` depends on (with all features enabled)
+pub(crate) fn resolve_dependencies(manifest: &Path) -> Result<Vec<Package>, Error> {
+    let metadata = MetadataCommand::new()
+        .manifest_path(manifest)
+        .features(CargoOpt::AllFeatures)
+        .exec()
+        .map_err(|e| Error::UnparsableManifest(manifest.to_path_buf(), e))?;
+
+    Ok(metadata.packages)
+}
+
 /// look at a crate manifest and remove all items from the cargo cache that are not referenced, also run --autoclean and invalidate caches
 #[allow(clippy::too_many_arguments)]
 pub(crate) fn clean_unref(
     cargo_cache_paths: &CargoCachePaths,
     manifest_path: Option<&str>,
+    lock_mode: LockMode,
     bin_cache: &mut bin::BinaryCache,
     checkouts_cache: &mut git_checkouts::GitCheckoutCache,
     bare_repos_cache: &mut git_bare_repos::GitRepoCache,
@@ -106,13 +119,14 @@ pub(crate) fn clean_unref(
         None => crate::local::get_manifest()?,
     };
 
-    let metadata = MetadataCommand::new()
-        .manifest_path(&manifest)
-        .features(CargoOpt::AllFeatures)
-        .exec()
-        .map_err(|e| Error::UnparsableManifest(manifest, e))?;
+    let dependencies = resolve_dependencies(&manifest)?;
 
-    let dependencies = metadata.packages;
+    // cargo metadata may have to take the package cache lock itself, so only lock it now
+    let _package_cache_lock = if dry_run {
+        None
+    } else {
+        Some(PackageCacheLock::acquire(cargo_home, lock_mode)?)
+    };
 
     // get the path inside the CARGO_HOME of the source of the dependency
     #[allow(clippy::manual_filter_map)]
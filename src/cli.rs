@@ -8,9 +8,16 @@
 // except according to those terms.
 
 /// This file provides the command line interface of the cargo-cache crate
-use clap::{value_t, App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::time::Duration;
 
+use clap::{value_t, App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+
+use crate::commands::trim::parse_size_limit_to_bytes;
+use crate::date::parse_duration;
+use crate::git::GcConfig;
 use crate::library::*;
+use crate::lock::LockMode;
+use crate::remove::{RetentionScope, RevisionOrder};
 use rustc_tools_util::*;
 
 /// cargo-cache can perform these operaitons, but only one at a time
@@ -20,11 +27,18 @@ pub(crate) enum CargoCacheCommands<'a> {
 
     GitGCRepos {
         dry_run: bool,
+        gc_config: GcConfig<'a>,
     },
     Info,
     KeepDuplicateCrates {
         dry_run: bool,
         limit: u64,
+        scope: RetentionScope,
+    },
+    KeepGitRevisions {
+        dry_run: bool,
+        limit: u64,
+        order: RevisionOrder,
     },
     ListDirs,
     RemoveDir {
@@ -35,9 +49,11 @@ pub(crate) enum CargoCacheCommands<'a> {
     },
     AutoCleanExpensive {
         dry_run: bool,
+        gc_config: GcConfig<'a>,
     },
     TopCacheItems {
         limit: u32,
+        group_by_crate: bool,
     },
     //Debug,
     Version,
@@ -54,8 +70,58 @@ pub(crate) enum CargoCacheCommands<'a> {
     Trim {
         dry_run: bool,
         trim_limit: Option<&'a str>,
+        ensure_free: Option<&'a str>,
     }, // subcommand
     Toolchain,  // subcommand
+    Undo,       // subcommand
+    Log {
+        since: Option<&'a str>,
+        until: Option<&'a str>,
+        krate: Option<&'a str>,
+    }, // subcommand
+    EmptyTrash {
+        dry_run: bool,
+        older_than: Option<&'a str>,
+    }, // subcommand
+    Metrics {
+        textfile: Option<&'a str>,
+        listen: Option<&'a str>,
+    }, // subcommand
+    Watch {
+        dry_run: bool,
+        limit: &'a str,
+        interval: Duration,
+    }, // subcommand
+    CiKey {
+        manifest_path: Option<&'a str>,
+        prefix: &'a str,
+        restore_keys: bool,
+    }, // subcommand
+    MigrateRegistry {
+        dry_run: bool,
+        from: &'a str,
+        to: &'a str,
+        hardlink: bool,
+        remove_old: bool,
+    }, // subcommand
+    Doctor {
+        dry_run: bool,
+        fix: bool,
+    }, // subcommand
+    Extract {
+        dry_run: bool,
+        manifest_path: Option<&'a str>,
+    }, // subcommand
+    PruneIndex {
+        dry_run: bool,
+        unused_for: &'a str,
+        lockfiles: Option<&'a str>,
+    }, // subcommand
+    Bin {
+        dry_run: bool,
+        remove: Option<&'a str>,
+        remove_unused_since: Option<&'a str>,
+    }, // subcommand
     RemoveIfDate {
         dry_run: bool,
         arg_younger: Option<&'a str>,
@@ -66,13 +132,57 @@ pub(crate) enum CargoCacheCommands<'a> {
     DefaultSummary,
 }
 
+/// collect the --gc-* settings, terminate on invalid values
+fn gc_config_from_clap<'a>(config: &'a ArgMatches<'_>) -> GcConfig<'a> {
+    let jobs = if config.is_present("gc-jobs") {
+        value_t!(config.value_of("gc-jobs"), usize)
+            .map_err(|e| {
+                format!(
+                    "Error: \"--gc-jobs\" expected an integer argument.\n{}\"",
+                    e
+                )
+            })
+            .unwrap_or_fatal_error()
+    } else {
+        1
+    };
+
+    let memory_limit = config
+        .value_of("gc-memory")
+        .map(|limit| parse_size_limit_to_bytes(Some(limit)).unwrap_or_fatal_error());
+
+    let min_size = config
+        .value_of("gc-min-size")
+        .map(|size| parse_size_limit_to_bytes(Some(size)).unwrap_or_fatal_error());
+
+    let skip_recent = config
+        .value_of("gc-skip-recent")
+        .map(|duration| parse_duration(duration).unwrap_or_fatal_error());
+
+    let only = config
+        .value_of("gc-only")
+        .map(|repos| repos.split(',').collect());
+
+    GcConfig {
+        jobs,
+        memory_limit,
+        min_size,
+        skip_recent,
+        only,
+    }
+}
+
 pub(crate) fn clap_to_enum<'a, 'b>(config: &'b ArgMatches<'a>) -> CargoCacheCommands<'b> {
     let dry_run = config.is_present("dry-run");
 
-    // if no args were passed, or ONLY --debug is passed, print the default summary
-    if (config.args.is_empty() && config.subcommand.is_none())
-        || (config.subcommand.is_none() && config.is_present("debug") && config.args.len() == 1)
-    {
+    // flags that don't select an operation by themselves
+    let modifiers = ["debug", "wait", "no-wait", "trash", "strict", "disk-usage"]
+        .iter()
+        .filter(|arg| config.is_present(arg))
+        .count();
+
+    // if no args were passed, or ONLY --debug (or --wait, --trash, --strict, --disk-usage) is passed, print the default summary
+    if config.subcommand.is_none() && config.args.len() == modifiers {
         return CargoCacheCommands::DefaultSummary;
     }
 
@@ -84,11 +194,81 @@ pub(crate) fn clap_to_enum<'a, 'b>(config: &'b ArgMatches<'a>) -> CargoCacheComm
         CargoCacheCommands::SCCache
     } else if config.subcommand_matches("toolchain").is_some() {
         CargoCacheCommands::Toolchain
+    } else if let Some(log_config) = config.subcommand_matches("log") {
+        CargoCacheCommands::Log {
+            since: log_config.value_of("since"),
+            until: log_config.value_of("until"),
+            krate: log_config.value_of("crate"),
+        }
+    } else if let Some(metrics_config) = config.subcommand_matches("metrics") {
+        CargoCacheCommands::Metrics {
+            textfile: metrics_config.value_of("textfile"),
+            listen: metrics_config.value_of("listen"),
+        }
+    } else if let Some(watch_config) = config.subcommand_matches("watch") {
+        let interval = watch_config
+            .value_of("interval")
+            .map_or(Duration::from_secs(60), |interval| {
+                parse_duration(interval).unwrap_or_fatal_error()
+            });
+        CargoCacheCommands::Watch {
+            dry_run: dry_run || watch_config.is_present("dry-run"),
+            // required by clap
+            limit: watch_config.value_of("trim_limit").unwrap(),
+            interval,
+        }
+    } else if let Some(ci_key_config) = config.subcommand_matches("ci-key") {
+        CargoCacheCommands::CiKey {
+            manifest_path: ci_key_config.value_of("manifest-path"),
+            // has a default value
+            prefix: ci_key_config.value_of("prefix").unwrap(),
+            restore_keys: ci_key_config.is_present("restore-keys"),
+        }
+    } else if let Some(migrate_config) = config.subcommand_matches("migrate-registry") {
+        CargoCacheCommands::MigrateRegistry {
+            dry_run: dry_run || migrate_config.is_present("dry-run"),
+            // both are required by clap
+            from: migrate_config.value_of("FROM").unwrap(),
+            to: migrate_config.value_of("TO").unwrap(),
+            hardlink: migrate_config.is_present("hardlink"),
+            remove_old: migrate_config.is_present("remove-old"),
+        }
+    } else if let Some(doctor_config) = config.subcommand_matches("doctor") {
+        CargoCacheCommands::Doctor {
+            dry_run: dry_run || doctor_config.is_present("dry-run"),
+            fix: doctor_config.is_present("fix"),
+        }
+    } else if let Some(extract_config) = config.subcommand_matches("extract") {
+        CargoCacheCommands::Extract {
+            dry_run: dry_run || extract_config.is_present("dry-run"),
+            manifest_path: extract_config.value_of("manifest-path"),
+        }
+    } else if let Some(prune_index_config) = config.subcommand_matches("prune-index") {
+        CargoCacheCommands::PruneIndex {
+            dry_run: dry_run || prune_index_config.is_present("dry-run"),
+            // has a default value
+            unused_for: prune_index_config.value_of("unused-for").unwrap(),
+            lockfiles: prune_index_config.value_of("lockfiles"),
+        }
+    } else if let Some(bin_config) = config.subcommand_matches("bin") {
+        CargoCacheCommands::Bin {
+            dry_run: dry_run || bin_config.is_present("dry-run"),
+            remove: bin_config.value_of("remove"),
+            remove_unused_since: bin_config.value_of("remove-unused-since"),
+        }
+    } else if config.subcommand_matches("undo").is_some() {
+        CargoCacheCommands::Undo
+    } else if let Some(empty_trash_config) = config.subcommand_matches("empty-trash") {
+        CargoCacheCommands::EmptyTrash {
+            dry_run: dry_run || empty_trash_config.is_present("dry-run"),
+            older_than: empty_trash_config.value_of("older-than"),
+        }
     } else if let Some(trimconfig) = config.subcommand_matches("trim") {
         let trim_dry_run = dry_run || trimconfig.is_present("dry-run");
         CargoCacheCommands::Trim {
             dry_run: trim_dry_run,
             trim_limit: trimconfig.value_of("trim_limit"),
+            ensure_free: trimconfig.value_of("ensure-free"),
         } // take config trim_config.value_of("trim_limit")
     } else if let Some(clean_unref_config) = config.subcommand_matches("clean-unref") {
         let arg_dry_run = dry_run || clean_unref_config.is_present("dry-run");
@@ -99,7 +279,10 @@ pub(crate) fn clap_to_enum<'a, 'b>(config: &'b ArgMatches<'a>) -> CargoCacheComm
     } else if config.is_present("top-cache-items") {
         let limit =
             value_t!(config.value_of("top-cache-items"), u32).unwrap_or(20 /* default*/);
-        CargoCacheCommands::TopCacheItems { limit }
+        CargoCacheCommands::TopCacheItems {
+            limit,
+            group_by_crate: config.is_present("group-by-crate"),
+        }
     } else if config.is_present("query") || config.is_present("q") {
         let query_config = if config.is_present("query") {
             config.subcommand_matches("query").unwrap()
@@ -119,11 +302,17 @@ pub(crate) fn clap_to_enum<'a, 'b>(config: &'b ArgMatches<'a>) -> CargoCacheComm
     {
         // if we pass both --gc and --autoclean-expensive, we want autoclean-expensive to run
         // since is already includes --gc
-        CargoCacheCommands::AutoCleanExpensive { dry_run }
+        CargoCacheCommands::AutoCleanExpensive {
+            dry_run,
+            gc_config: gc_config_from_clap(config),
+        }
     } else if config.is_present("fsck-repos") {
         CargoCacheCommands::FSCKRepos
     } else if config.is_present("gc-repos") {
-        CargoCacheCommands::GitGCRepos { dry_run }
+        CargoCacheCommands::GitGCRepos {
+            dry_run,
+            gc_config: gc_config_from_clap(config),
+        }
     } else if config.is_present("autoclean") {
         CargoCacheCommands::AutoClean { dry_run }
     } else if config.is_present("keep-duplicate-crates") {
@@ -136,7 +325,34 @@ pub(crate) fn clap_to_enum<'a, 'b>(config: &'b ArgMatches<'a>) -> CargoCacheComm
                 )
             })
             .unwrap_or_fatal_error();
-        CargoCacheCommands::KeepDuplicateCrates { dry_run, limit }
+        let scope = match config.value_of("keep-per") {
+            Some("major") => RetentionScope::Major,
+            _ => RetentionScope::Crate,
+        };
+        CargoCacheCommands::KeepDuplicateCrates {
+            dry_run,
+            limit,
+            scope,
+        }
+    } else if config.is_present("keep-git-revisions") {
+        let clap_val = value_t!(config.value_of("keep-git-revisions"), u64);
+        let limit = clap_val
+            .map_err(|e| {
+                format!(
+                    "Error: \"--keep-git-revisions\" expected an integer argument.\n{}\"",
+                    e
+                )
+            })
+            .unwrap_or_fatal_error();
+        let order = match config.value_of("git-revisions-by") {
+            Some("committed") => RevisionOrder::Committed,
+            _ => RevisionOrder::Accessed,
+        };
+        CargoCacheCommands::KeepGitRevisions {
+            dry_run,
+            limit,
+            order,
+        }
     } else if config.subcommand_matches("registry").is_some()
         || config.subcommand_matches("r").is_some()
         || config.subcommand_matches("registries").is_some()
@@ -163,6 +379,78 @@ pub(crate) fn clap_to_enum<'a, 'b>(config: &'b ArgMatches<'a>) -> CargoCacheComm
     }
 }
 
+impl CargoCacheCommands<'_> {
+    /// does the command remove or rewrite anything inside the cache?
+    /// clean-unref is not included since it has to run "cargo metadata" before it can take the lock
+    pub(crate) fn removes_files(&self) -> bool {
+        match self {
+            Self::GitGCRepos { dry_run, .. }
+            | Self::KeepDuplicateCrates { dry_run, .. }
+            | Self::KeepGitRevisions { dry_run, .. }
+            | Self::RemoveDir { dry_run }
+            | Self::AutoClean { dry_run }
+            | Self::AutoCleanExpensive { dry_run, .. }
+            | Self::Trim { dry_run, .. }
+            | Self::EmptyTrash { dry_run, .. }
+            | Self::MigrateRegistry { dry_run, .. }
+            | Self::Extract { dry_run, .. }
+            | Self::PruneIndex { dry_run, .. }
+            | Self::RemoveIfDate { dry_run, .. } => !dry_run,
+            Self::Doctor { dry_run, fix } => *fix && !dry_run,
+            Self::Bin {
+                dry_run,
+                remove,
+                remove_unused_since,
+            } => (remove.is_some() || remove_unused_since.is_some()) && !dry_run,
+            Self::Undo => true,
+            Self::Query { query_config } => {
+                query_config.is_present("remove") && !query_config.is_present("dry-run")
+            }
+            _ => false,
+        }
+    }
+}
+
+// flags like --no-wait can be passed to the top level or to the subcommands that remove things
+fn present_anywhere(config: &ArgMatches<'_>, arg: &str) -> bool {
+    config.is_present(arg)
+        || [
+            "trim",
+            "clean-unref",
+            "query",
+            "q",
+            "extract",
+            "prune-index",
+        ]
+        .iter()
+        .filter_map(|subcmd| config.subcommand_matches(subcmd))
+        .any(|subcmd_config| subcmd_config.is_present(arg))
+}
+
+/// whether to wait for the package cache lock, --no-wait can also be passed to subcommands
+pub(crate) fn lock_mode_from_clap(config: &ArgMatches<'_>) -> LockMode {
+    if present_anywhere(config, "no-wait") {
+        LockMode::NoWait
+    } else {
+        LockMode::Wait
+    }
+}
+
+/// whether removed items go to the trash, --trash can also be passed to subcommands
+pub(crate) fn trash_from_clap(config: &ArgMatches<'_>) -> bool {
+    present_anywhere(config, "trash")
+}
+
+/// whether the summary shows the sizes on disk next to the apparent sizes
+pub(crate) fn disk_usage_from_clap(config: &ArgMatches<'_>) -> bool {
+    config.is_present("disk-usage")
+}
+
+/// whether unreadable paths make us exit with an error, --strict can also be passed to subcommands
+pub(crate) fn strict_from_clap(config: &ArgMatches<'_>) -> bool {
+    present_anywhere(config, "strict")
+}
+
 /// generates the version info with what we have in the build.rs
 pub(crate) fn get_version() -> String {
     // remove the "cargo-cache" since CLAP already adds that by itself
@@ -182,7 +470,7 @@ pub(crate) fn gen_clap<'a>() -> ArgMatches<'a> {
         .help("List all found directory paths");
 
     let remove_dir = Arg::with_name("remove-dir").short("r").long("remove-dir")
-        .help("Remove directories, accepted values: all,git-db,git-repos,\nregistry-sources,registry-crate-cache,registry-index,registry")
+        .help("Remove directories, accepted values: all,git-db,git-repos,\nregistry-sources,registry-crate-cache,registry-index,registry,\n<dir>@NAME for a single registry or git repo, crate:NAME[@VERSION]")
         .takes_value(true)
         .value_name("dir1,dir2,dir3");
 
@@ -191,6 +479,46 @@ pub(crate) fn gen_clap<'a>() -> ArgMatches<'a> {
         .long("gc")
         .help("Recompress git repositories (may take some time)");
 
+    // "--gc-*" only make sense if we recompress, either via --gc or --autoclean-expensive
+    let gc_group = ArgGroup::with_name("gc")
+        .args(&["gc-repos", "autoclean-expensive"])
+        .multiple(true);
+
+    let gc_jobs = Arg::with_name("gc-jobs")
+        .long("gc-jobs")
+        .help("Number of git repositories to recompress in parallel")
+        .takes_value(true)
+        .value_name("N")
+        .requires("gc");
+
+    let gc_memory = Arg::with_name("gc-memory")
+        .long("gc-memory")
+        .help("Memory limit shared by all recompressing jobs, for example: '2G'")
+        .takes_value(true)
+        .value_name("SIZE")
+        .requires("gc");
+
+    let gc_min_size = Arg::with_name("gc-min-size")
+        .long("gc-min-size")
+        .help("Don't recompress git repositories smaller than SIZE, for example: '10M'")
+        .takes_value(true)
+        .value_name("SIZE")
+        .requires("gc");
+
+    let gc_skip_recent = Arg::with_name("gc-skip-recent")
+        .long("gc-skip-recent")
+        .help("Skip git repositories recompressed within DURATION, for example: '7d'")
+        .takes_value(true)
+        .value_name("DURATION")
+        .requires("gc");
+
+    let gc_only = Arg::with_name("gc-only")
+        .long("gc-only")
+        .help("Only recompress the named git repositories")
+        .takes_value(true)
+        .value_name("repo1,repo2")
+        .requires("gc");
+
     let fsck_repos = Arg::with_name("fsck-repos")
         .short("f")
         .long("fsck")
@@ -207,15 +535,58 @@ pub(crate) fn gen_clap<'a>() -> ArgMatches<'a> {
     let keep_duplicate_crates = Arg::with_name("keep-duplicate-crates")
         .short("k")
         .long("keep-duplicate-crates")
-        .help("Remove all but N versions of crate in the source archives directory")
+        .help("Remove all but the N newest versions of each crate (archives and sources)")
+        .takes_value(true)
+        .value_name("N");
+
+    let keep_per = Arg::with_name("keep-per")
+        .long("keep-per")
+        .help("Keep N per crate or per semver major version")
+        .takes_value(true)
+        .possible_values(&["crate", "major"])
+        .requires("keep-duplicate-crates")
+        .value_name("SCOPE");
+
+    let keep_git_revisions = Arg::with_name("keep-git-revisions")
+        .long("keep-git-revisions")
+        .help("Remove all but the N newest checkouts of each git repo")
         .takes_value(true)
         .value_name("N");
 
+    let git_revisions_by = Arg::with_name("git-revisions-by")
+        .long("git-revisions-by")
+        .help("How to rank the git checkouts")
+        .takes_value(true)
+        .possible_values(&["accessed", "committed"])
+        .requires("keep-git-revisions")
+        .value_name("ORDER");
+
     let dry_run = Arg::with_name("dry-run")
         .short("n")
         .long("dry-run")
         .help("Don't remove anything, just pretend");
 
+    let wait = Arg::with_name("wait")
+        .long("wait")
+        .help("Wait if cargo holds the package cache lock (default)")
+        .conflicts_with("no-wait");
+
+    let no_wait = Arg::with_name("no-wait")
+        .long("no-wait")
+        .help("Exit with an error if cargo holds the package cache lock");
+
+    let trash = Arg::with_name("trash")
+        .long("trash")
+        .help("Move removed items to a trash in CARGO_HOME so they can be restored");
+
+    let disk_usage = Arg::with_name("disk-usage")
+        .long("disk-usage")
+        .help("Also show the sizes on disk in the summary, hardlinked files are counted once");
+
+    let strict = Arg::with_name("strict")
+        .long("strict")
+        .help("Exit with an error if paths vanished or were unreadable while scanning");
+
     let autoclean = Arg::with_name("autoclean")
         .short("a")
         .long("autoclean")
@@ -233,6 +604,11 @@ pub(crate) fn gen_clap<'a>() -> ArgMatches<'a> {
         .takes_value(true)
         .value_name("N");
 
+    let group_by_crate = Arg::with_name("group-by-crate")
+        .long("group-by-crate")
+        .help("With --top-cache-items, show one row per crate for all its versions")
+        .requires("top-cache-items");
+
     let remove_if_older = Arg::with_name("remove-if-older-than")
         .short("o")
         .long("remove-if-older-than")
@@ -278,19 +654,58 @@ pub(crate) fn gen_clap<'a>() -> ArgMatches<'a> {
         .long("human-readable")
         .help("print sizes in human readable format");
 
+    // arg of query sbcmd
+    let query_terms = Arg::with_name("QUERY").multiple(true).help(
+        "regex or filters like: 'registry:crates.io name:tokio* version:<1.0 size>5M unused>90d'",
+    );
+
+    // arg of query sbcmd
+    let query_format = Arg::with_name("format")
+        .long("format")
+        .help("print matches as text or as csv")
+        .takes_value(true)
+        .possible_values(&["text", "csv"]);
+
+    // arg of query sbcmd
+    let query_remove = Arg::with_name("remove")
+        .long("remove")
+        .help("remove all matches from the cache")
+        .conflicts_with("format");
+
+    // arg of query sbcmd
+    let query_dry_run = Arg::with_name("dry-run")
+        .short("n")
+        .long("dry-run")
+        .help("don't remove anything, just pretend")
+        .requires("remove");
+
     // query subcommand to allow querying
     let query = SubCommand::with_name("query")
         .about("run a query")
-        .arg(Arg::with_name("QUERY"))
+        .arg(&query_terms)
         .arg(&query_order)
-        .arg(&human_readable);
+        .arg(&human_readable)
+        .arg(&query_format)
+        .arg(&query_remove)
+        .arg(&query_dry_run)
+        .arg(&wait)
+        .arg(&no_wait)
+        .arg(&trash)
+        .arg(&strict);
 
     // short q (shorter query sbcmd)
     let query_short = SubCommand::with_name("q")
         .about("run a query")
-        .arg(Arg::with_name("QUERY"))
+        .arg(&query_terms)
         .arg(&query_order)
-        .arg(&human_readable);
+        .arg(&human_readable)
+        .arg(&query_format)
+        .arg(&query_remove)
+        .arg(&query_dry_run)
+        .arg(&wait)
+        .arg(&no_wait)
+        .arg(&trash)
+        .arg(&strict);
     // </query>
 
     //<local>
@@ -337,24 +752,228 @@ pub(crate) fn gen_clap<'a>() -> ArgMatches<'a> {
     let clean_unref = SubCommand::with_name("clean-unref")
         .about("remove crates that are not referenced in a Cargo.toml from the cache")
         .arg(&manifest_path)
-        .arg(&dry_run);
+        .arg(&dry_run)
+        .arg(&wait)
+        .arg(&no_wait)
+        .arg(&trash)
+        .arg(&strict);
     //</clean-unref>
 
     //<trim>
     let size_limit = Arg::with_name("trim_limit")
         .long("limit")
         .short("l")
-        .help("size that the cache will be reduced to, for example: '6B', '1K', '4M', '5G' or '1T'")
+        .help("size that the cache will be reduced to, for example: '4M', '1.5GB', '2GiB' or '10%' of the disk")
         .takes_value(true)
         .value_name("LIMIT")
-        .required(true);
+        .required_unless("ensure-free");
+
+    let ensure_free = Arg::with_name("ensure-free")
+        .long("ensure-free")
+        .help("trim the cache until this much space is free on the disk, for example: '30G'")
+        .takes_value(true)
+        .value_name("SIZE");
 
     let trim = SubCommand::with_name("trim")
         .about("trim old items from the cache until maximum cache size limit is reached")
         .arg(&size_limit)
-        .arg(&dry_run);
+        .arg(&ensure_free)
+        .arg(&dry_run)
+        .arg(&wait)
+        .arg(&no_wait)
+        .arg(&trash)
+        .arg(&strict);
 
     // </trim>
+
+    //<trash>
+    let undo = SubCommand::with_name("undo")
+        .about("restore the items removed by the last run that used --trash");
+
+    let older_than = Arg::with_name("older-than")
+        .long("older-than")
+        .help("only remove items trashed more than DURATION ago, for example: '30d'")
+        .takes_value(true)
+        .value_name("DURATION");
+
+    let empty_trash = SubCommand::with_name("empty-trash")
+        .about("permanently remove the items in the trash")
+        .arg(&older_than)
+        .arg(&dry_run);
+    //</trash>
+
+    //<log>
+    let since = Arg::with_name("since")
+        .long("since")
+        .help("only show items removed after this date: YYYY.MM.DD or HH:MM:SS")
+        .takes_value(true)
+        .value_name("date");
+
+    let until = Arg::with_name("until")
+        .long("until")
+        .help("only show items removed before this date: YYYY.MM.DD or HH:MM:SS")
+        .takes_value(true)
+        .value_name("date");
+
+    let log_crate = Arg::with_name("crate")
+        .long("crate")
+        .help("only show versions of this crate")
+        .takes_value(true)
+        .value_name("NAME");
+
+    let log = SubCommand::with_name("log")
+        .about("show what cargo-cache removed from the cache")
+        .arg(&since)
+        .arg(&until)
+        .arg(&log_crate);
+    //</log>
+
+    //<metrics>
+    let textfile = Arg::with_name("textfile")
+        .long("textfile")
+        .help("write the metrics to PATH for the node-exporter textfile collector")
+        .takes_value(true)
+        .value_name("PATH")
+        .conflicts_with("listen");
+
+    let listen = Arg::with_name("listen")
+        .long("listen")
+        .help("serve the metrics on http://ADDRESS/metrics, for example: '127.0.0.1:9184'")
+        .takes_value(true)
+        .value_name("ADDRESS");
+
+    let metrics = SubCommand::with_name("metrics")
+        .about("print the cache sizes in the OpenMetrics format")
+        .arg(&textfile)
+        .arg(&listen);
+    //</metrics>
+
+    //<watch>
+    let interval = Arg::with_name("interval")
+        .long("interval")
+        .help("check the cache size at most once per DURATION, for example: '30s' (default: '1m')")
+        .takes_value(true)
+        .value_name("DURATION");
+
+    let watch = SubCommand::with_name("watch")
+        .about("keep running and trim the cache whenever it grows beyond the limit")
+        .arg(&size_limit)
+        .arg(&interval)
+        .arg(&dry_run);
+    //</watch>
+
+    //<ci-key>
+    let prefix = Arg::with_name("prefix")
+        .long("prefix")
+        .help("the first component of the key")
+        .takes_value(true)
+        .value_name("PREFIX")
+        .default_value("cargo-cache");
+
+    let restore_keys = Arg::with_name("restore-keys")
+        .long("restore-keys")
+        .help("print the prefixes of the key to fall back to, most specific first");
+
+    let ci_key = SubCommand::with_name("ci-key")
+        .about("print a key for caching the cargo home on CI, derived from the dependencies and the toolchain")
+        .arg(&manifest_path)
+        .arg(&prefix)
+        .arg(&restore_keys);
+    //</ci-key>
+
+    //<migrate-registry>
+    let migrate_from = Arg::with_name("FROM")
+        .help("the old registry: directory name, host or configured name")
+        .required(true);
+
+    let migrate_to = Arg::with_name("TO")
+        .help("the new registry: directory name, host or configured name")
+        .required(true);
+
+    let hardlink = Arg::with_name("hardlink")
+        .long("hardlink")
+        .help("link the crate archives into the new registry and keep the old one");
+
+    let remove_old = Arg::with_name("remove-old")
+        .long("remove-old")
+        .conflicts_with("hardlink")
+        .help(
+            "remove the directories of the old registry once all its crate archives are migrated",
+        );
+
+    let migrate_registry = SubCommand::with_name("migrate-registry")
+        .about("move the crate archives of a registry into its new directory")
+        .arg(&migrate_from)
+        .arg(&migrate_to)
+        .arg(&hardlink)
+        .arg(&remove_old)
+        .arg(&dry_run);
+    //</migrate-registry>
+
+    //<doctor>
+    let fix = Arg::with_name("fix")
+        .long("fix")
+        .help("remove broken items, trash unknown files and fix permissions");
+
+    let doctor = SubCommand::with_name("doctor")
+        .about("check the cache for unknown files, incomplete extractions, broken archives and permissions")
+        .arg(&fix)
+        .arg(&dry_run);
+    //</doctor>
+
+    //<extract>
+    let extract = SubCommand::with_name("extract")
+        .about("extract the crate archives into registry/src, only those in the Cargo.lock of --manifest-path if given")
+        .arg(&manifest_path)
+        .arg(&dry_run)
+        .arg(&wait)
+        .arg(&no_wait);
+    //</extract>
+
+    //<prune-index>
+    let unused_for = Arg::with_name("unused-for")
+        .long("unused-for")
+        .help("remove the entries of crates without archive that were not used for DURATION")
+        .takes_value(true)
+        .default_value("90d")
+        .value_name("DURATION");
+
+    let lockfiles = Arg::with_name("lockfiles")
+        .long("lockfiles")
+        .help("only keep the entries of the crates in these Cargo.lock files")
+        .takes_value(true)
+        .value_name("LOCKFILE1,LOCKFILE2,...");
+
+    let prune_index = SubCommand::with_name("prune-index")
+        .about("remove index cache entries of crates that are neither cached nor used")
+        .arg(&unused_for)
+        .arg(&lockfiles)
+        .arg(&dry_run)
+        .arg(&wait)
+        .arg(&no_wait)
+        .arg(&trash);
+    //</prune-index>
+
+    //<bin>
+    let remove_package = Arg::with_name("remove")
+        .long("remove")
+        .help("uninstall packages: remove their binaries and install metadata")
+        .takes_value(true)
+        .value_name("CRATE1,CRATE2,...");
+
+    let remove_unused_since = Arg::with_name("remove-unused-since")
+        .long("remove-unused-since")
+        .help("uninstall packages whose binaries were not used since: YYYY.MM.DD or HH:MM:SS")
+        .conflicts_with("remove")
+        .takes_value(true)
+        .value_name("date");
+
+    let bin = SubCommand::with_name("bin")
+        .about("list the installed binaries with the package, features and toolchain they were built with")
+        .arg(&remove_package)
+        .arg(&remove_unused_since)
+        .arg(&dry_run);
+    //</bin>
     let toolchain = SubCommand::with_name("toolchain").about("print stats on installed toolchains");
     // now thread all of these together
 
@@ -380,16 +999,42 @@ pub(crate) fn gen_clap<'a>() -> ArgMatches<'a> {
         .subcommand(clean_unref.clone())
         .subcommand(toolchain.clone())
         .subcommand(trim.clone())
+        .subcommand(undo.clone())
+        .subcommand(empty_trash.clone())
+        .subcommand(log.clone())
+        .subcommand(metrics.clone())
+        .subcommand(watch.clone())
+        .subcommand(ci_key.clone())
+        .subcommand(migrate_registry.clone())
+        .subcommand(doctor.clone())
+        .subcommand(extract.clone())
+        .subcommand(prune_index.clone())
+        .subcommand(bin.clone())
         .arg(&list_dirs)
         .arg(&remove_dir)
         .arg(&gc_repos)
+        .arg(&gc_jobs)
+        .arg(&gc_memory)
+        .arg(&gc_min_size)
+        .arg(&gc_skip_recent)
+        .arg(&gc_only)
+        .group(gc_group.clone())
         .arg(&fsck_repos)
         .arg(&info)
         .arg(&keep_duplicate_crates)
+        .arg(&keep_git_revisions)
+        .arg(&keep_per)
+        .arg(&git_revisions_by)
         .arg(&dry_run)
         .arg(&autoclean)
         .arg(&autoclean_expensive)
         .arg(&list_top_cache_items)
+        .arg(&group_by_crate)
+        .arg(&wait)
+        .arg(&no_wait)
+        .arg(&trash)
+        .arg(&strict)
+        .arg(&disk_usage)
         .arg(&remove_if_younger)
         .arg(&remove_if_older)
         .arg(&debug)
@@ -415,16 +1060,42 @@ pub(crate) fn gen_clap<'a>() -> ArgMatches<'a> {
         .subcommand(clean_unref)
         .subcommand(toolchain.clone())
         .subcommand(trim)
+        .subcommand(undo)
+        .subcommand(empty_trash)
+        .subcommand(log)
+        .subcommand(metrics)
+        .subcommand(watch)
+        .subcommand(ci_key)
+        .subcommand(migrate_registry)
+        .subcommand(doctor)
+        .subcommand(extract)
+        .subcommand(prune_index)
+        .subcommand(bin)
         .arg(&list_dirs)
         .arg(&remove_dir)
         .arg(&gc_repos)
+        .arg(&gc_jobs)
+        .arg(&gc_memory)
+        .arg(&gc_min_size)
+        .arg(&gc_skip_recent)
+        .arg(&gc_only)
+        .group(gc_group)
         .arg(&fsck_repos)
         .arg(&info)
         .arg(&keep_duplicate_crates)
+        .arg(&keep_git_revisions)
+        .arg(&keep_per)
+        .arg(&git_revisions_by)
         .arg(&dry_run)
         .arg(&autoclean)
         .arg(&autoclean_expensive)
         .arg(&list_top_cache_items)
+        .arg(&group_by_crate)
+        .arg(&wait)
+        .arg(&no_wait)
+        .arg(&trash)
+        .arg(&strict)
+        .arg(&disk_usage)
         .arg(&remove_if_younger)
         .arg(&remove_if_older)
         .arg(&debug)
@@ -457,33 +1128,61 @@ USAGE:
 FLAGS:
     -a, --autoclean              Removes crate source checkouts and git repo checkouts
     -e, --autoclean-expensive    As --autoclean, but also recompresses git repositories
+        --disk-usage             Also show the sizes on disk in the summary, hardlinked files are counted once
     -n, --dry-run                Don't remove anything, just pretend
     -f, --fsck                   Fsck git repositories
     -g, --gc                     Recompress git repositories (may take some time)
+        --group-by-crate         With --top-cache-items, show one row per crate for all its versions
     -h, --help                   Prints help information
     -i, --info                   Print information cache directories, what they are for and what can be safely deleted
     -l, --list-dirs              List all found directory paths
-    -V, --version                Prints version information\n
+        --no-wait                Exit with an error if cargo holds the package cache lock
+        --strict                 Exit with an error if paths vanished or were unreadable while scanning
+        --trash                  Move removed items to a trash in CARGO_HOME so they can be restored
+    -V, --version                Prints version information
+        --wait                   Wait if cargo holds the package cache lock (default)\n
 OPTIONS:
-    -k, --keep-duplicate-crates <N>        Remove all but N versions of crate in the source archives directory
+        --gc-jobs <N>                      Number of git repositories to recompress in parallel
+        --gc-memory <SIZE>                 Memory limit shared by all recompressing jobs, for example: '2G'
+        --gc-min-size <SIZE>               Don't recompress git repositories smaller than SIZE, for example: '10M'
+        --gc-only <repo1,repo2>            Only recompress the named git repositories
+        --gc-skip-recent <DURATION>        Skip git repositories recompressed within DURATION, for example: '7d'
+        --git-revisions-by <ORDER>         How to rank the git checkouts [possible values: accessed, committed]
+    -k, --keep-duplicate-crates <N>        Remove all but the N newest versions of each crate (archives and sources)
+        --keep-git-revisions <N>           Remove all but the N newest checkouts of each git repo
+        --keep-per <SCOPE>                 Keep N per crate or per semver major version [possible values: crate, major]
     -r, --remove-dir <dir1,dir2,dir3>      Remove directories, accepted values: all,git-db,git-repos,
-                                           registry-sources,registry-crate-cache,registry-index,registry
+                                           registry-sources,registry-crate-cache,registry-index,registry,
+                                           <dir>@NAME for a single registry or git repo, crate:NAME[@VERSION]
     -o, --remove-if-older-than <date>      Removes items older than specified date: YYYY.MM.DD or HH:MM:SS
     -y, --remove-if-younger-than <date>    Removes items younger than the specified date: YYYY.MM.DD or HH:MM:SS
     -t, --top-cache-items <N>              List the top N items taking most space in the cache\n
 SUBCOMMANDS:
-    clean-unref    remove crates that are not referenced in a Cargo.toml from the cache
-    help           Prints this message or the help of the given subcommand(s)
-    l              check local build cache (target) of a rust project
-    local          check local build cache (target) of a rust project
-    q              run a query
-    query          run a query
-    r              query each package registry separately
-    registry       query each package registry separately
-    sc             gather stats on a local sccache cache
-    sccache        gather stats on a local sccache cache
-    toolchain      print stats on installed toolchains
-    trim           trim old items from the cache until maximum cache size limit is reached\n");
+    bin                 list the installed binaries with the package, features and toolchain they were built with
+    ci-key              print a key for caching the cargo home on CI, derived from the dependencies and the
+                        toolchain
+    clean-unref         remove crates that are not referenced in a Cargo.toml from the cache
+    doctor              check the cache for unknown files, incomplete extractions, broken archives and permissions
+    empty-trash         permanently remove the items in the trash
+    extract             extract the crate archives into registry/src, only those in the Cargo.lock of --manifest-
+                        path if given
+    help                Prints this message or the help of the given subcommand(s)
+    l                   check local build cache (target) of a rust project
+    local               check local build cache (target) of a rust project
+    log                 show what cargo-cache removed from the cache
+    metrics             print the cache sizes in the OpenMetrics format
+    migrate-registry    move the crate archives of a registry into its new directory
+    prune-index         remove index cache entries of crates that are neither cached nor used
+    q                   run a query
+    query               run a query
+    r                   query each package registry separately
+    registry            query each package registry separately
+    sc                  gather stats on a local sccache cache
+    sccache             gather stats on a local sccache cache
+    toolchain           print stats on installed toolchains
+    trim                trim old items from the cache until maximum cache size limit is reached
+    undo                restore the items removed by the last run that used --trash
+    watch               keep running and trim the cache whenever it grows beyond the limit\n");
         assert_eq!(help_desired, help_real);
     }
     #[test]
@@ -505,33 +1204,61 @@ USAGE:
 FLAGS:
     -a, --autoclean              Removes crate source checkouts and git repo checkouts
     -e, --autoclean-expensive    As --autoclean, but also recompresses git repositories
+        --disk-usage             Also show the sizes on disk in the summary, hardlinked files are counted once
     -n, --dry-run                Don't remove anything, just pretend
     -f, --fsck                   Fsck git repositories
     -g, --gc                     Recompress git repositories (may take some time)
+        --group-by-crate         With --top-cache-items, show one row per crate for all its versions
     -h, --help                   Prints help information
     -i, --info                   Print information cache directories, what they are for and what can be safely deleted
     -l, --list-dirs              List all found directory paths
-    -V, --version                Prints version information\n
+        --no-wait                Exit with an error if cargo holds the package cache lock
+        --strict                 Exit with an error if paths vanished or were unreadable while scanning
+        --trash                  Move removed items to a trash in CARGO_HOME so they can be restored
+    -V, --version                Prints version information
+        --wait                   Wait if cargo holds the package cache lock (default)\n
 OPTIONS:
-    -k, --keep-duplicate-crates <N>        Remove all but N versions of crate in the source archives directory
+        --gc-jobs <N>                      Number of git repositories to recompress in parallel
+        --gc-memory <SIZE>                 Memory limit shared by all recompressing jobs, for example: '2G'
+        --gc-min-size <SIZE>               Don't recompress git repositories smaller than SIZE, for example: '10M'
+        --gc-only <repo1,repo2>            Only recompress the named git repositories
+        --gc-skip-recent <DURATION>        Skip git repositories recompressed within DURATION, for example: '7d'
+        --git-revisions-by <ORDER>         How to rank the git checkouts [possible values: accessed, committed]
+    -k, --keep-duplicate-crates <N>        Remove all but the N newest versions of each crate (archives and sources)
+        --keep-git-revisions <N>           Remove all but the N newest checkouts of each git repo
+        --keep-per <SCOPE>                 Keep N per crate or per semver major version [possible values: crate, major]
     -r, --remove-dir <dir1,dir2,dir3>      Remove directories, accepted values: all,git-db,git-repos,
-                                           registry-sources,registry-crate-cache,registry-index,registry
+                                           registry-sources,registry-crate-cache,registry-index,registry,
+                                           <dir>@NAME for a single registry or git repo, crate:NAME[@VERSION]
     -o, --remove-if-older-than <date>      Removes items older than specified date: YYYY.MM.DD or HH:MM:SS
     -y, --remove-if-younger-than <date>    Removes items younger than the specified date: YYYY.MM.DD or HH:MM:SS
     -t, --top-cache-items <N>              List the top N items taking most space in the cache\n
 SUBCOMMANDS:
-    clean-unref    remove crates that are not referenced in a Cargo.toml from the cache
-    help           Prints this message or the help of the given subcommand(s)
-    l              check local build cache (target) of a rust project
-    local          check local build cache (target) of a rust project
-    q              run a query
-    query          run a query
-    r              query each package registry separately
-    registry       query each package registry separately
-    sc             gather stats on a local sccache cache
-    sccache        gather stats on a local sccache cache
-    toolchain      print stats on installed toolchains
-    trim           trim old items from the cache until maximum cache size limit is reached\n");
+    bin                 list the installed binaries with the package, features and toolchain they were built with
+    ci-key              print a key for caching the cargo home on CI, derived from the dependencies and the
+                        toolchain
+    clean-unref         remove crates that are not referenced in a Cargo.toml from the cache
+    doctor              check the cache for unknown files, incomplete extractions, broken archives and permissions
+    empty-trash         permanently remove the items in the trash
+    extract             extract the crate archives into registry/src, only those in the Cargo.lock of --manifest-
+                        path if given
+    help                Prints this message or the help of the given subcommand(s)
+    l                   check local build cache (target) of a rust project
+    local               check local build cache (target) of a rust project
+    log                 show what cargo-cache removed from the cache
+    metrics             print the cache sizes in the OpenMetrics format
+    migrate-registry    move the crate archives of a registry into its new directory
+    prune-index         remove index cache entries of crates that are neither cached nor used
+    q                   run a query
+    query               run a query
+    r                   query each package registry separately
+    registry            query each package registry separately
+    sc                  gather stats on a local sccache cache
+    sccache             gather stats on a local sccache cache
+    toolchain           print stats on installed toolchains
+    trim                trim old items from the cache until maximum cache size limit is reached
+    undo                restore the items removed by the last run that used --trash
+    watch               keep running and trim the cache whenever it grows beyond the limit\n");
 
         assert_eq!(help_desired, help_real);
     }
@@ -556,18 +1283,25 @@ SUBCOMMANDS:
 run a query
 
 USAGE:
-    cargo cache query [FLAGS] [OPTIONS] [QUERY]
+    cargo cache query [FLAGS] [OPTIONS] [QUERY]...
 
 FLAGS:
+    -n, --dry-run           don't remove anything, just pretend
         --help              Prints help information
     -h, --human-readable    print sizes in human readable format
+        --no-wait           Exit with an error if cargo holds the package cache lock
+        --remove            remove all matches from the cache
+        --strict            Exit with an error if paths vanished or were unreadable while scanning
+        --trash             Move removed items to a trash in CARGO_HOME so they can be restored
     -V, --version           Prints version information
+        --wait              Wait if cargo holds the package cache lock (default)
 
 OPTIONS:
-    -s, --sort-by <sort>    sort files alphabetically or by file size [possible values: size, name]
+        --format <format>    print matches as text or as csv [possible values: text, csv]
+    -s, --sort-by <sort>     sort files alphabetically or by file size [possible values: size, name]
 
 ARGS:
-    <QUERY>    \n",
+    <QUERY>...    regex or filters like: 'registry:crates.io name:tokio* version:<1.0 size>5M unused>90d'\n",
         );
 
         assert_eq!(help_desired, help_real);
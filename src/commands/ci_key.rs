@@ -0,0 +1,252 @@
+// Copyright 2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// "cargo cache ci-key" command
+// print a key for caching the CARGO_HOME on CI: <prefix>-<host>-<index protocol>-<rustc version>-<hash>
+// The hash covers the registry and git dependencies of the crate (resolved like "clean-unref" does),
+// so the key only changes if a different set of crates has to be downloaded.
+// --restore-keys prints the prefixes of the key instead, most specific one first, so that a CI run
+// can start from the closest cache that exists if there is none for the exact key.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::clean_unref::resolve_dependencies;
+use crate::library::{CargoCachePaths, Error};
+use crate::registries::{config_files, config_values};
+
+use cargo_metadata::Package;
+use git2::{ObjectType, Oid};
+
+/// the toolchain, as reported by "rustc -vV"
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Toolchain {
+    host: String,
+    release: String,
+    commit_date: Option<String>,
+}
+
+impl Toolchain {
+    fn from_rustc_output(output: &str) -> Option<Self> {
+        let field = |name: &str| {
+            output
+                .lines()
+                .filter_map(|line| line.split_once(": "))
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.trim().to_string())
+        };
+        Some(Self {
+            host: field("host")?,
+            release: field("release")?,
+            commit_date: field("commit-date").filter(|date| date != "unknown"),
+        })
+    }
+
+    /// "1.56.0" for releases, but every nightly is "1.57.0-nightly" so we add the date to those
+    fn version(&self) -> String {
+        match &self.commit_date {
+            Some(date) if self.release.contains('-') => format!("{}-{}", self.release, date),
+            _ => self.release.clone(),
+        }
+    }
+
+    /// cargo uses the sparse protocol for crates.io by default since 1.70
+    fn sparse_by_default(&self) -> bool {
+        let mut numbers = self
+            .release
+            .split(|c| c == '.' || c == '-')
+            .map(|number| number.parse::<u64>().unwrap_or(0));
+        let major = numbers.next().unwrap_or(0);
+        let minor = numbers.next().unwrap_or(0);
+        (major, minor) >= (1, 70)
+    }
+}
+
+fn rustc_toolchain() -> Result<Toolchain, Error> {
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let output = Command::new(rustc)
+        .arg("-vV")
+        .output()
+        .map_err(|error| Error::RustcVersionFailed(error.to_string()))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Toolchain::from_rustc_output(&stdout)
+        .ok_or_else(|| Error::RustcVersionFailed(String::from_utf8_lossy(&output.stderr).into()))
+}
+
+/// the value of `registries.crates-io.protocol` in a cargo config file, if it is set there
+fn configured_protocol(config: &str) -> Option<String> {
+    config_values(config)
+        .into_iter()
+        .find(|(key, _)| key == "registries.crates-io.protocol")
+        .map(|(_, protocol)| protocol)
+}
+
+/// how the crates.io index is fetched ("git" or "sparse"), this decides the directory names
+/// of the registry inside the cache so caches of different protocols are not interchangeable
+fn index_protocol(manifest: &Path, cargo_home: &Path, toolchain: &Toolchain) -> String {
+    if let Ok(protocol) = env::var("CARGO_REGISTRIES_CRATES_IO_PROTOCOL") {
+        return protocol;
+    }
+    // the config next to the crate takes precedence over the ones further up and in the CARGO_HOME
+    let crate_dir = manifest.parent().unwrap_or(manifest);
+    config_files(crate_dir, cargo_home)
+        .iter()
+        .filter_map(|file| fs::read_to_string(file).ok())
+        .find_map(|config| configured_protocol(&config))
+        .unwrap_or_else(|| {
+            if toolchain.sparse_by_default() {
+                String::from("sparse")
+            } else {
+                String::from("git")
+            }
+        })
+}
+
+/// hash of the registry and git dependencies, path dependencies are part of the repo anyway
+fn dependencies_hash(packages: &[Package]) -> String {
+    let mut dependencies: Vec<String> = packages
+        .iter()
+        .filter_map(|package| {
+            package
+                .source
+                .as_ref()
+                .map(|source| format!("{} {} {}", package.name, package.version, source))
+        })
+        .collect();
+    dependencies.sort();
+    dependencies.dedup();
+    hash_lines(&dependencies)
+}
+
+// a stable hash, unlike the hashers of std it will not change between rust versions
+fn hash_lines(lines: &[String]) -> String {
+    let mut content = lines.join("\n");
+    content.push('\n');
+    Oid::hash_object(ObjectType::Blob, content.as_bytes())
+        .expect("failed to hash the dependencies")
+        .to_string()
+}
+
+/// the key and its prefixes down to "<prefix>-<host>-", most specific first
+fn keys(prefix: &str, toolchain: &Toolchain, protocol: &str, hash: &str) -> Vec<String> {
+    let components = [
+        prefix,
+        &toolchain.host,
+        protocol,
+        &toolchain.version(),
+        hash,
+    ];
+    let mut keys = vec![components.join("-")];
+    keys.extend(
+        (2..components.len())
+            .rev()
+            .map(|length| format!("{}-", components[..length].join("-"))),
+    );
+    keys
+}
+
+/// "cargo cache ci-key": print the cache key or, with `restore_keys`, its prefixes
+pub(crate) fn ci_key(
+    ccd: &CargoCachePaths,
+    manifest_path: Option<&str>,
+    prefix: &str,
+    restore_keys: bool,
+) -> Result<(), Error> {
+    let manifest = match manifest_path {
+        Some(path_str) => PathBuf::from(path_str),
+        None => crate::local::get_manifest()?,
+    };
+
+    let toolchain = rustc_toolchain()?;
+    let protocol = index_protocol(&manifest, &ccd.cargo_home, &toolchain);
+    let hash = dependencies_hash(&resolve_dependencies(&manifest)?);
+
+    let hierarchy = keys(prefix, &toolchain, &protocol, &hash);
+    if restore_keys {
+        hierarchy[1..].iter().for_each(|key| println!("{}", key));
+    } else {
+        println!("{}", hierarchy[0]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod ci_key_tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn ci_key_toolchain() {
+        let stable = Toolchain::from_rustc_output(
+            "rustc 1.56.0 (09c42c458 2021-10-18)
+binary: rustc
+commit-hash: 09c42c45858d5f3aedfa670698275303a3d19afa
+commit-date: 2021-10-18
+host: x86_64-unknown-linux-gnu
+release: 1.56.0
+LLVM version: 13.0.0",
+        )
+        .unwrap();
+        assert_eq!(stable.host, "x86_64-unknown-linux-gnu");
+        assert_eq!(stable.version(), "1.56.0");
+        assert!(!stable.sparse_by_default());
+
+        let nightly = Toolchain {
+            host: String::from("aarch64-apple-darwin"),
+            release: String::from("1.72.0-nightly"),
+            commit_date: Some(String::from("2023-06-01")),
+        };
+        assert_eq!(nightly.version(), "1.72.0-nightly-2023-06-01");
+        assert!(nightly.sparse_by_default());
+
+        assert_eq!(Toolchain::from_rustc_output("error: no such file"), None);
+    }
+
+    #[test]
+    fn ci_key_configured_protocol() {
+        assert_eq!(
+            configured_protocol("[net]\ngit-fetch-with-cli = true\n\n[registries.crates-io]\nprotocol = \"git\" # no sparse\n"),
+            Some(String::from("git"))
+        );
+        assert_eq!(
+            configured_protocol("registries.crates-io.protocol = \"sparse\""),
+            Some(String::from("sparse"))
+        );
+        assert_eq!(
+            configured_protocol("[registries.my-registry]\nprotocol = \"git\"\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn ci_key_hierarchy() {
+        // same as "git hash-object" of a file with these lines
+        assert_eq!(
+            hash_lines(&[String::from("hello")]),
+            "ce013625030ba8dba906f756967f9e9ca394464a"
+        );
+
+        let toolchain = Toolchain {
+            host: String::from("x86_64-unknown-linux-gnu"),
+            release: String::from("1.56.0"),
+            commit_date: None,
+        };
+        assert_eq!(
+            keys("cargo-cache", &toolchain, "git", "1234abcd"),
+            vec![
+                "cargo-cache-x86_64-unknown-linux-gnu-git-1.56.0-1234abcd",
+                "cargo-cache-x86_64-unknown-linux-gnu-git-1.56.0-",
+                "cargo-cache-x86_64-unknown-linux-gnu-git-",
+                "cargo-cache-x86_64-unknown-linux-gnu-",
+            ]
+        );
+    }
+}
@@ -0,0 +1,539 @@
+// Copyright 2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// "cargo cache doctor" command
+// check the CARGO_HOME for things that neither cargo nor cargo-cache put there and for damage
+// that cargo does not always recover from: interrupted extractions, checkouts of git repos that
+// are gone, broken .crate archives, dangling symlinks and files we are not allowed to touch.
+// --fix removes the broken items (cargo downloads or extracts them again when it needs them),
+// unknown files directly in the CARGO_HOME might belong to the user, so these go into the trash.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::library::{CargoCachePaths, Error};
+use crate::registries::{archive_checksum, index_checksums};
+use crate::remove::{parse_version, remove_file, DryRunMessage};
+use crate::trash;
+
+use rayon::prelude::*;
+use walkdir::WalkDir;
+
+// what cargo, rustup and cargo-cache keep directly in the CARGO_HOME
+const KNOWN_IN_CARGO_HOME: &[&str] = &[
+    "bin",
+    "git",
+    "registry",
+    "config",
+    "config.toml",
+    "credentials",
+    "credentials.toml",
+    // cargo install
+    ".crates.toml",
+    ".crates2.json",
+    // locks and the database of the automatic gc of cargo
+    ".package-cache",
+    ".package-cache-mutate",
+    ".global-cache",
+    // rustup
+    "env",
+    "env.fish",
+    "env.nu",
+    "env.ps1",
+    "env.bat",
+    // cargo-cache itself: size index, trash and audit log
+    ".cargo-cache-index",
+    ".cargo-cache-trash",
+    ".cargo-cache-audit.log",
+];
+
+// cargo marks the caches in registry/ and git/ so backup tools skip them
+const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+
+/// a problem in the cache
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Problem {
+    /// a file directly in the `CARGO_HOME` that we don't know
+    UnknownInCargoHome(PathBuf),
+    /// a file inside the cache where only registries, crates or repos belong
+    UnknownInCache(PathBuf),
+    /// an extracted crate or a git checkout without ".cargo-ok", the extraction was interrupted
+    IncompleteExtraction(PathBuf),
+    /// a git checkout whose bare repo in git/db is gone
+    CheckoutWithoutRepo(PathBuf),
+    /// an empty .crate archive, or one that does not match the checksum in the index
+    BrokenArchive(PathBuf, &'static str),
+    /// a symlink pointing nowhere
+    BrokenSymlink(PathBuf),
+    /// a file or directory we may not read or write
+    PermissionDenied(PathBuf),
+}
+
+impl Problem {
+    fn path(&self) -> &Path {
+        match self {
+            Self::UnknownInCargoHome(path)
+            | Self::UnknownInCache(path)
+            | Self::IncompleteExtraction(path)
+            | Self::CheckoutWithoutRepo(path)
+            | Self::BrokenArchive(path, _)
+            | Self::BrokenSymlink(path)
+            | Self::PermissionDenied(path) => path,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownInCargoHome(path) => {
+                write!(f, "unknown file in the cargo home: '{}'", path.display())
+            }
+            Self::UnknownInCache(path) => {
+                write!(f, "unknown file in the cache: '{}'", path.display())
+            }
+            Self::IncompleteExtraction(path) => write!(
+                f,
+                "incomplete extraction, no .cargo-ok: '{}'",
+                path.display()
+            ),
+            Self::CheckoutWithoutRepo(path) => write!(
+                f,
+                "git checkout without bare repo in git/db: '{}'",
+                path.display()
+            ),
+            Self::BrokenArchive(path, reason) => {
+                write!(f, "broken crate archive ({}): '{}'", reason, path.display())
+            }
+            Self::BrokenSymlink(path) => write!(f, "broken symlink: '{}'", path.display()),
+            Self::PermissionDenied(path) => write!(f, "permission denied: '{}'", path.display()),
+        }
+    }
+}
+
+fn dir_entries(dir: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .collect()
+        })
+        .unwrap_or_default();
+    entries.sort();
+    entries
+}
+
+fn file_name(path: &Path) -> &str {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+}
+
+/// files directly in the `CARGO_HOME` and files where the cache only has directories
+fn unknown_files(ccd: &CargoCachePaths) -> Vec<Problem> {
+    let mut problems: Vec<Problem> = dir_entries(&ccd.cargo_home)
+        .into_iter()
+        .filter(|path| !KNOWN_IN_CARGO_HOME.contains(&file_name(path)))
+        .map(Problem::UnknownInCargoHome)
+        .collect();
+
+    let git = ccd.cargo_home.join("git");
+    let known_dirs = [
+        (&ccd.registry, vec!["cache", "index", "src"]),
+        (&git, vec!["checkouts", "db"]),
+    ];
+    for (dir, known) in &known_dirs {
+        problems.extend(
+            dir_entries(dir)
+                .into_iter()
+                .filter(|path| {
+                    let name = file_name(path);
+                    !(known.contains(&name) && path.is_dir()) && name != CACHEDIR_TAG
+                })
+                .map(Problem::UnknownInCache),
+        );
+    }
+
+    // registry/src/github.com-1ecc6299db9ec823, git/db/cargo-e7ff1db891893a9e and the extracted crates
+    // and checkouts inside of these are directories, the index and the repos are cargo's business
+    let containers = [
+        &ccd.registry_pkg_cache,
+        &ccd.registry_index,
+        &ccd.registry_sources,
+        &ccd.git_repos_bare,
+        &ccd.git_checkouts,
+    ];
+    let nested = [&ccd.registry_sources, &ccd.git_checkouts];
+    for container in containers {
+        for entry in dir_entries(container) {
+            if !entry.is_dir() {
+                problems.push(Problem::UnknownInCache(entry));
+            } else if nested.contains(&container) {
+                problems.extend(
+                    dir_entries(&entry)
+                        .into_iter()
+                        .filter(|path| !path.is_dir())
+                        .map(Problem::UnknownInCache),
+                );
+            }
+        }
+    }
+
+    // registry/cache/<registry>/ only has .crate archives
+    for registry in dir_entries(&ccd.registry_pkg_cache)
+        .iter()
+        .filter(|dir| dir.is_dir())
+    {
+        problems.extend(
+            dir_entries(registry)
+                .into_iter()
+                .filter(|path| path.is_dir() || path.extension().map_or(true, |ext| ext != "crate"))
+                .map(Problem::UnknownInCache),
+        );
+    }
+    problems
+}
+
+/// extracted crates and git checkouts without the ".cargo-ok" cargo writes once it is done
+fn incomplete_extractions(ccd: &CargoCachePaths) -> Vec<Problem> {
+    [&ccd.registry_sources, &ccd.git_checkouts]
+        .iter()
+        .flat_map(|dir| dir_entries(dir))
+        .filter(|dir| dir.is_dir())
+        .flat_map(|dir| dir_entries(&dir))
+        .filter(|dir| dir.is_dir() && !dir.join(".cargo-ok").exists())
+        .map(Problem::IncompleteExtraction)
+        .collect()
+}
+
+/// git/checkouts/<repo> of which there is no git/db/<repo> anymore
+fn checkouts_without_repo(ccd: &CargoCachePaths) -> Vec<Problem> {
+    dir_entries(&ccd.git_checkouts)
+        .into_iter()
+        .filter(|checkout| {
+            checkout.is_dir() && !ccd.git_repos_bare.join(file_name(checkout)).is_dir()
+        })
+        .map(Problem::CheckoutWithoutRepo)
+        .collect()
+}
+
+/// why a .crate archive is broken, if it is
+fn archive_problem(archive: &Path, index_dir: &Path) -> Option<&'static str> {
+    match fs::metadata(archive) {
+        Ok(metadata) if metadata.len() == 0 => return Some("empty"),
+        Ok(_) => {}
+        // reported as a permission problem
+        Err(_) => return None,
+    }
+    // archives are gzipped tarballs
+    let mut magic = [0; 2];
+    let gzip = fs::File::open(archive)
+        .and_then(|mut file| io::Read::read_exact(&mut file, &mut magic))
+        .map_or(false, |()| magic == [0x1f, 0x8b]);
+    if !gzip {
+        return Some("not gzipped");
+    }
+    // without a checksum in the index we can't tell whether it was truncated
+    let (name, version) = parse_version(archive).ok()?;
+    let expected = index_checksums(index_dir, &name).remove(&version)?;
+    match archive_checksum(archive) {
+        Ok(checksum) if checksum != expected => Some("checksum mismatch"),
+        _ => None,
+    }
+}
+
+/// empty, truncated or otherwise damaged .crate archives
+fn broken_archives(ccd: &CargoCachePaths) -> Vec<Problem> {
+    let archives: Vec<(PathBuf, PathBuf)> = dir_entries(&ccd.registry_pkg_cache)
+        .into_iter()
+        .filter(|registry| registry.is_dir())
+        .flat_map(|registry| {
+            let index_dir = ccd.registry_index.join(file_name(&registry));
+            dir_entries(&registry)
+                .into_iter()
+                .filter(|path| path.extension().map_or(false, |ext| ext == "crate"))
+                .map(move |archive| (archive, index_dir.clone()))
+        })
+        .collect();
+
+    archives
+        .par_iter()
+        .filter_map(|(archive, index_dir)| {
+            archive_problem(archive, index_dir)
+                .map(|reason| Problem::BrokenArchive(archive.clone(), reason))
+        })
+        .collect()
+}
+
+/// dangling symlinks and whatever we may not read (or, for directories, not change)
+fn symlinks_and_permissions(ccd: &CargoCachePaths) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    for entry in WalkDir::new(&ccd.cargo_home).min_depth(1) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                let denied = error.io_error().map_or(false, |io_error| {
+                    io_error.kind() == io::ErrorKind::PermissionDenied
+                });
+                if let (true, Some(path)) = (denied, error.path()) {
+                    problems.push(Problem::PermissionDenied(path.to_path_buf()));
+                }
+                continue;
+            }
+        };
+        let path = entry.path();
+        if entry.path_is_symlink() {
+            if fs::metadata(path).is_err() {
+                problems.push(Problem::BrokenSymlink(path.to_path_buf()));
+            }
+        } else if entry
+            .metadata()
+            .map_or(false, |metadata| !permissions_ok(&metadata))
+        {
+            problems.push(Problem::PermissionDenied(path.to_path_buf()));
+        }
+    }
+    problems
+}
+
+// the owner can read files and read, enter and change directories
+#[cfg(unix)]
+fn permissions_ok(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = metadata.permissions().mode();
+    if metadata.is_dir() {
+        mode & 0o700 == 0o700
+    } else {
+        mode & 0o400 == 0o400
+    }
+}
+
+#[cfg(not(unix))]
+fn permissions_ok(metadata: &fs::Metadata) -> bool {
+    !(metadata.is_dir() && metadata.permissions().readonly())
+}
+
+#[cfg(unix)]
+fn fix_permissions(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let metadata = fs::metadata(path)?;
+    let mut permissions = metadata.permissions();
+    let needed = if metadata.is_dir() { 0o700 } else { 0o600 };
+    permissions.set_mode(permissions.mode() | needed);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn fix_permissions(path: &Path) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(false);
+    fs::set_permissions(path, permissions)
+}
+
+fn find_problems(ccd: &CargoCachePaths) -> Vec<Problem> {
+    let mut problems = unknown_files(ccd);
+    problems.extend(incomplete_extractions(ccd));
+    problems.extend(checkouts_without_repo(ccd));
+    problems.extend(broken_archives(ccd));
+    problems.extend(symlinks_and_permissions(ccd));
+    problems
+}
+
+fn fix(
+    problem: &Problem,
+    ccd: &CargoCachePaths,
+    dry_run: bool,
+    size_changed: &mut bool,
+) -> Result<(), Error> {
+    let path = problem.path();
+    match problem {
+        Problem::UnknownInCargoHome(_) => {
+            if dry_run {
+                println!("dry-run: would move to the trash: '{}'", path.display());
+            } else {
+                println!("moving to the trash: '{}'", path.display());
+                trash::move_to_trash(&ccd.cargo_home, path)?;
+                *size_changed = true;
+            }
+        }
+        Problem::PermissionDenied(_) => {
+            if dry_run {
+                println!("dry-run: would fix the permissions of '{}'", path.display());
+            } else {
+                println!("fixing the permissions of '{}'", path.display());
+                fix_permissions(path)
+                    .map_err(|error| Error::DoctorFixFailed(path.to_path_buf(), error))?;
+            }
+        }
+        // cargo downloads, extracts or checks these out again
+        Problem::BrokenSymlink(_)
+        | Problem::UnknownInCache(_)
+        | Problem::IncompleteExtraction(_)
+        | Problem::CheckoutWithoutRepo(_)
+        | Problem::BrokenArchive(..) => remove_file(
+            path,
+            dry_run,
+            size_changed,
+            Some(format!("removing: '{}'", path.display())),
+            &DryRunMessage::Default,
+            None,
+        ),
+    }
+    Ok(())
+}
+
+/// "cargo cache doctor": report problems in the cache and with `fix`, fix them
+pub(crate) fn doctor(
+    ccd: &CargoCachePaths,
+    fix_problems: bool,
+    dry_run: bool,
+    size_changed: &mut bool,
+) -> Result<(), Error> {
+    let problems = find_problems(ccd);
+    if problems.is_empty() {
+        println!("No problems found in {}", ccd.cargo_home.display());
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    println!();
+    if !fix_problems {
+        return Err(Error::DoctorFoundProblems(problems.len()));
+    }
+
+    let mut unfixed = 0;
+    for problem in &problems {
+        // removed together with an earlier problem
+        if fs::symlink_metadata(problem.path()).is_err() {
+            continue;
+        }
+        if let Err(error) = fix(problem, ccd, dry_run, size_changed) {
+            eprintln!("Warning: {}", error);
+            unfixed += 1;
+        }
+    }
+    if unfixed > 0 {
+        return Err(Error::DoctorFoundProblems(unfixed));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod doctortests {
+    use super::*;
+    use crate::test_helpers::assert_path_end;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn doctor_finds_problems() {
+        let cargo_home = PathBuf::from("target/doctor_finds_problems/.cargo");
+        let _ = fs::remove_dir_all(&cargo_home);
+        let crate_cache = cargo_home.join("registry/cache/example.com-0123456789abcdef");
+        let crate_sources = cargo_home.join("registry/src/example.com-0123456789abcdef");
+        for dir in &[
+            cargo_home.join("bin"),
+            cargo_home.join(".cargo-cache-trash"),
+            crate_cache.clone(),
+            crate_sources.join("foo-1.0.0"),
+            crate_sources.join("bar-1.0.0"),
+            cargo_home.join("git/db/repo-0123456789abcdef"),
+            cargo_home.join("git/checkouts/repo-0123456789abcdef/abcdef0"),
+            cargo_home.join("git/checkouts/gone-0123456789abcdef/abcdef0"),
+        ] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        for file in &[
+            ".package-cache",
+            ".cargo-cache-index",
+            ".cargo-cache-audit.log",
+            "registry/CACHEDIR.TAG",
+            "registry/src/example.com-0123456789abcdef/foo-1.0.0/.cargo-ok",
+            "git/checkouts/repo-0123456789abcdef/abcdef0/.cargo-ok",
+            "git/checkouts/gone-0123456789abcdef/abcdef0/.cargo-ok",
+            "notes.txt",
+            "git/.DS_Store",
+        ] {
+            fs::write(cargo_home.join(file), "").unwrap();
+        }
+        fs::write(crate_cache.join("foo-1.0.0.crate"), [0x1f, 0x8b, 0]).unwrap();
+        fs::write(crate_cache.join("bar-1.0.0.crate"), "").unwrap();
+        fs::write(crate_cache.join("baz-1.0.0.crate"), "<html>").unwrap();
+
+        let ccd = CargoCachePaths::new(cargo_home.clone()).unwrap();
+        let problems = find_problems(&ccd);
+        let described: Vec<(String, PathBuf)> = problems
+            .iter()
+            .map(|problem| {
+                let kind = format!("{:?}", problem);
+                (
+                    kind.split('(').next().unwrap().to_string(),
+                    problem.path().to_path_buf(),
+                )
+            })
+            .collect();
+        let kinds: Vec<&str> = described.iter().map(|(kind, _)| kind.as_str()).collect();
+        assert_eq!(
+            kinds,
+            [
+                "UnknownInCargoHome",
+                "UnknownInCache",
+                "IncompleteExtraction",
+                "CheckoutWithoutRepo",
+                "BrokenArchive",
+                "BrokenArchive",
+            ]
+        );
+        assert_path_end(&described[0].1, &["notes.txt"]);
+        assert_path_end(&described[1].1, &["git", ".DS_Store"]);
+        assert_path_end(&described[2].1, &["bar-1.0.0"]);
+        assert_path_end(&described[3].1, &["gone-0123456789abcdef"]);
+        assert_eq!(
+            problems[4],
+            Problem::BrokenArchive(crate_cache.join("bar-1.0.0.crate"), "empty")
+        );
+        assert_eq!(
+            problems[5],
+            Problem::BrokenArchive(crate_cache.join("baz-1.0.0.crate"), "not gzipped")
+        );
+
+        let mut size_changed = false;
+        doctor(&ccd, true, false, &mut size_changed).unwrap();
+        assert!(size_changed);
+        assert!(find_problems(&ccd).is_empty());
+        // it might be the users, so it goes into the trash
+        assert!(!cargo_home.join("notes.txt").exists());
+        assert_eq!(trash::runs(&cargo_home).len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn doctor_removes_broken_symlinks() {
+        let cargo_home = PathBuf::from("target/doctor_removes_broken_symlinks/.cargo");
+        let _ = fs::remove_dir_all(&cargo_home);
+        let foo = cargo_home.join("registry/src/example.com-0123456789abcdef/foo-1.0.0");
+        fs::create_dir_all(&foo).unwrap();
+        fs::write(foo.join(".cargo-ok"), "ok").unwrap();
+        std::os::unix::fs::symlink("gone", foo.join("link")).unwrap();
+
+        let ccd = CargoCachePaths::new(cargo_home).unwrap();
+        assert_eq!(
+            find_problems(&ccd),
+            [Problem::BrokenSymlink(foo.join("link"))]
+        );
+        let mut size_changed = false;
+        doctor(&ccd, true, false, &mut size_changed).unwrap();
+        assert!(size_changed);
+        assert!(fs::symlink_metadata(foo.join("link")).is_err());
+        assert!(foo.join(".cargo-ok").exists());
+    }
+}
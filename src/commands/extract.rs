@@ -0,0 +1,371 @@
+// Copyright 2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// "cargo cache extract" command
+// --autoclean removes registry/src since cargo extracts the .crate archives again when it needs them.
+// On machines that start with a fresh copy of the cache that costs time at the start of every build,
+// so this unpacks the archives of registry/cache into registry/src ahead of time, all of them or only
+// the ones in the Cargo.lock of a project. The result is the same as an extraction by cargo,
+// including the ".cargo-ok" marker, so cargo uses the sources as they are.
+
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cache::caches::*;
+use crate::cache::*;
+use crate::library::{CargoCachePaths, Error};
+use crate::lockfile::{find_lockfile, read_lockfile};
+use crate::registries::Registries;
+
+use flate2::read::GzDecoder;
+use rayon::prelude::*;
+use tar::Archive;
+
+// cargo writes this into the extracted crate once everything is unpacked
+const CARGO_OK: &str = ".cargo-ok";
+// cargo >= 1.71 re-extracts crates with any other content, older versions only check that it is not empty
+const CARGO_OK_CONTENT: &str = r#"{"v":1}"#;
+
+/// has cargo (or we) finished extracting the crate into `dir`?
+fn is_extracted(dir: &Path) -> bool {
+    fs::metadata(dir.join(CARGO_OK)).map_or(false, |metadata| metadata.len() > 0)
+}
+
+/// registry/cache/<registry>/foo-1.0.0.crate => registry/src/<registry>/foo-1.0.0
+fn source_dir(archive: &Path, registry_sources: &Path) -> Option<PathBuf> {
+    let registry = archive.parent()?.file_name()?;
+    // can't use .file_stem(), that is fine here but would be wrong for "foo-1.0.0-rc.1"
+    let package = archive.file_name()?.to_str()?.strip_suffix(".crate")?;
+    Some(registry_sources.join(registry).join(package))
+}
+
+/// unpack `archive` into `destination` the way `unpack_package` of cargo does it
+fn extract_archive(archive: &Path, destination: &Path) -> io::Result<()> {
+    // an earlier extraction was interrupted, start over like cargo
+    if destination.exists() {
+        remove_dir_all::remove_dir_all(destination)?;
+    }
+    fs::create_dir_all(destination)?;
+
+    // every file of the archive is inside the "foo-1.0.0/" directory
+    let prefix = destination.file_name().unwrap_or_default();
+    let parent = destination.parent().unwrap_or(destination);
+    let mut tar = Archive::new(GzDecoder::new(File::open(archive)?));
+    // cargo applies the umask, this is the common default of 022
+    tar.set_mask(0o022);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if !path.starts_with(prefix) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the archive contains \"{}\" which is not inside \"{}\"",
+                    path.display(),
+                    prefix.to_string_lossy()
+                ),
+            ));
+        }
+        // a marker inside the archive must not make an incomplete extraction look finished
+        if path.file_name() == Some(OsStr::new(CARGO_OK)) {
+            continue;
+        }
+        let _ = entry.unpack_in(parent)?;
+    }
+
+    fs::write(destination.join(CARGO_OK), CARGO_OK_CONTENT)
+}
+
+/// extract the .crate archives of the cache that are not extracted yet, only those in the Cargo.lock of the
+/// `manifest_path` if given
+pub(crate) fn extract(
+    ccd: &CargoCachePaths,
+    manifest_path: Option<&str>,
+    registry_pkg_caches: &mut registry_pkg_cache::RegistryPkgCaches,
+    dry_run: bool,
+    size_changed: &mut bool,
+) -> Result<(), Error> {
+    let mut archives: Vec<PathBuf> = registry_pkg_caches.items().to_vec();
+
+    if let Some(manifest_path) = manifest_path {
+        let lockfile = find_lockfile(Path::new(manifest_path))?;
+        // the lockfile has the url of the index of a package, only the archive in a directory cargo uses for
+        // that registry is the locked one. If we don't know the registry, every directory with the archive qualifies
+        let registries =
+            Registries::load_in(lockfile.parent().unwrap_or(&lockfile), &ccd.cargo_home);
+        let locked: Vec<(String, Option<Vec<String>>)> = read_lockfile(&lockfile)?
+            .iter()
+            .map(|package| {
+                (
+                    package.archive_name(),
+                    registries.directory_names(&package.source),
+                )
+            })
+            .collect();
+        let is_locked = |archive: &Path, (name, directories): &(String, Option<Vec<String>>)| {
+            archive.file_name() == Some(OsStr::new(name))
+                && directories.as_ref().map_or(true, |directories| {
+                    archive
+                        .parent()
+                        .and_then(Path::file_name)
+                        .map_or(false, |registry| {
+                            directories
+                                .iter()
+                                .any(|directory| registry == OsStr::new(directory))
+                        })
+                })
+        };
+        archives.retain(|archive| locked.iter().any(|package| is_locked(archive, package)));
+
+        let missing = locked
+            .iter()
+            .filter(|package| !archives.iter().any(|archive| is_locked(archive, package)))
+            .count();
+        if missing > 0 {
+            println!(
+                "{} packages of \"{}\" are not in the cache, cargo will download them",
+                missing,
+                lockfile.display()
+            );
+        }
+    }
+
+    let pending: Vec<(PathBuf, PathBuf)> = archives
+        .into_iter()
+        .filter_map(|archive| {
+            let destination = source_dir(&archive, &ccd.registry_sources)?;
+            Some((archive, destination))
+        })
+        .filter(|(_archive, destination)| !is_extracted(destination))
+        .collect();
+
+    if pending.is_empty() {
+        println!("All crate archives are extracted already");
+        return Ok(());
+    }
+
+    if dry_run {
+        for (archive, _destination) in &pending {
+            println!("dry-run: would extract: '{}'", archive.display());
+        }
+        return Ok(());
+    }
+
+    let failures: Vec<(PathBuf, io::Error)> = pending
+        .par_iter()
+        .filter_map(|(archive, destination)| {
+            extract_archive(archive, destination).err().map(|error| {
+                // don't leave half a crate behind
+                let _ = remove_dir_all::remove_dir_all(destination);
+                (archive.clone(), error)
+            })
+        })
+        .collect();
+
+    // the extracted crates are new to the inventory
+    let mut registries: Vec<&Path> = pending
+        .iter()
+        .filter_map(|(_archive, destination)| destination.parent())
+        .collect();
+    registries.sort();
+    registries.dedup();
+    registries.into_iter().for_each(inventory::rescan);
+    *size_changed = true;
+
+    println!(
+        "Extracted {} crate archives",
+        pending.len() - failures.len()
+    );
+
+    // report all failures, the first one is the error we exit with
+    let mut failures = failures
+        .into_iter()
+        .map(|(archive, error)| Error::ExtractionFailed(archive, error));
+    match failures.next() {
+        None => Ok(()),
+        Some(first_failure) => {
+            failures.for_each(|failure| eprintln!("{}", failure));
+            Err(first_failure)
+        }
+    }
+}
+
+#[cfg(test)]
+mod extract_tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    // a .crate archive with the given files below "<package>/"
+    fn write_archive(archive: &Path, package: &str, files: &[(&str, &str)]) {
+        let mut tar = tar::Builder::new(GzEncoder::new(
+            File::create(archive).unwrap(),
+            Compression::default(),
+        ));
+        for (name, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o664);
+            header.set_cksum();
+            tar.append_data(
+                &mut header,
+                format!("{}/{}", package, name),
+                content.as_bytes(),
+            )
+            .unwrap();
+        }
+        let _ = tar.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn extract_archives() {
+        let cargo_home = PathBuf::from("target/extract_archives/.cargo");
+        let _ = fs::remove_dir_all(&cargo_home);
+        let registry_cache = cargo_home.join("registry/cache/index.crates.io-6f17d22bba15001f");
+        let registry_src = cargo_home.join("registry/src/index.crates.io-6f17d22bba15001f");
+        fs::create_dir_all(&registry_cache).unwrap();
+        fs::create_dir_all(&registry_src).unwrap();
+        write_archive(
+            &registry_cache.join("foo-1.0.0.crate"),
+            "foo-1.0.0",
+            &[
+                ("Cargo.toml", "[package]"),
+                ("src/lib.rs", "fn main() {}"),
+                (".cargo-ok", "ok"),
+            ],
+        );
+        write_archive(
+            &registry_cache.join("evil-1.0.0.crate"),
+            "other-1.0.0",
+            &[("Cargo.toml", "[package]")],
+        );
+        // an interrupted extraction
+        fs::create_dir_all(registry_src.join("foo-1.0.0")).unwrap();
+        fs::write(registry_src.join("foo-1.0.0/leftover"), "").unwrap();
+
+        let ccd = CargoCachePaths::new(cargo_home).unwrap();
+        let mut registry_pkg_caches =
+            registry_pkg_cache::RegistryPkgCaches::new(ccd.registry_pkg_cache.clone());
+        let mut size_changed = false;
+        let error = extract(
+            &ccd,
+            None,
+            &mut registry_pkg_caches,
+            false,
+            &mut size_changed,
+        )
+        .expect_err("evil-1.0.0.crate should fail to extract");
+        assert!(
+            matches!(error, Error::ExtractionFailed(path, _) if path.ends_with("evil-1.0.0.crate"))
+        );
+        assert!(size_changed);
+
+        let foo = registry_src.join("foo-1.0.0");
+        assert_eq!(
+            fs::read_to_string(foo.join("src/lib.rs")).unwrap(),
+            "fn main() {}"
+        );
+        assert_eq!(
+            fs::read_to_string(foo.join(CARGO_OK)).unwrap(),
+            CARGO_OK_CONTENT
+        );
+        assert!(!foo.join("leftover").exists());
+        assert!(!registry_src.join("evil-1.0.0").exists());
+        assert!(!registry_src.join("other-1.0.0").exists());
+
+        // extracted crates are left alone
+        fs::write(foo.join("src/lib.rs"), "changed").unwrap();
+        fs::remove_file(registry_cache.join("evil-1.0.0.crate")).unwrap();
+        registry_pkg_caches.invalidate();
+        extract(
+            &ccd,
+            None,
+            &mut registry_pkg_caches,
+            false,
+            &mut size_changed,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(foo.join("src/lib.rs")).unwrap(),
+            "changed"
+        );
+    }
+
+    #[test]
+    fn extract_locked_archives() {
+        let root = PathBuf::from("target/extract_locked_archives");
+        let _ = fs::remove_dir_all(&root);
+        let cargo_home = root.join(".cargo");
+        let crates_io = "index.crates.io-1949cf8c6b5b557f";
+        let other = "example.com-0123456789abcdef";
+        for registry in &[crates_io, other] {
+            let registry_cache = cargo_home.join("registry/cache").join(registry);
+            fs::create_dir_all(&registry_cache).unwrap();
+            fs::create_dir_all(cargo_home.join("registry/src").join(registry)).unwrap();
+            write_archive(
+                &registry_cache.join("foo-1.0.0.crate"),
+                "foo-1.0.0",
+                &[("Cargo.toml", "[package]")],
+            );
+        }
+        write_archive(
+            &cargo_home
+                .join("registry/cache")
+                .join(other)
+                .join("private-0.1.0.crate"),
+            "private-0.1.0",
+            &[("Cargo.toml", "[package]")],
+        );
+        let project = root.join("project");
+        fs::create_dir_all(&project).unwrap();
+        fs::write(project.join("Cargo.toml"), "[package]").unwrap();
+        fs::write(
+            project.join("Cargo.lock"),
+            r#"[[package]]
+name = "foo"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "private"
+version = "0.1.0"
+source = "sparse+https://crates.example.com/index/"
+"#,
+        )
+        .unwrap();
+
+        let ccd = CargoCachePaths::new(cargo_home.clone()).unwrap();
+        let mut registry_pkg_caches =
+            registry_pkg_cache::RegistryPkgCaches::new(ccd.registry_pkg_cache.clone());
+        let mut size_changed = false;
+        extract(
+            &ccd,
+            Some(project.join("Cargo.toml").to_str().unwrap()),
+            &mut registry_pkg_caches,
+            false,
+            &mut size_changed,
+        )
+        .unwrap();
+
+        let registry_src = cargo_home.join("registry/src");
+        // crates.io packages are only taken from a crates.io directory
+        assert!(is_extracted(
+            &registry_src.join(crates_io).join("foo-1.0.0")
+        ));
+        assert!(!registry_src.join(other).join("foo-1.0.0").exists());
+        // we don't know the registry of "private", any directory will do
+        assert!(is_extracted(
+            &registry_src.join(other).join("private-0.1.0")
+        ));
+    }
+}
@@ -0,0 +1,708 @@
+// Copyright 2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// "cargo cache bin" command
+// list the binaries in CARGO_HOME/bin together with the package "cargo install" built them from.
+// cargo records installed packages twice: .crates.toml maps the package id to its binaries and
+// .crates2.json additionally has the features, profile, target and rustc version of the build.
+// Binaries that are in neither were copied into bin/ by hand (or the metadata was lost), metadata
+// without a binary means the binary was deleted and "cargo install --list" is wrong.
+// --remove and --remove-unused-since remove packages like "cargo uninstall": the binaries and the
+// entries in both metadata files.
+
+use std::fmt::Write;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write as _};
+use std::path::{Path, PathBuf};
+
+use crate::cache::bin::BinaryCache;
+use crate::cache::caches::Cache;
+use crate::date::parse_date;
+use crate::library::{last_access, size_of_file, CargoCachePaths, Error};
+use crate::registries::Registries;
+use crate::remove::remove_with_default_message;
+use crate::tables::format_table;
+use crate::trash;
+
+use chrono::{DateTime, Local, NaiveDateTime};
+use fs2::FileExt;
+use humansize::{file_size_opts, FileSize};
+use serde_json::Value;
+
+// hardlinks of rustup in CARGO_HOME/bin, not installed by "cargo install"
+const RUSTUP_PROXIES: &[&str] = &[
+    "cargo",
+    "cargo-clippy",
+    "cargo-fmt",
+    "cargo-miri",
+    "clippy-driver",
+    "rls",
+    "rust-analyzer",
+    "rust-gdb",
+    "rust-gdbgui",
+    "rust-lldb",
+    "rustc",
+    "rustdoc",
+    "rustfmt",
+    "rustup",
+];
+
+/// a package installed with "cargo install"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct InstalledPackage {
+    /// `ripgrep 13.0.0 (registry+https://github.com/rust-lang/crates.io-index)`
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) version: String,
+    /// `registry+https://...`, `sparse+https://...`, `git+https://...#<commit>` or `path+file:///...`
+    pub(crate) source: String,
+    /// file names inside `CARGO_HOME/bin`
+    pub(crate) bins: Vec<String>,
+    /// None if only .crates.toml knows the package
+    pub(crate) features: Option<Vec<String>>,
+    pub(crate) all_features: bool,
+    pub(crate) no_default_features: bool,
+    /// the first line of "rustc -V" of the toolchain that built the package
+    pub(crate) rustc: Option<String>,
+}
+
+impl InstalledPackage {
+    fn new(id: &str, bins: Vec<String>) -> Self {
+        // "name version (source)"
+        let mut parts = id.splitn(3, ' ');
+        let name = parts.next().unwrap_or_default().to_string();
+        let version = parts.next().unwrap_or_default().to_string();
+        let source = parts
+            .next()
+            .unwrap_or_default()
+            .trim_start_matches('(')
+            .trim_end_matches(')')
+            .to_string();
+        Self {
+            id: id.to_string(),
+            name,
+            version,
+            source,
+            bins,
+            features: None,
+            all_features: false,
+            no_default_features: false,
+            rustc: None,
+        }
+    }
+
+    /// "crates-io", `git: https://github.com/foo/bar#0123abc` or "path: /home/user/foo"
+    fn source_description(&self, registries: &Registries) -> String {
+        let (kind, url) = self
+            .source
+            .split_once('+')
+            .unwrap_or(("", self.source.as_str()));
+        match kind {
+            "registry" | "sparse" => registries.by_source(&self.source).map_or_else(
+                || format!("registry: {}", url),
+                |registry| registry.name.clone(),
+            ),
+            "git" => {
+                // the query has the branch, tag or rev that was asked for, the fragment the commit
+                let (repo, commit) = url.split_once('#').unwrap_or((url, ""));
+                let repo = repo.split('?').next().unwrap_or(repo);
+                let commit = commit.get(..7).unwrap_or(commit);
+                format!("git: {}#{}", repo, commit)
+            }
+            "path" => format!("path: {}", url.strip_prefix("file://").unwrap_or(url)),
+            _ => self.source.clone(),
+        }
+    }
+
+    /// "default", "all", or the features with "no-default" in front if the defaults were disabled
+    fn features_description(&self) -> String {
+        let features = if let Some(features) = &self.features {
+            features
+        } else {
+            return String::new();
+        };
+        if self.all_features {
+            return String::from("all");
+        }
+        let mut description = Vec::new();
+        if self.no_default_features {
+            description.push("no-default");
+        } else if features.is_empty() {
+            description.push("default");
+        }
+        description.extend(features.iter().map(String::as_str));
+        description.join(",")
+    }
+
+    /// "1.56.0", or "1.57.0-nightly (2021-09-01)" for nightlies which all have the same version
+    fn toolchain_description(&self) -> String {
+        let rustc = if let Some(rustc) = &self.rustc {
+            rustc
+        } else {
+            return String::new();
+        };
+        // "rustc 1.57.0-nightly (c02371c44 2021-09-01)"
+        let mut words = rustc.split_whitespace().skip(1);
+        let version = words.next().unwrap_or_default();
+        match words.last() {
+            Some(date) if version.contains('-') => {
+                format!("{} ({})", version, date.trim_end_matches(')'))
+            }
+            _ => version.to_string(),
+        }
+    }
+}
+
+/// the [v1] table of .crates.toml: package id => binaries
+fn parse_crates_toml(content: &str) -> Vec<(String, Vec<String>)> {
+    let mut entries = Vec::new();
+    let mut in_v1 = false;
+    // the package id and the array of binaries up to here, it may span several lines
+    let mut current: Option<(String, String)> = None;
+    for line in content.lines() {
+        if let Some((id, mut value)) = current.take() {
+            value.push_str(line);
+            if value.contains(']') {
+                entries.push((id, quoted_strings(&value)));
+            } else {
+                current = Some((id, value));
+            }
+            continue;
+        }
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_v1 = line == "[v1]";
+            continue;
+        }
+        if !in_v1 {
+            continue;
+        }
+        let (id, value) = match line
+            .strip_prefix('"')
+            .and_then(|line| line.split_once('"'))
+            .and_then(|(id, rest)| Some((id, rest.trim_start().strip_prefix('=')?)))
+        {
+            Some(entry) => entry,
+            None => continue,
+        };
+        if value.contains(']') {
+            entries.push((id.to_string(), quoted_strings(value)));
+        } else {
+            current = Some((id.to_string(), value.to_string()));
+        }
+    }
+    entries
+}
+
+// the strings of a toml array like ["rg", "rg-debug"]
+fn quoted_strings(array: &str) -> Vec<String> {
+    array
+        .split('"')
+        .skip(1)
+        .step_by(2)
+        .map(ToString::to_string)
+        .collect()
+}
+
+fn string_array(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .map(|array| {
+            array
+                .iter()
+                .filter_map(Value::as_str)
+                .map(ToString::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// the install metadata of cargo in the `CARGO_HOME`
+#[derive(Debug, Clone, Default)]
+pub(crate) struct InstallMetadata {
+    /// the [v1] entries of .crates.toml, None if there is no such file
+    crates_toml: Option<Vec<(String, Vec<String>)>>,
+    /// .crates2.json, { "installs": { <package id>: { "bins": [...], "features": [...], ... } } }
+    crates2_json: Option<Value>,
+}
+
+impl InstallMetadata {
+    fn read(path: &Path) -> Result<Option<String>, Error> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Some(content)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(Error::InstallMetadataInvalid(
+                path.to_path_buf(),
+                error.to_string(),
+            )),
+        }
+    }
+
+    /// `.crates.toml` is read through `locked_crates_toml` if we hold the lock on it
+    pub(crate) fn load(
+        cargo_home: &Path,
+        locked_crates_toml: Option<&File>,
+    ) -> Result<Self, Error> {
+        let toml_path = cargo_home.join(".crates.toml");
+        let crates_toml = match locked_crates_toml {
+            Some(mut file) => {
+                let mut content = String::new();
+                let _ = file.read_to_string(&mut content).map_err(|error| {
+                    Error::InstallMetadataInvalid(toml_path.clone(), error.to_string())
+                })?;
+                Some(content)
+            }
+            None => Self::read(&toml_path)?,
+        }
+        .map(|content| parse_crates_toml(&content));
+        let json_path = cargo_home.join(".crates2.json");
+        let crates2_json = Self::read(&json_path)?
+            .map(|content| {
+                serde_json::from_str(&content).map_err(|error| {
+                    Error::InstallMetadataInvalid(json_path.clone(), error.to_string())
+                })
+            })
+            .transpose()?;
+        Ok(Self {
+            crates_toml,
+            crates2_json,
+        })
+    }
+
+    /// all installed packages, .crates2.json has more details but older cargos only wrote .crates.toml
+    pub(crate) fn packages(&self) -> Vec<InstalledPackage> {
+        let installs = self
+            .crates2_json
+            .as_ref()
+            .and_then(|json| json.get("installs"))
+            .and_then(Value::as_object);
+        let mut packages: Vec<InstalledPackage> = installs
+            .into_iter()
+            .flatten()
+            .map(|(id, install)| {
+                let mut package = InstalledPackage::new(id, string_array(&install["bins"]));
+                package.features = Some(string_array(&install["features"]));
+                package.all_features = install["all_features"].as_bool().unwrap_or(false);
+                package.no_default_features =
+                    install["no_default_features"].as_bool().unwrap_or(false);
+                package.rustc = install["rustc"]
+                    .as_str()
+                    .and_then(|rustc| rustc.lines().next())
+                    .map(ToString::to_string);
+                package
+            })
+            .collect();
+        for (id, bins) in self.crates_toml.iter().flatten() {
+            if !packages.iter().any(|package| &package.id == id) {
+                packages.push(InstalledPackage::new(id, bins.clone()));
+            }
+        }
+        packages.sort_by(|a, b| a.id.cmp(&b.id));
+        packages
+    }
+
+    /// drop packages from both files
+    fn remove(&mut self, ids: &[&str]) {
+        if let Some(entries) = &mut self.crates_toml {
+            entries.retain(|(id, _)| !ids.contains(&id.as_str()));
+        }
+        if let Some(installs) = self
+            .crates2_json
+            .as_mut()
+            .and_then(|json| json.get_mut("installs"))
+            .and_then(Value::as_object_mut)
+        {
+            for id in ids {
+                let _ = installs.remove(*id);
+            }
+        }
+    }
+
+    /// write back the files that existed, in the format cargo writes them. `.crates.toml` is written
+    /// through `locked_crates_toml` if we hold the lock on it, Windows does not allow a second handle to write
+    fn save(&self, cargo_home: &Path, locked_crates_toml: Option<&File>) -> Result<(), Error> {
+        let write = |path: PathBuf, content: String| {
+            fs::write(&path, content)
+                .map_err(|error| Error::InstallMetadataWriteFailed(path, error))
+        };
+        if let Some(entries) = &self.crates_toml {
+            let content = crates_toml_content(entries);
+            match locked_crates_toml {
+                // like the install tracker of cargo
+                Some(mut file) => file
+                    .set_len(0)
+                    .and_then(|()| file.seek(SeekFrom::Start(0)))
+                    .and_then(|_| file.write_all(content.as_bytes()))
+                    .map_err(|error| {
+                        Error::InstallMetadataWriteFailed(cargo_home.join(".crates.toml"), error)
+                    })?,
+                None => write(cargo_home.join(".crates.toml"), content)?,
+            }
+        }
+        if let Some(json) = &self.crates2_json {
+            // serializing a Value can't fail
+            write(
+                cargo_home.join(".crates2.json"),
+                serde_json::to_string(json).unwrap(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// the inverse of parse_crates_toml
+fn crates_toml_content(entries: &[(String, Vec<String>)]) -> String {
+    let mut content = String::from("[v1]\n");
+    for (id, bins) in entries {
+        let bins: Vec<String> = bins.iter().map(|bin| format!("\"{}\"", bin)).collect();
+        let _ = writeln!(content, "\"{}\" = [{}]", id, bins.join(", "));
+    }
+    content
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn is_rustup_proxy(bin: &str) -> bool {
+    RUSTUP_PROXIES.contains(&bin.strip_suffix(".exe").unwrap_or(bin))
+}
+
+/// the binaries without install metadata and the metadata entries whose binary is gone
+fn mismatches<'a>(
+    packages: &'a [InstalledPackage],
+    binaries: &[PathBuf],
+) -> (Vec<PathBuf>, Vec<(&'a InstalledPackage, &'a str)>) {
+    let without_metadata = binaries
+        .iter()
+        .filter(|binary| {
+            let name = file_name(binary);
+            !is_rustup_proxy(&name) && !packages.iter().any(|package| package.bins.contains(&name))
+        })
+        .cloned()
+        .collect();
+    let without_binary = packages
+        .iter()
+        .flat_map(|package| package.bins.iter().map(move |bin| (package, bin.as_str())))
+        .filter(|(_, bin)| !binaries.iter().any(|binary| file_name(binary) == *bin))
+        .collect();
+    (without_metadata, without_binary)
+}
+
+/// "cargo cache bin": print the installed binaries and the packages they come from
+pub(crate) fn bin_inventory(
+    ccd: &CargoCachePaths,
+    bin_cache: &mut BinaryCache,
+) -> Result<(), Error> {
+    let metadata = InstallMetadata::load(&ccd.cargo_home, None)?;
+    let packages = metadata.packages();
+    let registries = Registries::load(&ccd.cargo_home);
+    let mut binaries = bin_cache.files().to_vec();
+    binaries.sort();
+    let human = |size: u64| size.file_size(file_size_opts::DECIMAL).unwrap();
+
+    let mut table = vec![vec![
+        String::from("Binary"),
+        String::from("Package"),
+        String::from("Version"),
+        String::from("Source"),
+        String::from("Features"),
+        String::from("Toolchain"),
+        String::from("Size"),
+    ]];
+    for binary in &binaries {
+        let name = file_name(binary);
+        if let Some(package) = packages.iter().find(|package| package.bins.contains(&name)) {
+            table.push(vec![
+                name,
+                package.name.clone(),
+                package.version.clone(),
+                package.source_description(&registries),
+                package.features_description(),
+                package.toolchain_description(),
+                human(size_of_file(binary)),
+            ]);
+        }
+    }
+
+    println!("Installed binaries in '{}':\n", ccd.bin_dir.display());
+    if table.len() == 1 {
+        println!("No binaries installed with \"cargo install\"");
+    } else {
+        println!("{}", format_table(&table, 1).trim_end());
+    }
+
+    let (without_metadata, without_binary) = mismatches(&packages, &binaries);
+    if !without_metadata.is_empty() {
+        println!("\nBinaries without install metadata, not installed with \"cargo install\":");
+        for binary in &without_metadata {
+            println!("  {} ({})", file_name(binary), human(size_of_file(binary)));
+        }
+    }
+    if !without_binary.is_empty() {
+        println!("\nInstall metadata without binary, \"cargo install --list\" still shows these:");
+        for (package, bin) in &without_binary {
+            println!("  {} {}: {}", package.name, package.version, bin);
+        }
+    }
+    Ok(())
+}
+
+/// when one of the binaries of the package was last used, None if none of them exists
+fn last_used(package: &InstalledPackage, binaries: &[PathBuf]) -> Option<NaiveDateTime> {
+    binaries
+        .iter()
+        .filter(|binary| package.bins.contains(&file_name(binary)))
+        .filter_map(|binary| last_access(binary))
+        .map(|accessed| DateTime::<Local>::from(accessed).naive_local())
+        .max()
+}
+
+/// the packages named in `names` ("ripgrep,cargo-cache")
+fn packages_by_name<'a>(
+    packages: &'a [InstalledPackage],
+    names: &str,
+) -> Result<Vec<&'a InstalledPackage>, Error> {
+    let mut selected = Vec::new();
+    for name in names.split(',').map(str::trim) {
+        let before = selected.len();
+        selected.extend(packages.iter().filter(|package| package.name == name));
+        if selected.len() == before {
+            return Err(Error::BinNotInstalled(name.to_string()));
+        }
+    }
+    Ok(selected)
+}
+
+/// take the lock "cargo install" holds on .crates.toml while it updates the install metadata,
+/// None if there is no .crates.toml. The lock is released when the file is dropped.
+fn lock_install_metadata(cargo_home: &Path) -> Result<Option<File>, Error> {
+    let path = cargo_home.join(".crates.toml");
+    let file = match OpenOptions::new().read(true).write(true).open(&path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(Error::InstallMetadataWriteFailed(path, error)),
+    };
+    match file.try_lock_exclusive() {
+        Ok(()) => {}
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+            // same message as cargo
+            eprintln!("Blocking waiting for file lock on crate metadata");
+            file.lock_exclusive()
+                .map_err(|error| Error::InstallMetadataWriteFailed(path, error))?;
+        }
+        Err(e) => {
+            // cargo does not lock either if the filesystem does not support it
+            eprintln!(
+                "Warning: failed to lock \"{}\", continuing without lock: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+    Ok(Some(file))
+}
+
+/// "cargo cache bin --remove / --remove-unused-since": remove installed packages, both their binaries
+/// and their entries in .crates.toml and .crates2.json so "cargo install --list" stays correct
+pub(crate) fn remove_installed(
+    ccd: &CargoCachePaths,
+    bin_cache: &mut BinaryCache,
+    names: Option<&str>,
+    unused_since: Option<&str>,
+    dry_run: bool,
+    size_changed: &mut bool,
+) -> Result<(), Error> {
+    if trash::enabled() && !dry_run {
+        return Err(Error::BinRemoveWithTrash);
+    }
+    // a concurrent "cargo install" must not write its entries between our load and save
+    let lock = if dry_run {
+        None
+    } else {
+        lock_install_metadata(&ccd.cargo_home)?
+    };
+    let mut metadata = InstallMetadata::load(&ccd.cargo_home, lock.as_ref())?;
+    let packages = metadata.packages();
+    let binaries = bin_cache.files().to_vec();
+
+    let selected: Vec<&InstalledPackage> = match (names, unused_since) {
+        (Some(names), _) => packages_by_name(&packages, names)?,
+        (None, Some(date)) => {
+            let since = parse_date(date)?;
+            // metadata without binary is never used
+            packages
+                .iter()
+                .filter(|package| last_used(package, &binaries).map_or(true, |used| used < since))
+                .collect()
+        }
+        (None, None) => Vec::new(),
+    };
+    if selected.is_empty() {
+        println!("No installed packages to remove");
+        return Ok(());
+    }
+
+    for package in &selected {
+        for bin in &package.bins {
+            let path = ccd.bin_dir.join(bin);
+            if fs::symlink_metadata(&path).is_ok() {
+                remove_with_default_message(&path, dry_run, size_changed, None);
+            }
+        }
+        if dry_run {
+            println!(
+                "dry-run: would remove {} {} from .crates.toml and .crates2.json",
+                package.name, package.version
+            );
+        } else {
+            println!(
+                "removing {} {} from .crates.toml and .crates2.json",
+                package.name, package.version
+            );
+        }
+    }
+    if dry_run {
+        return Ok(());
+    }
+    let ids: Vec<&str> = selected.iter().map(|package| package.id.as_str()).collect();
+    metadata.remove(&ids);
+    metadata.save(&ccd.cargo_home, lock.as_ref())
+}
+
+#[cfg(test)]
+mod installedtests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const CRATES_TOML: &str = r#"[v1]
+"cargo-cache 0.6.3 (registry+https://github.com/rust-lang/crates.io-index)" = ["cargo-cache"]
+"old-tool 0.1.0 (path+file:///home/user/old-tool)" = [
+    "old-tool",
+    "old-helper",
+]
+"ripgrep 13.0.0 (registry+https://github.com/rust-lang/crates.io-index)" = ["rg"]
+"#;
+
+    const CRATES2_JSON: &str = r#"{"installs":{
+"cargo-cache 0.6.3 (registry+https://github.com/rust-lang/crates.io-index)":{"version_req":null,"bins":["cargo-cache"],"features":[],"all_features":false,"no_default_features":false,"profile":"release","target":"x86_64-unknown-linux-gnu","rustc":"rustc 1.56.0 (09c42c458 2021-10-18)\nbinary: rustc\nhost: x86_64-unknown-linux-gnu"},
+"ripgrep 13.0.0 (registry+https://github.com/rust-lang/crates.io-index)":{"version_req":null,"bins":["rg"],"features":["pcre2"],"all_features":false,"no_default_features":false,"profile":"release","target":"x86_64-unknown-linux-gnu","rustc":"rustc 1.57.0-nightly (c02371c44 2021-09-01)\nbinary: rustc"},
+"tool 0.2.0 (git+https://github.com/user/tool?branch=main#0123456789abcdef)":{"version_req":null,"bins":["tool"],"features":[],"all_features":true,"no_default_features":false,"profile":"release","target":"x86_64-unknown-linux-gnu","rustc":null}
+}}"#;
+
+    #[test]
+    fn bin_install_metadata() {
+        let metadata = InstallMetadata {
+            crates_toml: Some(parse_crates_toml(CRATES_TOML)),
+            crates2_json: Some(serde_json::from_str(CRATES2_JSON).unwrap()),
+        };
+        assert_eq!(
+            metadata.crates_toml.as_ref().unwrap()[1],
+            (
+                String::from("old-tool 0.1.0 (path+file:///home/user/old-tool)"),
+                vec![String::from("old-tool"), String::from("old-helper")]
+            )
+        );
+
+        let packages = metadata.packages();
+        let registries = Registries::from_configs(&[]);
+        let described: Vec<(String, String, String, String)> = packages
+            .iter()
+            .map(|package| {
+                (
+                    package.name.clone(),
+                    package.source_description(&registries),
+                    package.features_description(),
+                    package.toolchain_description(),
+                )
+            })
+            .collect();
+        let expected = [
+            ("cargo-cache", "crates-io", "default", "1.56.0"),
+            ("old-tool", "path: /home/user/old-tool", "", ""),
+            (
+                "ripgrep",
+                "crates-io",
+                "pcre2",
+                "1.57.0-nightly (2021-09-01)",
+            ),
+            (
+                "tool",
+                "git: https://github.com/user/tool#0123456",
+                "all",
+                "",
+            ),
+        ];
+        assert_eq!(
+            described
+                .iter()
+                .map(|(a, b, c, d)| (a.as_str(), b.as_str(), c.as_str(), d.as_str()))
+                .collect::<Vec<_>>(),
+            expected
+        );
+
+        let binaries: Vec<PathBuf> = ["cargo", "cargo-cache", "copied", "old-tool", "rg", "tool"]
+            .iter()
+            .map(|bin| PathBuf::from("bin").join(bin))
+            .collect();
+        let (without_metadata, without_binary) = mismatches(&packages, &binaries);
+        assert_eq!(without_metadata, vec![PathBuf::from("bin/copied")]);
+        assert_eq!(without_binary.len(), 1);
+        assert_eq!(without_binary[0].0.name, "old-tool");
+        assert_eq!(without_binary[0].1, "old-helper");
+    }
+
+    #[test]
+    fn installed_remove() {
+        let cargo_home = PathBuf::from("target/installed_remove");
+        let _ = fs::remove_dir_all(&cargo_home);
+        fs::create_dir_all(&cargo_home).unwrap();
+        fs::write(cargo_home.join(".crates.toml"), CRATES_TOML).unwrap();
+        fs::write(cargo_home.join(".crates2.json"), CRATES2_JSON).unwrap();
+
+        // load and save go through the locked file, like remove_installed() does
+        let locked = lock_install_metadata(&cargo_home).unwrap();
+        let mut metadata = InstallMetadata::load(&cargo_home, locked.as_ref()).unwrap();
+        let packages = metadata.packages();
+        let selected = packages_by_name(&packages, "old-tool, ripgrep").unwrap();
+        assert_eq!(selected.len(), 2);
+        assert!(matches!(
+            packages_by_name(&packages, "ripgrep,fd"),
+            Err(Error::BinNotInstalled(name)) if name == "fd"
+        ));
+
+        let ids: Vec<&str> = selected.iter().map(|package| package.id.as_str()).collect();
+        metadata.remove(&ids);
+        metadata.save(&cargo_home, locked.as_ref()).unwrap();
+        drop(locked);
+        assert_eq!(
+            fs::read_to_string(cargo_home.join(".crates.toml")).unwrap(),
+            "[v1]\n\"cargo-cache 0.6.3 (registry+https://github.com/rust-lang/crates.io-index)\" = [\"cargo-cache\"]\n"
+        );
+        let remaining: Vec<String> = InstallMetadata::load(&cargo_home, None)
+            .unwrap()
+            .packages()
+            .into_iter()
+            .map(|package| package.name)
+            .collect();
+        assert_eq!(remaining, vec!["cargo-cache", "tool"]);
+
+        // cargo install can't take the lock while we hold it
+        let lock = lock_install_metadata(&cargo_home).unwrap().unwrap();
+        let other = File::open(cargo_home.join(".crates.toml")).unwrap();
+        assert!(other.try_lock_exclusive().is_err());
+        drop(lock);
+        assert!(other.try_lock_exclusive().is_ok());
+        assert!(lock_install_metadata(&cargo_home.join("missing"))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,360 @@
+// Copyright 2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// "cargo cache migrate-registry <from> <to>" command
+// When the directory of a registry changes (crates.io moved from the git index "github.com-1ecc6299db9ec823"
+// to the sparse "index.crates.io-6f17d22bba15001f", cargo 1.85 changed the hash of all the names), cargo no
+// longer uses the .crate archives in the old directory and downloads all of them again.
+// We move (or hardlink) the archives whose sha256 matches the checksum in the index of the new registry into
+// its directory. Whether cargo still uses the old directories can't be told from here (crates.io's git index is
+// configured forever), so they are only removed on request (--remove-old) once all archives were migrated.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cache::caches::get_cache_name;
+use crate::cache::inventory;
+use crate::library::{registry_matches, size_of_path, CargoCachePaths, Error};
+use crate::registries::{archive_checksum, index_checksums, Registries};
+use crate::remove::{parse_version, remove_file, remove_with_default_message, DryRunMessage};
+
+use humansize::{file_size_opts, FileSize};
+
+fn dir_names(dir: &Path) -> Vec<String> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter(|entry| entry.path().is_dir())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// the directory name of a registry in registry/cache or registry/index, `wanted` can be the
+/// directory name, the host ("github.com") or the configured name of the registry ("crates-io")
+fn resolve_registry(
+    ccd: &CargoCachePaths,
+    registries: &Registries,
+    wanted: &str,
+) -> Result<String, Error> {
+    let mut candidates: Vec<String> = dir_names(&ccd.registry_pkg_cache)
+        .into_iter()
+        .chain(dir_names(&ccd.registry_index))
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+    if candidates.iter().any(|name| name == wanted) {
+        return Ok(wanted.to_string());
+    }
+
+    candidates.retain(|name| {
+        registry_matches(&get_cache_name(Path::new(name)), wanted)
+            || registries
+                .lookup(name)
+                .map_or(false, |registry| registry.name == wanted)
+    });
+    match candidates.len() {
+        0 => Err(Error::MigrateRegistryNotFound(wanted.to_string())),
+        1 => Ok(candidates.remove(0)),
+        _ => Err(Error::MigrateRegistryAmbiguous(
+            wanted.to_string(),
+            candidates,
+        )),
+    }
+}
+
+/// why an archive could not be migrated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Skipped {
+    NotInIndex,
+    ChecksumMismatch,
+}
+
+/// "cargo cache migrate-registry": move the .crate archives of `from` into the registry `to`
+pub(crate) fn migrate_registry(
+    ccd: &CargoCachePaths,
+    from: &str,
+    to: &str,
+    hardlink: bool,
+    remove_old: bool,
+    dry_run: bool,
+    size_changed: &mut bool,
+) -> Result<(), Error> {
+    let registries = Registries::load(&ccd.cargo_home);
+    let from = resolve_registry(ccd, &registries, from)?;
+    let to = resolve_registry(ccd, &registries, to)?;
+    if from == to {
+        return Err(Error::MigrateRegistrySame(from));
+    }
+    let target_index = ccd.registry_index.join(&to);
+    if !target_index.is_dir() {
+        return Err(Error::MigrateRegistryNoIndex(to));
+    }
+    let source = ccd.registry_pkg_cache.join(&from);
+    let target = ccd.registry_pkg_cache.join(&to);
+
+    let mut archives: Vec<PathBuf> = fs::read_dir(&source)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().map_or(false, |ext| ext == "crate"))
+                .collect()
+        })
+        .unwrap_or_default();
+    archives.sort();
+
+    let mut checksums: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut migrated: (usize, u64) = (0, 0);
+    let mut duplicates: (usize, u64) = (0, 0);
+    let mut skipped: Vec<Skipped> = Vec::new();
+    if !dry_run && !archives.is_empty() {
+        fs::create_dir_all(&target)
+            .map_err(|error| Error::MigrateRegistryFailed(target.clone(), error))?;
+    }
+
+    for archive in &archives {
+        let (name, version) = parse_version(archive)?;
+        let expected = checksums
+            .entry(name.clone())
+            .or_insert_with(|| index_checksums(&target_index, &name))
+            .get(&version);
+        let expected = if let Some(checksum) = expected {
+            checksum
+        } else {
+            skipped.push(Skipped::NotInIndex);
+            continue;
+        };
+        let checksum = archive_checksum(archive)
+            .map_err(|error| Error::MigrateRegistryFailed(archive.clone(), error))?;
+        if &checksum != expected {
+            skipped.push(Skipped::ChecksumMismatch);
+            continue;
+        }
+
+        let size = size_of_path(archive);
+        let destination = target.join(archive.file_name().unwrap());
+        let failed = |error| Error::MigrateRegistryFailed(archive.clone(), error);
+        if destination.exists() {
+            // cargo downloaded it again already, keep only one copy
+            duplicates.0 += 1;
+            duplicates.1 += size;
+            if hardlink {
+                if !dry_run {
+                    fs::remove_file(&destination).map_err(failed)?;
+                    fs::hard_link(archive, &destination).map_err(failed)?;
+                    *size_changed = true;
+                }
+            } else {
+                remove_file(
+                    archive,
+                    dry_run,
+                    size_changed,
+                    None,
+                    &DryRunMessage::None,
+                    Some(size),
+                );
+            }
+            continue;
+        }
+
+        migrated.0 += 1;
+        migrated.1 += size;
+        if dry_run {
+            continue;
+        }
+        if hardlink {
+            fs::hard_link(archive, &destination).map_err(failed)?;
+        } else {
+            fs::rename(archive, &destination).map_err(failed)?;
+        }
+        *size_changed = true;
+    }
+
+    // we moved and linked the archives behind the back of the inventory
+    if !dry_run {
+        inventory::rescan(&source);
+        inventory::rescan(&target);
+    }
+
+    let human = |size: u64| size.file_size(file_size_opts::DECIMAL).unwrap();
+    let action = match (dry_run, hardlink) {
+        (true, true) => "dry-run: would link",
+        (true, false) => "dry-run: would move",
+        (false, true) => "Linked",
+        (false, false) => "Moved",
+    };
+    println!(
+        "{} {} crate archives ({}) from {} to {}",
+        action,
+        migrated.0,
+        human(migrated.1),
+        from,
+        to
+    );
+    if duplicates.0 > 0 {
+        println!(
+            "{} {} crate archives ({}) that were already downloaded again",
+            match (dry_run, hardlink) {
+                (true, true) => "dry-run: would deduplicate",
+                (true, false) => "dry-run: would remove",
+                (false, true) => "Deduplicated",
+                (false, false) => "Removed",
+            },
+            duplicates.0,
+            human(duplicates.1),
+        );
+    }
+
+    let not_in_index = skipped
+        .iter()
+        .filter(|s| **s == Skipped::NotInIndex)
+        .count();
+    let mismatched = skipped.len() - not_in_index;
+    if !skipped.is_empty() {
+        // the old registry still has archives that cargo may need, keep its directories
+        println!(
+            "Kept {} crate archives in {}: {} are not in the index of {}, {} do not match its checksums",
+            skipped.len(),
+            from,
+            not_in_index,
+            to,
+            mismatched
+        );
+        return Ok(());
+    }
+    // nothing was migrated, there is no reason to believe the old directories are unused
+    if hardlink || migrated.0 + duplicates.0 == 0 {
+        return Ok(());
+    }
+
+    // everything was migrated, cargo may or may not use the old directories again
+    let old_dirs: Vec<PathBuf> = [
+        &ccd.registry_pkg_cache,
+        &ccd.registry_sources,
+        &ccd.registry_index,
+    ]
+    .iter()
+    .map(|dir| dir.join(&from))
+    .filter(|dir| dir.is_dir())
+    .collect();
+    if old_dirs.is_empty() {
+        return Ok(());
+    }
+    if !remove_old {
+        println!(
+            "All crate archives of {} are migrated, if cargo does not use it anymore remove its directories with --remove-old:",
+            from
+        );
+        for dir in &old_dirs {
+            println!("'{}' ({})", dir.display(), human(size_of_path(dir)));
+        }
+        return Ok(());
+    }
+    for dir in &old_dirs {
+        remove_with_default_message(dir, dry_run, size_changed, Some(size_of_path(dir)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod migrate_registry_tests {
+    use super::*;
+    use crate::cache::caches::{RegistrySubCache, RegistrySuperCache};
+    use crate::cache::registry_pkg_cache::RegistryPkgCaches;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn migrate_registry_updates_inventory() {
+        let _scanning = inventory::SCANNING.lock().unwrap();
+        let cargo_home = PathBuf::from("target/migrate_registry_updates_inventory/.cargo");
+        let _ = fs::remove_dir_all(&cargo_home);
+        let from = "github.com-1ecc6299db9ec823";
+        let to = "index.crates.io-6f17d22bba15001f";
+        let old_cache = cargo_home.join("registry/cache").join(from);
+        fs::create_dir_all(&old_cache).unwrap();
+        let archive = old_cache.join("foo-1.0.0.crate");
+        fs::write(&archive, vec![1; 2_000]).unwrap();
+        // the sparse index has the plain index file, one json line per version
+        let index_file = cargo_home.join("registry/index").join(to).join("3/f/foo");
+        fs::create_dir_all(index_file.parent().unwrap()).unwrap();
+        fs::write(
+            &index_file,
+            format!(
+                "{{\"name\":\"foo\",\"vers\":\"1.0.0\",\"cksum\":\"{}\"}}\n",
+                archive_checksum(&archive).unwrap()
+            ),
+        )
+        .unwrap();
+
+        let ccd = CargoCachePaths::new(cargo_home.clone()).unwrap();
+        inventory::scan(&cargo_home);
+        let mut size_changed = false;
+        migrate_registry(&ccd, from, to, false, true, false, &mut size_changed).unwrap();
+        assert!(size_changed);
+
+        let mut registry_pkg_caches = RegistryPkgCaches::new(ccd.registry_pkg_cache.clone());
+        assert_eq!(registry_pkg_caches.total_size(), 2_000);
+        assert_eq!(registry_pkg_caches.total_number_of_files(), 1);
+        let caches = registry_pkg_caches.caches();
+        assert_eq!(caches.len(), 1);
+        assert_eq!(
+            caches[0].files(),
+            [cargo_home
+                .join("registry/cache")
+                .join(to)
+                .join("foo-1.0.0.crate")]
+        );
+    }
+
+    #[test]
+    fn migrate_registry_keeps_old_directories() {
+        let cargo_home = PathBuf::from("target/migrate_registry_keeps_old_directories/.cargo");
+        let _ = fs::remove_dir_all(&cargo_home);
+        let from = "github.com-1ecc6299db9ec823";
+        let to = "index.crates.io-6f17d22bba15001f";
+        let old_index = cargo_home.join("registry/index").join(from);
+        let old_sources = cargo_home.join("registry/src").join(from).join("foo-1.0.0");
+        fs::create_dir_all(&old_index).unwrap();
+        fs::create_dir_all(&old_sources).unwrap();
+        fs::create_dir_all(cargo_home.join("registry/index").join(to)).unwrap();
+        let ccd = CargoCachePaths::new(cargo_home.clone()).unwrap();
+        let mut size_changed = false;
+
+        // there was nothing to migrate, that does not make the old registry unused
+        migrate_registry(&ccd, from, to, false, true, false, &mut size_changed).unwrap();
+        assert!(old_index.is_dir());
+        assert!(old_sources.is_dir());
+
+        // everything was migrated, but the removal was not asked for
+        let old_cache = cargo_home.join("registry/cache").join(from);
+        fs::create_dir_all(&old_cache).unwrap();
+        let archive = old_cache.join("foo-1.0.0.crate");
+        fs::write(&archive, "foo").unwrap();
+        let index_file = cargo_home.join("registry/index").join(to).join("3/f/foo");
+        fs::create_dir_all(index_file.parent().unwrap()).unwrap();
+        fs::write(
+            &index_file,
+            format!(
+                "{{\"name\":\"foo\",\"vers\":\"1.0.0\",\"cksum\":\"{}\"}}\n",
+                archive_checksum(&archive).unwrap()
+            ),
+        )
+        .unwrap();
+        migrate_registry(&ccd, from, to, false, false, false, &mut size_changed).unwrap();
+        assert!(size_changed);
+        assert!(!archive.exists());
+        assert!(old_cache.is_dir());
+        assert!(old_index.is_dir());
+        assert!(old_sources.is_dir());
+    }
+}
@@ -8,8 +8,15 @@
 // except according to those terms.
 
 // code related to subcommands is located here
+pub(crate) mod ci_key;
+pub(crate) mod doctor;
+pub(crate) mod extract;
+pub(crate) mod installed;
 pub(crate) mod local;
+pub(crate) mod migrate_registry;
+pub(crate) mod prune_index;
 pub(crate) mod query;
 pub(crate) mod sccache;
 pub(crate) mod toolchains;
 pub(crate) mod trim;
+pub(crate) mod watch;
@@ -0,0 +1,366 @@
+// Copyright 2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// "cargo cache prune-index" command
+// cargo keeps the index entry of every crate it ever resolved in registry/index/<registry>/.cache,
+// over time these add up to tens of thousands of files. An entry is only needed to resolve the crate
+// again, so we remove those of crates that are neither in the cache nor were used recently, or all
+// but those of the crates in some lockfiles. Cargo fetches removed entries again when it needs them.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::audit;
+use crate::cache::caches::*;
+use crate::cache::*;
+use crate::library::{last_access, size_of_path, CargoCachePaths, Error};
+use crate::lockfile::read_lockfile;
+use crate::remove::parse_version;
+use crate::trash;
+
+use humansize::{file_size_opts, FileSize};
+use walkdir::WalkDir;
+
+/// which index cache entries are kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Keep {
+    /// those of crates with an archive in the cache of the registry or that were used within the duration
+    CachedOrUsedWithin(Duration),
+    /// only those of the crates in these Cargo.locks
+    Locked(Vec<PathBuf>),
+}
+
+/// an entry of the .cache of an index
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IndexCacheEntry {
+    /// the directory name of the registry, "index.crates.io-6f17d22bba15001f"
+    registry: String,
+    /// the lowercase name of the crate
+    name: String,
+    path: PathBuf,
+}
+
+/// all entries of the .cache directories of the indices
+fn index_cache_entries(registry_index: &Path) -> Vec<IndexCacheEntry> {
+    let mut entries: Vec<IndexCacheEntry> = WalkDir::new(registry_index)
+        .min_depth(1)
+        .max_depth(1)
+        .into_iter()
+        .filter_map(Result::ok)
+        .flat_map(|index| {
+            let registry = index.file_name().to_string_lossy().into_owned();
+            // entries are at least two levels deep: "1/a", "3/f/foo", "se/rd/serde"
+            WalkDir::new(index.path().join(".cache"))
+                .min_depth(2)
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_type().is_file())
+                .map(move |entry| IndexCacheEntry {
+                    registry: registry.clone(),
+                    name: entry.file_name().to_string_lossy().to_lowercase(),
+                    path: entry.into_path(),
+                })
+        })
+        .collect();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    entries
+}
+
+/// (registry, lowercase crate name) of every archive in the cache
+fn cached_crates(archives: &[PathBuf]) -> HashSet<(String, String)> {
+    archives
+        .iter()
+        .filter_map(|archive| {
+            let registry = archive.parent()?.file_name()?.to_str()?.to_string();
+            let (name, _version) = parse_version(archive).ok()?;
+            Some((registry, name.to_lowercase()))
+        })
+        .collect()
+}
+
+/// the entries that `keep` does not keep
+fn entries_to_remove(
+    entries: Vec<IndexCacheEntry>,
+    keep: &Keep,
+    archives: &[PathBuf],
+) -> Result<Vec<IndexCacheEntry>, Error> {
+    Ok(match keep {
+        Keep::CachedOrUsedWithin(duration) => {
+            let cached = cached_crates(archives);
+            let used_since = SystemTime::now()
+                .checked_sub(*duration)
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            entries
+                .into_iter()
+                .filter(|entry| !cached.contains(&(entry.registry.clone(), entry.name.clone())))
+                .filter(|entry| last_access(&entry.path).map_or(true, |time| time < used_since))
+                .collect()
+        }
+        Keep::Locked(lockfiles) => {
+            // a Cargo.lock lists the whole dependency tree, we don't need to resolve anything
+            let mut locked = HashSet::new();
+            for lockfile in lockfiles {
+                locked.extend(
+                    read_lockfile(lockfile)?
+                        .into_iter()
+                        .map(|package| package.name.to_lowercase()),
+                );
+            }
+            entries
+                .into_iter()
+                .filter(|entry| !locked.contains(&entry.name))
+                .collect()
+        }
+    })
+}
+
+/// remove the `entries` of the index cache `cache` (or move them to the trash), returns their size.
+/// There are tens of thousands of them, so unlike `remove_file()` the inventory is updated and the
+/// audit log written once for the whole cache instead of once per entry.
+fn remove_entries(
+    cargo_home: &Path,
+    cache: &Path,
+    entries: &[&IndexCacheEntry],
+    size_changed: &mut bool,
+) -> u64 {
+    let trash = trash::enabled();
+    let mut removed_size = 0;
+    let mut removed_any = false;
+    for entry in entries {
+        let size = size_of_path(&entry.path);
+        let removed = if trash {
+            trash::move_to_trash_unscanned(cargo_home, &entry.path)
+                .map_err(|error| eprintln!("Warning: {}", error))
+                .is_ok()
+        } else {
+            fs::remove_file(&entry.path)
+                .map_err(|_| {
+                    eprintln!(
+                        "Warning: failed to remove file \"{}\".",
+                        entry.path.display()
+                    );
+                })
+                .is_ok()
+        };
+        if removed {
+            removed_size += size;
+            removed_any = true;
+        }
+    }
+    if !removed_any {
+        return 0;
+    }
+    *size_changed = true;
+
+    inventory::rescan(cache);
+    let action = if trash {
+        if let Ok(trashed) = trash::trash_path(cargo_home, cache) {
+            inventory::rescan(&trashed);
+        }
+        audit::Action::Trash
+    } else {
+        audit::Action::Remove
+    };
+    audit::record(cargo_home, cache, action, removed_size);
+    removed_size
+}
+
+/// remove the index cache entries that `keep` does not keep and invalidate the index cache
+pub(crate) fn prune_index(
+    ccd: &CargoCachePaths,
+    keep: &Keep,
+    registry_pkg_caches: &mut registry_pkg_cache::RegistryPkgCaches,
+    registry_index_caches: &mut registry_index::RegistryIndicesCache,
+    dry_run: bool,
+    size_changed: &mut bool,
+) -> Result<(), Error> {
+    let entries = index_cache_entries(&ccd.registry_index);
+    let number_of_entries = entries.len();
+    let removals = entries_to_remove(entries, keep, registry_pkg_caches.items())?;
+
+    // one line per entry would be tens of thousands of lines, print the sum instead
+    let removed_size: u64 = if dry_run {
+        removals.iter().map(|entry| size_of_path(&entry.path)).sum()
+    } else {
+        let mut registries: Vec<&str> = removals
+            .iter()
+            .map(|entry| entry.registry.as_str())
+            .collect();
+        registries.dedup();
+        registries
+            .into_iter()
+            .map(|registry| {
+                let of_registry: Vec<&IndexCacheEntry> = removals
+                    .iter()
+                    .filter(|entry| entry.registry == registry)
+                    .collect();
+                let cache = ccd.registry_index.join(registry).join(".cache");
+                remove_entries(&ccd.cargo_home, &cache, &of_registry, size_changed)
+            })
+            .sum()
+    };
+    registry_index_caches.invalidate();
+
+    println!(
+        "{} {} of {} index cache entries, {}",
+        if dry_run {
+            "dry-run: would remove"
+        } else {
+            "Removed"
+        },
+        removals.len(),
+        number_of_entries,
+        removed_size.file_size(file_size_opts::DECIMAL).unwrap()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod prune_index_tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+
+    fn names(entries: &[IndexCacheEntry]) -> Vec<(&str, &str)> {
+        entries
+            .iter()
+            .map(|entry| (entry.registry.as_str(), entry.name.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn prune_index_entries() {
+        let cargo_home = PathBuf::from("target/prune_index_entries/.cargo");
+        let _ = fs::remove_dir_all(&cargo_home);
+        let sparse = "index.crates.io-6f17d22bba15001f";
+        let git = "github.com-1ecc6299db9ec823";
+        for (registry, entry) in &[
+            (sparse, "1/a"),
+            (sparse, "3/f/foo"),
+            (sparse, "se/rd/serde"),
+            (git, "se/rd/serde"),
+        ] {
+            let path = cargo_home
+                .join("registry/index")
+                .join(registry)
+                .join(".cache")
+                .join(entry);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "entry").unwrap();
+        }
+        // not an index entry
+        fs::write(
+            cargo_home
+                .join("registry/index")
+                .join(sparse)
+                .join(".cache/config.json"),
+            "{}",
+        )
+        .unwrap();
+        let archives = vec![
+            cargo_home
+                .join("registry/cache")
+                .join(sparse)
+                .join("Serde-1.0.0.crate"),
+            cargo_home
+                .join("registry/cache")
+                .join(git)
+                .join("a-0.1.0-rc.1.crate"),
+        ];
+
+        let entries = index_cache_entries(&cargo_home.join("registry/index"));
+        assert_eq!(
+            names(&entries),
+            vec![
+                (git, "serde"),
+                (sparse, "a"),
+                (sparse, "foo"),
+                (sparse, "serde"),
+            ]
+        );
+
+        // everything was used just now
+        let week = Duration::from_secs(60 * 60 * 24 * 7);
+        let recently_used =
+            entries_to_remove(entries.clone(), &Keep::CachedOrUsedWithin(week), &archives);
+        assert_eq!(recently_used.unwrap(), vec![]);
+
+        // the archives are only kept in the registry they are cached for
+        let unused = entries_to_remove(
+            entries.clone(),
+            &Keep::CachedOrUsedWithin(Duration::from_secs(0)),
+            &archives,
+        );
+        assert_eq!(
+            names(&unused.unwrap()),
+            vec![(git, "serde"), (sparse, "a"), (sparse, "foo")]
+        );
+
+        let lockfile = cargo_home.join("Cargo.lock");
+        fs::write(
+            &lockfile,
+            "[[package]]\nname = \"foo\"\nversion = \"1.0.0\"\n\
+            source = \"registry+https://github.com/rust-lang/crates.io-index\"\n",
+        )
+        .unwrap();
+        let unlocked = entries_to_remove(entries, &Keep::Locked(vec![lockfile]), &archives);
+        assert_eq!(
+            names(&unlocked.unwrap()),
+            vec![(git, "serde"), (sparse, "a"), (sparse, "serde")]
+        );
+    }
+
+    #[test]
+    fn prune_index_one_audit_record_per_registry() {
+        let cargo_home = PathBuf::from("target/prune_index_one_audit_record_per_registry/.cargo");
+        let _ = fs::remove_dir_all(&cargo_home);
+        for registry in &[
+            "index.crates.io-6f17d22bba15001f",
+            "github.com-1ecc6299db9ec823",
+        ] {
+            for entry in &["1/a", "3/f/foo", "se/rd/serde"] {
+                let path = cargo_home
+                    .join("registry/index")
+                    .join(registry)
+                    .join(".cache")
+                    .join(entry);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, "entry").unwrap();
+            }
+        }
+
+        let ccd = CargoCachePaths::new(cargo_home.clone()).unwrap();
+        let mut registry_pkg_caches =
+            registry_pkg_cache::RegistryPkgCaches::new(ccd.registry_pkg_cache.clone());
+        let mut registry_index_caches =
+            registry_index::RegistryIndicesCache::new(ccd.registry_index.clone());
+        let mut size_changed = false;
+        prune_index(
+            &ccd,
+            &Keep::CachedOrUsedWithin(Duration::from_secs(0)),
+            &mut registry_pkg_caches,
+            &mut registry_index_caches,
+            false,
+            &mut size_changed,
+        )
+        .unwrap();
+
+        assert!(size_changed);
+        assert_eq!(index_cache_entries(&ccd.registry_index), vec![]);
+        let log = fs::read_to_string(cargo_home.join(".cargo-cache-audit.log")).unwrap();
+        let records: Vec<&str> = log
+            .lines()
+            .map(|line| line.split('\t').nth(2).unwrap())
+            .collect();
+        // the size of the three entries of each registry
+        assert_eq!(records, vec!["15", "15"]);
+    }
+}
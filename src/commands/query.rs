@@ -7,26 +7,246 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fs;
-use std::path::{Path, PathBuf};
+use std::fmt::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
-use crate::cache::caches::{Cache, RegistrySuperCache};
+use crate::cache::caches::{get_cache_name, Cache, RegistrySuperCache};
 use crate::cache::*;
-use crate::library::Error;
+use crate::commands::trim::{get_last_access_of_item, parse_size_limit_to_bytes};
+use crate::date::parse_duration;
+use crate::library::{registry_matches, size_of_file, size_of_path, Error};
+use crate::remove::{parse_version, remove_file, DryRunMessage};
 
+use cargo_metadata::{Version, VersionReq};
 use clap::ArgMatches;
 use humansize::{file_size_opts, FileSize};
-use rayon::prelude::*;
 
 use regex::Regex;
-use walkdir::WalkDir;
+
+/// the parts of the cache a query can be restricted to via `component:`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QueryComponent {
+    Binary,             // bin
+    GitDb,              // git/db
+    GitRepos,           // git/checkouts
+    RegistryCrateCache, // registry/cache
+    RegistrySources,    // registry/src
+}
+
+impl QueryComponent {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Binary => "bin",
+            Self::GitDb => "git-db",
+            Self::GitRepos => "git-repos",
+            Self::RegistryCrateCache => "registry-crate-cache",
+            Self::RegistrySources => "registry-sources",
+        }
+    }
+}
+
+impl FromStr for QueryComponent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "bin" => Ok(Self::Binary),
+            "git-db" => Ok(Self::GitDb),
+            "git-repos" => Ok(Self::GitRepos),
+            "registry-crate-cache" | "registry-cache" => Ok(Self::RegistryCrateCache),
+            "registry-sources" => Ok(Self::RegistrySources),
+            other => Err(format!(
+                "unknown component \"{}\", expected one of: bin,git-db,git-repos,registry-crate-cache,registry-sources",
+                other
+            )),
+        }
+    }
+}
+
+/// comparison operator of the `size` and `unused` filters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Comparison {
+    /// split `">=5M"` into `(GreaterOrEqual, "5M")`
+    fn split_off(input: &str) -> Option<(Self, &str)> {
+        // check the two-char operators first so ">=" is not taken for ">"
+        [
+            (">=", Self::GreaterOrEqual),
+            ("<=", Self::LessOrEqual),
+            (">", Self::Greater),
+            ("<", Self::Less),
+            ("=", Self::Equal),
+        ]
+        .iter()
+        .find_map(|(op, cmp)| input.strip_prefix(op).map(|rest| (*cmp, rest)))
+    }
+
+    fn holds<T: PartialOrd>(self, lhs: &T, rhs: &T) -> bool {
+        match self {
+            Self::Less => lhs < rhs,
+            Self::LessOrEqual => lhs <= rhs,
+            Self::Equal => lhs == rhs,
+            Self::GreaterOrEqual => lhs >= rhs,
+            Self::Greater => lhs > rhs,
+        }
+    }
+}
+
+/// a single term of a query, for example `name:tokio*` or `size>5M`
+#[derive(Debug)]
+enum Filter {
+    Component(QueryComponent),
+    Registry(String),
+    Name(Regex),
+    Version(VersionReq),
+    Size(Comparison, u64),
+    Unused(Comparison, Duration),
+    // terms without a key are matched as regex against the file name
+    Regex(Regex),
+}
+
+// turn a glob like "tokio*" into an anchored regex
+fn glob_to_regex(glob: &str) -> Regex {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            other => regex.push_str(&regex::escape(&other.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).unwrap()
+}
+
+impl Filter {
+    fn parse(term: &str) -> Result<Self, Error> {
+        let invalid = |reason: String| Error::QueryInvalidTerm(term.to_string(), reason);
+
+        if let Some((key, value)) = term.split_once(':') {
+            match key {
+                "component" => {
+                    return value.parse().map(Filter::Component).map_err(invalid);
+                }
+                "registry" => return Ok(Filter::Registry(value.to_string())),
+                "name" => return Ok(Filter::Name(glob_to_regex(value))),
+                "version" => {
+                    return VersionReq::parse(value)
+                        .map(Filter::Version)
+                        .map_err(|e| invalid(e.to_string()));
+                }
+                _ => {}
+            }
+        }
+
+        for key in &["size", "unused"] {
+            if let Some((cmp, value)) = term.strip_prefix(key).and_then(Comparison::split_off) {
+                return if *key == "size" {
+                    parse_size_limit_to_bytes(Some(value))
+                        .map(|size| Filter::Size(cmp, size))
+                        .map_err(|e| invalid(e.to_string()))
+                } else {
+                    parse_duration(value)
+                        .map(|age| Filter::Unused(cmp, age))
+                        .map_err(|e| invalid(e.to_string()))
+                };
+            }
+        }
+
+        Regex::new(term)
+            .map(Filter::Regex)
+            .map_err(|_| Error::QueryRegexFailedParsing(term.to_string()))
+    }
+
+    fn matches(&self, file: &File<'_>) -> bool {
+        match self {
+            Self::Component(component) => file.component == *component,
+            Self::Registry(wanted) => file
+                .registry
+                .as_ref()
+                .map_or(false, |registry| registry_matches(registry, wanted)),
+            Self::Name(glob) => glob.is_match(&file.crate_name),
+            Self::Version(req) => file.version.as_ref().map_or(false, |v| req.matches(v)),
+            Self::Size(cmp, size) => cmp.holds(&file.size, size),
+            Self::Unused(cmp, age) => {
+                let unused_for = SystemTime::now()
+                    .duration_since(get_last_access_of_item(file.path))
+                    .unwrap_or_default();
+                cmp.holds(&unused_for, age)
+            }
+            Self::Regex(re) => re.is_match(file.name.as_str()),
+        }
+    }
+}
+
+/// a parsed query, an item is selected if it matches all filters
+#[derive(Debug, Default)]
+struct Query {
+    filters: Vec<Filter>,
+}
+
+impl Query {
+    /// parse whitespace separated terms like "registry:crates.io name:tokio* size>5M unused>90d"
+    fn parse(query: &str) -> Result<Self, Error> {
+        let mut filters = query
+            .split_whitespace()
+            .map(Filter::parse)
+            .collect::<Result<Vec<Filter>, Error>>()?;
+        // getting the last access time is expensive, only check it if everything else matched
+        filters.sort_by_key(|filter| matches!(filter, Filter::Unused(..)));
+        Ok(Self { filters })
+    }
+
+    fn matches(&self, file: &File<'_>) -> bool {
+        self.filters.iter().all(|filter| filter.matches(file))
+    }
+}
 
 #[derive(Debug)]
 struct File<'a> {
-    #[allow(unused)]
     path: &'a Path,
     name: String,
     size: u64,
+    component: QueryComponent,
+    registry: Option<String>,
+    crate_name: String,
+    version: Option<Version>,
+}
+
+impl<'a> File<'a> {
+    fn new(path: &'a Path, name: String, size: u64, component: QueryComponent) -> Self {
+        let (registry, crate_name, version) = match component {
+            QueryComponent::Binary => (None, name.clone(), None),
+            QueryComponent::GitDb => (None, get_cache_name(path), None),
+            QueryComponent::GitRepos => (None, get_cache_name(path.parent().unwrap()), None),
+            QueryComponent::RegistryCrateCache | QueryComponent::RegistrySources => {
+                let (crate_name, version) = parse_version(path).unwrap_or_default();
+                (
+                    Some(get_cache_name(path.parent().unwrap())),
+                    crate_name,
+                    Version::parse(&version).ok(),
+                )
+            }
+        };
+        File {
+            path,
+            name,
+            size,
+            component,
+            registry,
+            crate_name,
+            version,
+        }
+    }
 }
 
 #[inline]
@@ -48,91 +268,50 @@ fn path_to_name_unstemmed(path: &Path) -> String {
 }
 
 fn binary_to_file(path: &Path) -> File<'_> {
-    File {
+    File::new(
         path,
-        name: path_to_name_unstemmed(path),
-        size: fs::metadata(&path)
-            .unwrap_or_else(|_| panic!("Failed to get metadata of file '{}'", &path.display()))
-            .len(),
-    }
+        path_to_name_unstemmed(path),
+        size_of_file(path),
+        QueryComponent::Binary,
+    )
 }
 
 fn git_checkout_to_file(path: &Path) -> File<'_> {
-    File {
+    File::new(
         path,
-        name: path_to_name_unstemmed(path),
-        size: WalkDir::new(path.display().to_string())
-            .into_iter()
-            .map(|d| d.unwrap().into_path())
-            .filter(|f| f.exists())
-            .collect::<Vec<PathBuf>>()
-            .par_iter()
-            .map(|f| {
-                fs::metadata(f)
-                    .unwrap_or_else(|_| panic!("Failed to read size of file: '{:?}'", f))
-                    .len()
-            })
-            .sum(),
-    }
+        path_to_name_unstemmed(path),
+        size_of_path(path),
+        QueryComponent::GitRepos,
+    )
 }
 
 fn bare_repo_to_file(path: &Path) -> File<'_> {
-    File {
+    File::new(
         path,
-        name: path_to_name_unstemmed(path),
-        size: WalkDir::new(path.display().to_string())
-            .into_iter()
-            .map(|d| d.unwrap().into_path())
-            .filter(|f| f.exists())
-            .collect::<Vec<PathBuf>>()
-            .par_iter()
-            .map(|f| {
-                fs::metadata(f)
-                    .unwrap_or_else(|_| panic!("Failed to read size of file: '{:?}'", f))
-                    .len()
-            })
-            .sum(),
-    }
+        path_to_name_unstemmed(path),
+        size_of_path(path),
+        QueryComponent::GitDb,
+    )
 }
 
 fn registry_pkg_cache_to_file(path: &Path) -> File<'_> {
-    File {
+    File::new(
         // todo: sum up the versions
         path,
-        name: path_to_name_stemmed(path),
-        size: WalkDir::new(path.display().to_string())
-            .into_iter()
-            .map(|d| d.unwrap().into_path())
-            .filter(|f| f.exists())
-            .collect::<Vec<PathBuf>>()
-            .par_iter()
-            .map(|f| {
-                fs::metadata(f)
-                    .unwrap_or_else(|_| panic!("Failed to read size of file: '{:?}'", f))
-                    .len()
-            })
-            .sum(),
-    }
+        path_to_name_stemmed(path),
+        size_of_path(path),
+        QueryComponent::RegistryCrateCache,
+    )
 }
 
 fn registry_source_cache_to_file(path: &Path) -> File<'_> {
-    File {
+    File::new(
         // todo: sum up the versions
         path,
-        name: path_to_name_unstemmed(path),
-        size: WalkDir::new(path.display().to_string())
-            .into_iter()
-            .map(|d| d.unwrap().into_path())
-            .filter(|f| f.exists())
-            .collect::<Vec<PathBuf>>()
-            .par_iter()
-            .map(|f| {
-                fs::metadata(f)
-                    .unwrap_or_else(|_| panic!("Failed to read size of file: '{:?}'", f))
-                    .len()
-            })
-            .sum(),
-    }
+        path_to_name_unstemmed(path),
+        size_of_path(path),
+        QueryComponent::RegistrySources,
+    )
 }
 
 fn sort_files_by_name(v: &mut Vec<File<'_>>) {
@@ -143,8 +322,58 @@ fn sort_files_by_size(v: &mut Vec<File<'_>>) {
     v.sort_by_key(|f| f.size);
 }
 
+// quote a csv field if needed
+fn csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn files_to_csv(sections: &[(&str, Vec<File<'_>>)]) -> String {
+    let mut output = String::from("component,registry,name,version,size,path\n");
+    for file in sections.iter().flat_map(|(_, files)| files) {
+        let _ = writeln!(
+            output,
+            "{},{},{},{},{},{}",
+            file.component.name(),
+            csv_field(file.registry.as_deref().unwrap_or_default()),
+            csv_field(&file.crate_name),
+            file.version
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_default(),
+            file.size,
+            csv_field(&file.path.display().to_string()),
+        );
+    }
+    output
+}
+
+fn remove_files(sections: &[(&str, Vec<File<'_>>)], dry_run: bool, size_changed: &mut bool) {
+    let mut removed_size = 0;
+    for file in sections.iter().flat_map(|(_, files)| files) {
+        removed_size += file.size;
+        remove_file(
+            file.path,
+            dry_run,
+            size_changed,
+            None,
+            &DryRunMessage::Default,
+            Some(file.size),
+        );
+    }
+    println!(
+        "{} {} of matching items.",
+        if dry_run { "Would remove" } else { "Removed" },
+        removed_size.file_size(file_size_opts::DECIMAL).unwrap()
+    );
+}
+
 pub(crate) fn run_query(
     query_config: &ArgMatches<'_>,
+    size_changed: &mut bool,
     bin_cache: &mut bin::BinaryCache,
     checkouts_cache: &mut git_checkouts::GitCheckoutCache,
     bare_repos_cache: &mut git_bare_repos::GitRepoCache,
@@ -152,207 +381,108 @@ pub(crate) fn run_query(
     registry_sources_caches: &mut registry_sources::RegistrySourceCaches,
 ) -> Result<(), Error> {
     let sorting = query_config.value_of("sort");
-    let query = query_config.value_of("QUERY").unwrap_or("" /* default */);
+    let query_string = query_config
+        .values_of("QUERY")
+        .map(|terms| terms.collect::<Vec<_>>().join(" "))
+        .unwrap_or_default();
     let hr_size = query_config.is_present("hr");
+    let remove = query_config.is_present("remove");
+    let dry_run = query_config.is_present("dry-run");
 
-    let mut output = String::new();
-
-    // make the regex
-    let re = match Regex::new(query) {
-        Ok(re) => re,
-        Err(_e) => {
-            return Err(Error::QueryRegexFailedParsing(query.to_string()));
-        }
-    };
+    let query = Query::parse(&query_string)?;
+    if remove && query.filters.is_empty() {
+        return Err(Error::QueryRemoveWithoutFilter);
+    }
 
-    let mut binary_matches: Vec<File<'_>> = bin_cache
+    let binary_matches: Vec<File<'_>> = bin_cache
         .files()
         .iter()
         .map(|path| binary_to_file(path)) // convert the path into a file struct
-        .filter(|f| re.is_match(f.name.as_str())) // filter by regex
+        .filter(|f| query.matches(f)) // apply the query
         .collect::<Vec<_>>();
 
-    let mut git_checkout_matches: Vec<_> = checkouts_cache
+    let git_checkout_matches: Vec<_> = checkouts_cache
         .items()
         .iter()
         .map(|path| git_checkout_to_file(path))
-        .filter(|f| re.is_match(f.name.as_str())) // filter by regex
+        .filter(|f| query.matches(f))
         .collect::<Vec<_>>();
 
-    let mut bare_repos_matches: Vec<_> = bare_repos_cache
+    let bare_repos_matches: Vec<_> = bare_repos_cache
         .items()
         .iter()
         .map(|path| bare_repo_to_file(path))
-        .filter(|f| re.is_match(f.name.as_str())) // filter by regex
+        .filter(|f| query.matches(f))
         .collect::<Vec<_>>();
 
-    let files = registry_pkg_cache.files(); //@TODO fixme?
-    let mut registry_pkg_cache_matches: Vec<_> = files
+    let pkg_files = registry_pkg_cache.files(); //@TODO fixme?
+    let registry_pkg_cache_matches: Vec<_> = pkg_files
         .iter()
         .map(|path| registry_pkg_cache_to_file(path))
-        .filter(|f| re.is_match(f.name.as_str())) // filter by regex
+        .filter(|f| query.matches(f))
         .collect::<Vec<_>>();
 
-    let mut registry_source_caches_matches: Vec<_> = registry_sources_caches
+    let registry_source_caches_matches: Vec<_> = registry_sources_caches
         .items()
         .iter()
         .map(|path| registry_source_cache_to_file(path))
-        .filter(|f| re.is_match(f.name.as_str())) // filter by regex
+        .filter(|f| query.matches(f))
         .collect::<Vec<_>>();
 
-    let humansize_opts = file_size_opts::FileSizeOpts {
-        allow_negative: true,
-        ..file_size_opts::DECIMAL
-    };
+    let mut sections = [
+        ("Binaries", binary_matches),
+        ("Git checkouts", git_checkout_matches),
+        ("Bare git repos", bare_repos_matches),
+        ("Registry cache", registry_pkg_cache_matches),
+        ("Registry source cache", registry_source_caches_matches),
+    ];
 
-    match sorting {
-        // make "name" the default
+    // make "name" the default
+    let sort_name = match sorting {
         Some("name") | None => {
-            // executables
-            if !binary_matches.is_empty() {
-                sort_files_by_name(&mut binary_matches);
-                output.push_str("Binaries sorted by name:\n");
-                binary_matches.iter().for_each(|b| {
-                    let size = if hr_size {
-                        b.size.file_size(&humansize_opts).unwrap()
-                    } else {
-                        b.size.to_string()
-                    };
-                    output.push_str(&format!("\t{}: {}\n", b.name, size));
-                });
-            }
-
-            // git checkouts
-            if !git_checkout_matches.is_empty() {
-                sort_files_by_name(&mut git_checkout_matches);
-                output.push_str("\nGit checkouts sorted by name:\n");
-                git_checkout_matches.iter().for_each(|b| {
-                    let size = if hr_size {
-                        b.size.file_size(&humansize_opts).unwrap()
-                    } else {
-                        b.size.to_string()
-                    };
-                    output.push_str(&format!("\t{}: {}\n", b.name, size));
-                });
-            }
-            // bare git repos
-            if !bare_repos_matches.is_empty() {
-                sort_files_by_name(&mut bare_repos_matches);
-                output.push_str("\nBare git repos sorted by name:\n");
-                bare_repos_matches.iter().for_each(|b| {
-                    let size = if hr_size {
-                        b.size.file_size(&humansize_opts).unwrap()
-                    } else {
-                        b.size.to_string()
-                    };
-                    output.push_str(&format!("\t{}: {}\n", b.name, size));
-                });
-            }
-
-            // registry cache
-            if !registry_pkg_cache_matches.is_empty() {
-                sort_files_by_name(&mut registry_pkg_cache_matches);
-                output.push_str("\nRegistry cache sorted by name:\n");
-                registry_pkg_cache_matches.iter().for_each(|b| {
-                    let size = if hr_size {
-                        b.size.file_size(&humansize_opts).unwrap()
-                    } else {
-                        b.size.to_string()
-                    };
-                    output.push_str(&format!("\t{}: {}\n", b.name, size));
-                });
-            }
-
-            // registry source
-            if !registry_source_caches_matches.is_empty() {
-                sort_files_by_name(&mut registry_source_caches_matches);
-                output.push_str("\nRegistry source cache sorted by name:\n");
-                registry_source_caches_matches.iter().for_each(|b| {
-                    let size = if hr_size {
-                        b.size.file_size(&humansize_opts).unwrap()
-                    } else {
-                        b.size.to_string()
-                    };
-                    output.push_str(&format!("\t{}: {}\n", b.name, size));
-                });
-            }
+            sections
+                .iter_mut()
+                .for_each(|(_, files)| sort_files_by_name(files));
+            "name"
         }
-
         Some("size") => {
-            // executables
-            if !binary_matches.is_empty() {
-                sort_files_by_size(&mut binary_matches);
-                output.push_str("\nBinaries sorted by size:\n");
-                binary_matches.iter().for_each(|b| {
-                    let size = if hr_size {
-                        b.size.file_size(&humansize_opts).unwrap()
-                    } else {
-                        b.size.to_string()
-                    };
-                    output.push_str(&format!("\t{}: {}\n", b.name, size));
-                });
-            }
+            sections
+                .iter_mut()
+                .for_each(|(_, files)| sort_files_by_size(files));
+            "size"
+        }
+        Some(&_) => {
+            unreachable!();
+        }
+    };
 
-            // git checkouts
-            if !git_checkout_matches.is_empty() {
-                sort_files_by_size(&mut git_checkout_matches);
-                output.push_str("\nGit checkouts sorted by size:\n");
-                git_checkout_matches.iter().for_each(|b| {
-                    let size = if hr_size {
-                        b.size.file_size(&humansize_opts).unwrap()
-                    } else {
-                        b.size.to_string()
-                    };
-                    output.push_str(&format!("\t{}: {}\n", b.name, size));
-                });
-            }
+    if remove {
+        remove_files(&sections, dry_run, size_changed);
+        return Ok(());
+    }
 
-            //bare repos matches
-            if !bare_repos_matches.is_empty() {
-                sort_files_by_size(&mut bare_repos_matches);
-                output.push_str("\nBare git repos sorted by size:\n");
-                bare_repos_matches.iter().for_each(|b| {
-                    let size = if hr_size {
-                        b.size.file_size(&humansize_opts).unwrap()
-                    } else {
-                        b.size.to_string()
-                    };
-                    output.push_str(&format!("\t{}: {}\n", b.name, size));
-                });
-            }
+    if query_config.value_of("format") == Some("csv") {
+        print!("{}", files_to_csv(&sections));
+        return Ok(());
+    }
 
-            // registry cache
-            if !registry_pkg_cache_matches.is_empty() {
-                sort_files_by_size(&mut registry_pkg_cache_matches);
-                output.push_str("\nRegistry cache sorted by size:\n");
-                registry_pkg_cache_matches.iter().for_each(|b| {
-                    let size = if hr_size {
-                        b.size.file_size(&humansize_opts).unwrap()
-                    } else {
-                        b.size.to_string()
-                    };
-                    output.push_str(&format!("\t{}: {}\n", b.name, size));
-                });
-            }
+    let humansize_opts = file_size_opts::FileSizeOpts {
+        allow_negative: true,
+        ..file_size_opts::DECIMAL
+    };
 
-            // registry source
-            if !registry_source_caches_matches.is_empty() {
-                sort_files_by_size(&mut registry_source_caches_matches);
-                output.push_str("\nRegistry source cache sorted by size:\n");
-                registry_source_caches_matches.iter().for_each(|b| {
-                    let size = if hr_size {
-                        b.size.file_size(&humansize_opts).unwrap()
-                    } else {
-                        b.size.to_string()
-                    };
-                    output.push_str(&format!("\t{}: {}\n", b.name, size));
-                });
-            }
-        }
+    let mut output = String::new();
 
-        Some(&_) => {
-            unreachable!();
-        }
+    for (title, files) in sections.iter().filter(|(_, files)| !files.is_empty()) {
+        let _ = writeln!(output, "\n{} sorted by {}:", title, sort_name);
+        files.iter().for_each(|b| {
+            let size = if hr_size {
+                b.size.file_size(&humansize_opts).unwrap()
+            } else {
+                b.size.to_string()
+            };
+            let _ = writeln!(output, "\t{}: {}", b.name, size);
+        });
     }
 
     let trimmed = output.trim();
@@ -364,9 +494,95 @@ pub(crate) fn run_query(
 
 #[cfg(test)]
 mod query_tests {
+    use super::*;
     use crate::test_helpers::bin_path;
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
     use std::process::Command;
 
+    #[test]
+    fn query_parse_filters() {
+        let query = Query::parse(
+            "unused>90d registry:crates.io name:tokio* version:<1.0 size>=5M component:registry-cache foo",
+        )
+        .unwrap();
+        assert_eq!(query.filters.len(), 7);
+        // the unused filter is moved to the end
+        assert!(matches!(
+            query.filters.last(),
+            Some(Filter::Unused(Comparison::Greater, _))
+        ));
+        assert!(matches!(
+            query.filters[3],
+            Filter::Size(Comparison::GreaterOrEqual, 5_242_880)
+        ));
+
+        assert!(Query::parse("").unwrap().filters.is_empty());
+        assert!(Query::parse("component:foo").is_err());
+        assert!(Query::parse("version:abc").is_err());
+        assert!(Query::parse("size>5X").is_err());
+        assert!(Query::parse("unused<3y").is_err());
+        assert!(Query::parse("(").is_err());
+    }
+
+    #[test]
+    fn query_matches() {
+        let path = PathBuf::from("registry/cache/github.com-1ecc6299db9ec823/tokio-0.2.22.crate");
+        let file = File::new(
+            &path,
+            path_to_name_stemmed(&path),
+            6_000_000,
+            QueryComponent::RegistryCrateCache,
+        );
+        assert_eq!(file.registry.as_deref(), Some("github.com"));
+        assert_eq!(file.crate_name, "tokio");
+
+        let matching = [
+            "",
+            "tokio",
+            "registry:crates.io",
+            "registry:github.com",
+            "name:tok?o*",
+            "version:<1.0",
+            "size>5M",
+            "component:registry-crate-cache",
+            "registry:crates.io name:tokio* version:^0.2 size<10M",
+        ];
+        for query in &matching {
+            assert!(Query::parse(query).unwrap().matches(&file), "{}", query);
+        }
+
+        let not_matching = [
+            "serde",
+            "registry:my-registry",
+            "name:tok",
+            "version:>=1.0",
+            "size<5M",
+            "component:registry-sources",
+            "registry:crates.io name:tokio* version:^0.3",
+        ];
+        for query in &not_matching {
+            assert!(!Query::parse(query).unwrap().matches(&file), "{}", query);
+        }
+    }
+
+    #[test]
+    fn query_csv() {
+        let path = PathBuf::from("registry/src/github.com-1ecc6299db9ec823/foo,bar-1.0.0");
+        let file = File::new(
+            &path,
+            path_to_name_unstemmed(&path),
+            42,
+            QueryComponent::RegistrySources,
+        );
+        let csv = files_to_csv(&[("Registry source cache", vec![file])]);
+        assert_eq!(
+            csv,
+            "component,registry,name,version,size,path\n\
+             registry-sources,github.com,\"foo,bar\",1.0.0,42,\"registry/src/github.com-1ecc6299db9ec823/foo,bar-1.0.0\"\n"
+        );
+    }
+
     #[test]
     fn query_subcmd_long() {
         let query_cmd = Command::new(bin_path()).arg("query").output();
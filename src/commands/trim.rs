@@ -17,24 +17,24 @@ use crate::cache::caches::*;
 use crate::cache::*;
 use crate::library::*;
 use crate::remove::*;
+use crate::trash;
 
 use humansize::{file_size_opts, FileSize};
-use walkdir::WalkDir;
 
-fn get_last_access_of_item(path: &Path) -> std::time::SystemTime {
+pub(crate) fn get_last_access_of_item(path: &Path) -> std::time::SystemTime {
     if path.is_file() {
         // if we have a file, simply get the access time
-        std::fs::metadata(path).unwrap().accessed().unwrap()
+        last_access(path)
     } else {
         // if we have a directory, get the latest access of all files of that directory
         // get the max time / the file with the youngest access date / most recently accessed
-        WalkDir::new(path)
-            .into_iter()
-            .map(|e| e.unwrap().path().to_owned())
-            .map(|filepath| std::fs::metadata(filepath).unwrap().accessed().unwrap()) //@TODO make this an reusable function/method to simplify code
+        walk_dir(path)
+            .iter()
+            .filter_map(|filepath| last_access(filepath))
             .max()
-            .unwrap()
     }
+    // the item vanished or can't be read: treat it as the oldest one
+    .unwrap_or(std::time::UNIX_EPOCH)
 }
 
 // get a list of all cache items, sorted by file access time (young to old)
@@ -61,53 +61,78 @@ pub(crate) fn gather_all_cache_items<'a>(
 }
 
 /// figure out how big the cache should remain after trimming
-fn parse_size_limit_to_bytes(limit: Option<&str>) -> Result<u64, Error> {
+pub(crate) fn parse_size_limit_to_bytes(limit: Option<&str>) -> Result<u64, Error> {
     match limit {
         None => unreachable!("No trim --limit was supplied although clap should enforce that!"),
         Some(limit) => {
-            // figure out the unit
-            let unit_multiplicator: Result<u64, Error> = match limit.chars().last() {
-                // we have no limit
-                None => Ok(0),
-                // we expect a unit such as B, K, M, G, T...
-                Some(c) => {
-                    if c.is_alphabetic() {
-                        match c {
-                            'b' | 'B' => Ok(1),
-                            'k' | 'K' => Ok(1024),
-                            'm' | 'M' => Ok(1024 * 1024),
-                            'g' | 'G' => Ok(1024 * 1024 * 1024),
-                            't' | 'T' => Ok(1024 * 1024 * 1024 * 1024),
-                            _ => Err(Error::TrimLimitUnitParseFailure(limit.to_string())),
-                        }
-                    } else {
-                        Err(Error::TrimLimitUnitParseFailure(limit.to_string()))
-                    }
-                }
-            };
+            let parse_failure = || Error::TrimLimitUnitParseFailure(limit.to_string());
+            // split "1.5GiB" into the value "1.5" and the unit "GiB"
+            let unit_start = limit
+                .find(|c: char| c.is_alphabetic())
+                .ok_or_else(parse_failure)?;
+            let (value, unit) = limit.split_at(unit_start);
 
-            let value: f64 = match limit[0..(limit.len() - 1)].parse() {
-                Ok(val) => val,
-                Err(_) => {
-                    return Err(Error::TrimLimitUnitParseFailure(limit.to_string()));
-                }
+            // the single letters are binary multiples for compatibility, the SI units are
+            // decimal multiples like the sizes we print, the IEC units binary multiples again
+            let unit_multiplicator: u64 = match unit.to_lowercase().as_str() {
+                "b" => 1,
+                "k" | "kib" => 1024,
+                "m" | "mib" => 1024 * 1024,
+                "g" | "gib" => 1024 * 1024 * 1024,
+                "t" | "tib" => 1024 * 1024 * 1024 * 1024,
+                "kb" => 1000,
+                "mb" => 1000 * 1000,
+                "gb" => 1000 * 1000 * 1000,
+                "tb" => 1000 * 1000 * 1000 * 1000,
+                _ => return Err(parse_failure()),
             };
-            if value == 0.0 {
-                return Ok(0);
+
+            let value: f64 = value.trim().parse().map_err(|_| parse_failure())?;
+            if !value.is_finite() || value < 0.0 {
+                return Err(parse_failure());
             }
             // we may truncate the value here but that's ok
             #[allow(clippy::cast_lossless)]
             #[allow(clippy::cast_sign_loss)]
             #[allow(clippy::cast_possible_truncation)]
             #[allow(clippy::cast_precision_loss)]
-            Ok((value * unit_multiplicator? as f64) as u64)
+            Ok((value * unit_multiplicator as f64) as u64)
         }
     }
 }
 
+/// parse a cache size limit which is either a size or a percentage of the filesystem holding `cargo_home`
+pub(crate) fn parse_cache_limit(limit: &str, cargo_home: &Path) -> Result<u64, Error> {
+    match limit.strip_suffix('%') {
+        Some(percentage) => {
+            let percentage: f64 = match percentage.trim().parse() {
+                Ok(percentage) if (0.0..=100.0).contains(&percentage) => percentage,
+                _ => return Err(Error::TrimLimitUnitParseFailure(limit.to_string())),
+            };
+            let filesystem_size = fs2::total_space(cargo_home)
+                .map_err(|e| Error::FilesystemSpaceUnknown(cargo_home.to_path_buf(), e))?;
+            #[allow(clippy::cast_sign_loss)]
+            #[allow(clippy::cast_possible_truncation)]
+            #[allow(clippy::cast_precision_loss)]
+            Ok((filesystem_size as f64 * percentage / 100.0) as u64)
+        }
+        None => parse_size_limit_to_bytes(Some(limit)),
+    }
+}
+
+/// the cache size that leaves `wanted` bytes free on a filesystem with `available` free bytes
+fn limit_for_free_space(cache_size: u64, available: u64, wanted: u64) -> u64 {
+    cache_size.saturating_sub(wanted.saturating_sub(available))
+}
+
 /// trim the cache to a certain limit and invalidate caches
-pub(crate) fn trim_cache<'a>(
-    unparsed_size_limit: Option<&'a str>,
+/// `limit` is the maximum size of the cache, `ensure_free` the free space the filesystem should have
+/// afterwards, if both are given the smaller cache size wins
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn trim_cache(
+    ccd: &CargoCachePaths,
+    limit: Option<&str>,
+    ensure_free: Option<&str>,
     git_checkouts_cache: &mut git_checkouts::GitCheckoutCache,
     bare_repos_cache: &mut git_bare_repos::GitRepoCache,
     registry_pkg_cache: &mut registry_pkg_cache::RegistryPkgCaches,
@@ -115,19 +140,64 @@ pub(crate) fn trim_cache<'a>(
     dry_run: bool,
     size_changed: &mut bool,
 ) -> Result<(), Error> {
+    let total_cache_size: u64 = git_checkouts_cache.total_size()
+        + bare_repos_cache.total_size()
+        + registry_pkg_cache.total_size()
+        + registry_sources_cache.total_size();
+
+    if ensure_free.is_some() && trash::enabled() {
+        return Err(Error::TrimEnsureFreeWithTrash);
+    }
+
+    // only needed (and shown) if the user cares about the filesystem, statvfs does not work everywhere
+    let available_space = if ensure_free.is_some() || limit.map_or(false, |l| l.ends_with('%')) {
+        Some(
+            fs2::available_space(&ccd.cargo_home)
+                .map_err(|e| Error::FilesystemSpaceUnknown(ccd.cargo_home.clone(), e))?,
+        )
+    } else {
+        None
+    };
+
     // the cache should not exceed this limit
-    let size_limit = parse_size_limit_to_bytes(unparsed_size_limit)?;
+    let size_limit = limit
+        .map(|limit| parse_cache_limit(limit, &ccd.cargo_home))
+        .transpose()?;
+    let free_space_limit = match (ensure_free, available_space) {
+        (Some(ensure_free), Some(available_space)) => {
+            let wanted = parse_size_limit_to_bytes(Some(ensure_free))?;
+            if wanted > available_space + total_cache_size {
+                eprintln!(
+                    "Warning: {} free space requested but even without the cache only {} will be free",
+                    wanted.file_size(file_size_opts::DECIMAL).unwrap(),
+                    (available_space + total_cache_size)
+                        .file_size(file_size_opts::DECIMAL)
+                        .unwrap()
+                );
+            }
+            Some(limit_for_free_space(
+                total_cache_size,
+                available_space,
+                wanted,
+            ))
+        }
+        _ => None,
+    };
+    let size_limit = match (size_limit, free_space_limit) {
+        (Some(size_limit), Some(free_space_limit)) => size_limit.min(free_space_limit),
+        (Some(only_limit), None) | (None, Some(only_limit)) => only_limit,
+        (None, None) => {
+            unreachable!("Neither trim --limit nor --ensure-free supplied although clap should enforce that!")
+        }
+    };
 
     // fast path:
     // if the  limit is bigger than the cache size, we can return
     // because we know we won't have to delete anything
-    let total_cache_size: u64 = git_checkouts_cache.total_size()
-        + bare_repos_cache.total_size()
-        + registry_pkg_cache.total_size()
-        + registry_sources_cache.total_size();
 
-    if size_limit > total_cache_size {
+    if size_limit >= total_cache_size {
         //println!("trim: limit exceeds cache-limit, doing nothing");
+        print_free_space(available_space, 0);
         return Ok(());
     }
 
@@ -185,9 +255,25 @@ pub(crate) fn trim_cache<'a>(
         removed_item_count,
         removed_size.file_size(file_size_opts::DECIMAL).unwrap()
     );
+    // items moved to the trash stay on the same filesystem and free nothing until the trash is emptied
+    let freed_size = if trash::enabled() { 0 } else { removed_size };
+    print_free_space(available_space, freed_size);
     Ok(())
 }
 
+// the free space before and after trimming, if we know it
+fn print_free_space(available_space: Option<u64>, freed_size: u64) {
+    if let Some(available_space) = available_space {
+        println!(
+            "Free space: {} before, {} after trimming (projected)",
+            available_space.file_size(file_size_opts::DECIMAL).unwrap(),
+            (available_space + freed_size)
+                .file_size(file_size_opts::DECIMAL)
+                .unwrap()
+        );
+    }
+}
+
 #[cfg(test)]
 mod parse_size_limit {
     use super::*;
@@ -228,6 +314,63 @@ mod parse_size_limit {
         }
     }
 
+    #[test]
+    fn size_limit_units() {
+        fn p(limit: &str) -> Result<u64, Error> {
+            parse_size_limit_to_bytes(Some(limit))
+        }
+
+        assert_eq!(p("1.5G").unwrap(), 1_610_612_736);
+        assert_eq!(p("0.5m").unwrap(), 524_288);
+
+        assert_eq!(p("1KB").unwrap(), 1_000);
+        assert_eq!(p("1.5GB").unwrap(), 1_500_000_000);
+        assert_eq!(p("2tb").unwrap(), 2_000_000_000_000);
+
+        assert_eq!(p("1KiB").unwrap(), 1_024);
+        assert_eq!(p("2GiB").unwrap(), 2_147_483_648);
+        assert_eq!(p("30 GB").unwrap(), 30_000_000_000);
+
+        assert_eq!(p("0G").unwrap(), 0);
+
+        for invalid in &["", "G", "30", "-1G", "1.5XB", "1GiBB", "inf G"] {
+            match p(invalid) {
+                Ok(_) => panic!("expected error for {:?}", invalid),
+                Err(Error::TrimLimitUnitParseFailure(string)) => assert_eq!(&string, invalid),
+                Err(..) => panic!("did not get enum variant TrimParseLimitUnitParseFailure"),
+            }
+        }
+    }
+
+    #[test]
+    fn cache_limit_percentage() {
+        let cargo_home = Path::new(".");
+        let filesystem_size = fs2::total_space(cargo_home).unwrap();
+
+        assert_eq!(
+            parse_cache_limit("100%", cargo_home).unwrap(),
+            filesystem_size
+        );
+        assert_eq!(parse_cache_limit("0%", cargo_home).unwrap(), 0);
+        let ten_percent = parse_cache_limit("10%", cargo_home).unwrap();
+        assert!((filesystem_size / 10).saturating_sub(1) <= ten_percent);
+        assert!(ten_percent <= filesystem_size / 10 + 1);
+        assert_eq!(parse_cache_limit("4M", cargo_home).unwrap(), 4_194_304);
+
+        assert!(parse_cache_limit("101%", cargo_home).is_err());
+        assert!(parse_cache_limit("G%", cargo_home).is_err());
+    }
+
+    #[test]
+    fn free_space_limit() {
+        // 10 GB free, 30 GB wanted: 20 GB of the 50 GB cache have to go
+        assert_eq!(limit_for_free_space(50, 10, 30), 30);
+        // already enough space
+        assert_eq!(limit_for_free_space(50, 40, 30), 50);
+        // even removing the whole cache is not enough
+        assert_eq!(limit_for_free_space(50, 10, 100), 0);
+    }
+
     // make sure Size limit None panicss
     #[test]
     #[should_panic(expected = "No trim --limit was supplied although clap should enforce that!")]
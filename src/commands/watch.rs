@@ -0,0 +1,213 @@
+// Copyright 2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// "cargo cache watch" command
+// keep running, watch the CARGO_HOME for changes and "trim" the cache whenever it grew beyond the limit.
+// The cache is checked at most once per --interval, no matter how many changes cargo makes.
+// We never wait for cargo's package cache lock: if cargo is busy downloading or extracting crates,
+// we try again later so that we neither block cargo nor remove something it is extracting.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use crate::cache::caches::{Cache, RegistrySuperCache};
+use crate::cache::*;
+use crate::commands::trim::{parse_cache_limit, trim_cache};
+use crate::library::{clear_scan_errors, CargoCachePaths, Error};
+use crate::lock::{LockMode, PackageCacheLock};
+
+use chrono::Local;
+use humansize::{file_size_opts, FileSize};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+
+// how long to wait for more events of a change before reporting it
+const DEBOUNCE_DELAY: Duration = Duration::from_secs(2);
+
+/// the directories to watch: new crates are downloaded into registry/cache/<registry>, extracted
+/// into registry/src/<registry> and git repos are cloned into git/db and git/checkouts/<repo>
+fn directories_to_watch(ccd: &CargoCachePaths) -> Vec<(PathBuf, RecursiveMode)> {
+    let mut directories = vec![
+        // a new registry/ or git/ directory
+        (ccd.cargo_home.clone(), RecursiveMode::NonRecursive),
+        (ccd.registry_pkg_cache.clone(), RecursiveMode::Recursive),
+        (ccd.registry_sources.clone(), RecursiveMode::NonRecursive),
+        (ccd.git_repos_bare.clone(), RecursiveMode::NonRecursive),
+        (ccd.git_checkouts.clone(), RecursiveMode::NonRecursive),
+    ];
+    // we don't care about the files inside of the extracted crates and checkouts, only about new ones
+    for parent in &[&ccd.registry_sources, &ccd.git_checkouts] {
+        if let Ok(entries) = std::fs::read_dir(parent) {
+            directories.extend(
+                entries
+                    .filter_map(Result::ok)
+                    .map(|entry| entry.path())
+                    .filter(|path| path.is_dir())
+                    .map(|path| (path, RecursiveMode::NonRecursive)),
+            );
+        }
+    }
+    directories
+}
+
+/// whether an event is a change of the cache, cargo touches the .package-cache lock file
+/// on every run and we write our size index and audit log into the `CARGO_HOME` ourselves
+fn changes_cache(event: &DebouncedEvent, cargo_home: &Path) -> bool {
+    let path = match event {
+        // these only announce an event that follows once the change is complete
+        DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => return false,
+        DebouncedEvent::Create(path)
+        | DebouncedEvent::Write(path)
+        | DebouncedEvent::Chmod(path)
+        | DebouncedEvent::Remove(path)
+        | DebouncedEvent::Rename(_, path) => path,
+        // we may have missed changes, better check
+        DebouncedEvent::Rescan | DebouncedEvent::Error(..) => return true,
+    };
+    path.parent() != Some(cargo_home) || path.is_dir()
+}
+
+/// scan the cache and trim it if it exceeds the limit,
+/// returns false if cargo holds the lock and we have to try again later
+fn enforce_limit(ccd: &CargoCachePaths, limit: &str, dry_run: bool) -> Result<bool, Error> {
+    let _lock = if dry_run {
+        None
+    } else {
+        match PackageCacheLock::acquire(&ccd.cargo_home, LockMode::NoWait) {
+            Ok(lock) => Some(lock),
+            Err(Error::PackageCacheLocked(_)) => return Ok(false),
+            Err(error) => return Err(error),
+        }
+    };
+
+    // every check scans the cache again, don't report the paths of earlier scans
+    clear_scan_errors();
+    inventory::scan(&ccd.cargo_home);
+
+    let mut checkouts_cache = git_checkouts::GitCheckoutCache::new(ccd.git_checkouts.clone());
+    let mut bare_repos_cache = git_bare_repos::GitRepoCache::new(ccd.git_repos_bare.clone());
+    let mut registry_pkgs_cache =
+        registry_pkg_cache::RegistryPkgCaches::new(ccd.registry_pkg_cache.clone());
+    let mut registry_sources_caches =
+        registry_sources::RegistrySourceCaches::new(ccd.registry_sources.clone());
+
+    let size_before = checkouts_cache.total_size()
+        + bare_repos_cache.total_size()
+        + registry_pkgs_cache.total_size()
+        + registry_sources_caches.total_size();
+    if size_before <= parse_cache_limit(limit, &ccd.cargo_home)? {
+        return Ok(true);
+    }
+
+    println!(
+        "{}: the cache grew to {}, trimming it to {}",
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        size_before.file_size(file_size_opts::DECIMAL).unwrap(),
+        limit
+    );
+    let mut size_changed = false;
+    trim_cache(
+        ccd,
+        Some(limit),
+        None,
+        &mut checkouts_cache,
+        &mut bare_repos_cache,
+        &mut registry_pkgs_cache,
+        &mut registry_sources_caches,
+        dry_run,
+        &mut size_changed,
+    )?;
+    Ok(true)
+}
+
+/// "cargo cache watch": trim the cache to `limit` whenever it changes, at most once per `interval`
+pub(crate) fn watch(
+    ccd: &CargoCachePaths,
+    limit: &str,
+    interval: Duration,
+    dry_run: bool,
+) -> Result<(), Error> {
+    // don't start watching with a limit that we can't parse
+    let _ = parse_cache_limit(limit, &ccd.cargo_home)?;
+
+    let (sender, receiver) = channel();
+    let mut watcher = notify::watcher(sender, DEBOUNCE_DELAY).map_err(Error::WatchFailed)?;
+    let mut registered: HashSet<PathBuf> = HashSet::new();
+
+    println!(
+        "Watching {} and keeping the cache below {}",
+        ccd.cargo_home.display(),
+        limit
+    );
+
+    // check the cache right away, it may already be too big
+    let mut pending = true;
+    let mut last_check: Option<Instant> = None;
+    loop {
+        // directories that were removed are no longer watched, new ones appear all the time
+        registered.retain(|path| path.is_dir());
+        for (path, mode) in directories_to_watch(ccd) {
+            if !registered.contains(&path) && watcher.watch(&path, mode).is_ok() {
+                let _ = registered.insert(path);
+            }
+        }
+
+        if pending {
+            let wait = last_check.map_or(Duration::from_secs(0), |last| {
+                interval
+                    .checked_sub(last.elapsed())
+                    .unwrap_or_else(|| Duration::from_secs(0))
+            });
+            if wait == Duration::from_secs(0) {
+                pending = !enforce_limit(ccd, limit, dry_run)?;
+                last_check = Some(Instant::now());
+                if pending {
+                    println!("The package cache is locked by cargo, trying again later");
+                }
+                continue;
+            }
+            // keep consuming events while we wait for the next check
+            match receiver.recv_timeout(wait) {
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        } else {
+            match receiver.recv() {
+                Ok(event) => pending = changes_cache(&event, &ccd.cargo_home),
+                // the watcher went away
+                Err(_) => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod watchtests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn watch_ignores_own_files() {
+        let cargo_home = Path::new("/nonexistent/.cargo");
+        let events = [
+            DebouncedEvent::Write(cargo_home.join(".package-cache")),
+            DebouncedEvent::Create(cargo_home.join(".cargo-cache-index")),
+            DebouncedEvent::NoticeWrite(cargo_home.join("registry/cache/foo/bar-1.0.0.crate")),
+            DebouncedEvent::Create(cargo_home.join("registry/cache/foo/bar-1.0.0.crate")),
+            DebouncedEvent::Remove(cargo_home.join("git/checkouts/foo-1234/abcdef")),
+            DebouncedEvent::Rescan,
+        ];
+        let changes: Vec<bool> = events
+            .iter()
+            .map(|event| changes_cache(event, cargo_home))
+            .collect();
+        assert_eq!(changes, vec![false, false, false, true, true, true]);
+    }
+}
@@ -29,7 +29,7 @@ enum AgeRelation<'a> {
     // OlderOrYounger(&'a str, &'a str),
 }
 
-fn parse_date(date: &str) -> Result<NaiveDateTime, Error> {
+pub(crate) fn parse_date(date: &str) -> Result<NaiveDateTime, Error> {
     // @TODO handle yyyyy.mm.dd hh:mm:ss
     // @TODO  handle dd.mm.yy if yy is yy and not yyyy
     let date_to_compare: NaiveDateTime = {
@@ -86,6 +86,29 @@ fn parse_date(date: &str) -> Result<NaiveDateTime, Error> {
     Ok(date_to_compare)
 }
 
+/// parse a duration such as "90d", "12h", "30m" or "2w" into a `std::time::Duration`
+pub(crate) fn parse_duration(duration: &str) -> Result<std::time::Duration, Error> {
+    let error = || Error::DurationParseFailure(duration.to_string());
+
+    let unit = duration.chars().last().ok_or_else(error)?;
+    let seconds_per_unit: u64 = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 60 * 60 * 24,
+        'w' => 60 * 60 * 24 * 7,
+        _ => return Err(error()),
+    };
+    let value: u64 = duration[0..(duration.len() - 1)]
+        .parse()
+        .map_err(|_| error())?;
+
+    value
+        .checked_mul(seconds_per_unit)
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(error)
+}
+
 #[derive(Debug, Clone)]
 struct FileWithDate {
     file: std::path::PathBuf,
@@ -176,20 +199,23 @@ pub(crate) fn remove_files_by_dates(
         (None, Some(younger)) => AgeRelation::FileYoungerThanDate(younger),
         (Some(older), None) => AgeRelation::FileOlderThanDate(older),
         (Some(_older), Some(_younger)) => {
-            unreachable!("passing both, --remove-if-{older,younger}-than was temporarily disabled!")
+            unreachable!(
+                "passing both, --remove-if-{{older,younger}}-than was temporarily disabled!"
+            )
         } // (Some(older), Some(younger)) => DateComparison::OlderOrYounger(older, younger),
     };
 
     // for each file, get the access time
     let mut dates: Vec<FileWithDate> = files_of_components
         .into_iter()
-        .map(|path| {
-            let access_time = path.metadata().unwrap().accessed().unwrap();
+        // skip items that vanished in the meantime
+        .filter_map(|path| {
+            let access_time = last_access(&path)?;
             let naive_datetime = chrono::DateTime::<Local>::from(access_time).naive_local();
-            FileWithDate {
+            Some(FileWithDate {
                 file: path,
                 access_date: naive_datetime,
-            }
+            })
         })
         .collect();
 
@@ -318,6 +344,26 @@ mod libtests {
         );
     }
 
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("30s").unwrap().as_secs(), 30);
+        assert_eq!(parse_duration("2m").unwrap().as_secs(), 120);
+        assert_eq!(parse_duration("12h").unwrap().as_secs(), 43_200);
+        assert_eq!(parse_duration("90d").unwrap().as_secs(), 7_776_000);
+        assert_eq!(parse_duration("1w").unwrap().as_secs(), 604_800);
+
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("12").is_err());
+        assert!(parse_duration("1.5d").is_err());
+        assert!(parse_duration("3y").is_err());
+        assert!(parse_duration("99999999999999999w").is_err());
+        assert_eq!(
+            parse_duration("18446744073709551615s").unwrap().as_secs(),
+            u64::MAX
+        );
+    }
+
     #[test]
     #[should_panic(expected = "invalid time")]
     fn parse_dates_panic1() {
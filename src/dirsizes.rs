@@ -17,8 +17,11 @@ use crate::cache::caches::RegistrySubCache;
 use crate::cache::caches::RegistrySuperCache;
 
 use crate::cache::*;
+use crate::disk_usage::DiskUsage;
 use crate::library::*;
+use crate::registries::{download_url, Registries};
 use crate::tables::*;
+use crate::trash;
 
 use humansize::{file_size_opts, FileSize};
 
@@ -56,6 +59,12 @@ pub(crate) struct DirSizes<'a> {
     numb_reg_cache_entries: usize,
     /// number of registry source checkouts// @TODO clarify
     numb_reg_src_checkouts: usize,
+    /// total size of the trash (not part of the total size)
+    total_trash_size: u64,
+    /// number of runs that moved items to the trash
+    numb_trash_runs: usize,
+    /// the sizes on disk, only with --disk-usage
+    disk_usage: Option<DiskUsage>,
     /// root path of the cache
     root_path: &'a std::path::PathBuf,
 }
@@ -83,6 +92,8 @@ impl<'a> DirSizes<'a> {
         let mut total_reg_cache_entries: Option<usize> = None;
         let mut total_reg_src_size: Option<u64> = None;
         let mut numb_reg_src_checkouts: Option<usize> = None;
+        let mut total_trash_size: Option<u64> = None;
+        let mut numb_trash_runs: Option<usize> = None;
 
         rayon::scope(|s| {
             // spawn one thread per cache
@@ -112,6 +123,11 @@ impl<'a> DirSizes<'a> {
                 total_reg_src_size = Some(registry_sources_caches.total_size());
                 numb_reg_src_checkouts = Some(registry_sources_caches.number_of_items());
             });
+
+            s.spawn(|_| {
+                total_trash_size = Some(trash::trash_size(&ccd.cargo_home));
+                numb_trash_runs = Some(trash::runs(&ccd.cargo_home).len());
+            });
         });
 
         let root_path = &ccd.cargo_home;
@@ -138,10 +154,18 @@ impl<'a> DirSizes<'a> {
             total_reg_index_num: registry_index_caches.number_of_subcaches() as u64, // number  of indices //@TODO parallelize like the rest
             numb_reg_cache_entries: total_reg_cache_entries.unwrap(), // number of source archives
             numb_reg_src_checkouts: numb_reg_src_checkouts.unwrap(),  // number of source checkouts
+            total_trash_size: total_trash_size.unwrap(),              // size of the trash
+            numb_trash_runs: numb_trash_runs.unwrap(),                // number of trashed runs
+            disk_usage: None,
             root_path,
         }
     }
 
+    /// also show the sizes on disk in the summary
+    pub(crate) fn add_disk_usage(&mut self, ccd: &CargoCachePaths) {
+        self.disk_usage = Some(DiskUsage::new(ccd));
+    }
+
     pub(crate) fn total_size(&self) -> u64 {
         self.total_size
     }
@@ -187,12 +211,33 @@ impl<'a> DirSizes<'a> {
     pub(crate) fn numb_reg_src_checkouts(&self) -> usize {
         self.numb_reg_src_checkouts
     }
+    pub(crate) fn total_trash_size(&self) -> u64 {
+        self.total_trash_size
+    }
+    pub(crate) fn numb_trash_runs(&self) -> usize {
+        self.numb_trash_runs
+    }
     pub(crate) fn root_path(&self) -> &'a std::path::PathBuf {
         self.root_path
     }
 }
 
 impl<'a> DirSizes<'a> {
+    /// the apparent size and with --disk-usage, the size on disk: "1.20 MB (on disk: 1.50 MB)"
+    fn size_column(&self, apparent: u64, on_disk: fn(&DiskUsage) -> u64) -> String {
+        let apparent_hr = apparent.file_size(file_size_opts::DECIMAL).unwrap();
+        match &self.disk_usage {
+            Some(disk_usage) => format!(
+                "{} (on disk: {})",
+                apparent_hr,
+                on_disk(disk_usage)
+                    .file_size(file_size_opts::DECIMAL)
+                    .unwrap()
+            ),
+            None => apparent_hr,
+        }
+    }
+
     /// returns the header of the summary which contains the path to the cache and its total size
     fn header(&self) -> Vec<TableLine> {
         vec![
@@ -204,10 +249,7 @@ impl<'a> DirSizes<'a> {
             TableLine::new(
                 0,
                 &"Total: ".to_string(),
-                &self
-                    .total_size()
-                    .file_size(file_size_opts::DECIMAL)
-                    .unwrap(),
+                &self.size_column(self.total_size(), DiskUsage::total),
             ),
         ]
     }
@@ -217,10 +259,7 @@ impl<'a> DirSizes<'a> {
         vec![TableLine::new(
             1,
             &format!("{} installed binaries: ", self.numb_bins()),
-            &self
-                .total_bin_size()
-                .file_size(file_size_opts::DECIMAL)
-                .unwrap(),
+            &self.size_column(self.total_bin_size(), |usage| usage.bin),
         )]
     }
 
@@ -230,39 +269,44 @@ impl<'a> DirSizes<'a> {
             TableLine::new(
                 1,
                 &"Git db: ".to_string(),
-                &self
-                    .total_git_db_size()
-                    .file_size(file_size_opts::DECIMAL)
-                    .unwrap(),
+                &self.size_column(self.total_git_db_size(), DiskUsage::git),
             ),
             TableLine::new(
                 2,
                 &format!("{} bare git repos: ", self.numb_git_repos_bare_repos()),
-                &self
-                    .total_git_repos_bare_size()
-                    .file_size(file_size_opts::DECIMAL)
-                    .unwrap(),
+                &self.size_column(self.total_git_repos_bare_size(), |usage| {
+                    usage.git_repos_bare
+                }),
             ),
             TableLine::new(
                 2,
                 &format!("{} git repo checkouts: ", self.numb_git_checkouts()),
-                &self
-                    .total_git_chk_size()
-                    .file_size(file_size_opts::DECIMAL)
-                    .unwrap(),
+                &self.size_column(self.total_git_chk_size(), |usage| usage.git_checkouts),
             ),
         ]
     }
 
+    /// returns the size of the trash, nothing if the trash is empty
+    fn trash(&self) -> Vec<TableLine> {
+        if self.total_trash_size() == 0 {
+            return Vec::new();
+        }
+        vec![TableLine::new(
+            0,
+            &format!("Trash ({} runs, not in total): ", self.numb_trash_runs()),
+            &self
+                .total_trash_size()
+                .file_size(file_size_opts::DECIMAL)
+                .unwrap(),
+        )]
+    }
+
     /// returns summary of sizes of registry indices and registries (both, .crate archives and the extracted sources)
     fn registries_summary(&self) -> Vec<TableLine> {
         let tl1 = TableLine::new(
             1,
             &"Registry: ".to_string(),
-            &self
-                .total_reg_size()
-                .file_size(file_size_opts::DECIMAL)
-                .unwrap(),
+            &self.size_column(self.total_reg_size(), DiskUsage::registry),
         );
 
         let left = if let 1 = self.total_reg_index_num {
@@ -273,28 +317,19 @@ impl<'a> DirSizes<'a> {
         let tl2 = TableLine::new(
             2,
             &left,
-            &self
-                .total_reg_index_size()
-                .file_size(file_size_opts::DECIMAL)
-                .unwrap(),
+            &self.size_column(self.total_reg_index_size(), |usage| usage.registry_index),
         );
 
         let tl3 = TableLine::new(
             2,
             &format!("{} crate archives: ", self.numb_reg_cache_entries()),
-            &self
-                .total_reg_cache_size()
-                .file_size(file_size_opts::DECIMAL)
-                .unwrap(),
+            &self.size_column(self.total_reg_cache_size(), |usage| usage.registry_cache),
         );
 
         let tl4 = TableLine::new(
             2,
             &format!("{} crate source checkouts: ", self.numb_reg_src_checkouts()),
-            &self
-                .total_reg_src_size()
-                .file_size(file_size_opts::DECIMAL)
-                .unwrap(),
+            &self.size_column(self.total_reg_src_size(), |usage| usage.registry_sources),
         );
 
         vec![tl1, tl2, tl3, tl4]
@@ -306,6 +341,8 @@ impl<'a> DirSizes<'a> {
         index_caches: &mut registry_index::RegistryIndicesCache,
         registry_sources: &mut registry_sources::RegistrySourceCaches,
         pkg_caches: &mut registry_pkg_cache::RegistryPkgCaches,
+        configured: &Registries,
+        unconfigured: &mut Vec<String>,
     ) -> Vec<TableLine> {
         let mut v: Vec<TableLine> = vec![];
 
@@ -420,9 +457,20 @@ impl<'a> DirSizes<'a> {
                 }
             }
 
+            // crates-io (sparse+https://index.crates.io/) or the directory name which --remove-dir accepts
+            let label = if let Some(known) = configured.lookup(registry) {
+                format!("{} ({})", known.name, known.index)
+            } else {
+                unconfigured.push(registry.clone());
+                let index_dir = self.root_path.join("registry").join("index").join(registry);
+                download_url(&index_dir).map_or_else(
+                    || format!("{} (not configured for this directory)", registry),
+                    |url| format!("{} (not configured for this directory, {})", registry, url),
+                )
+            };
             let header_line = TableLine::new(
                 1,
-                &format!("Registry: {}", registry_name.unwrap_or_default()),
+                &format!("Registry: {}", label),
                 &total_size.file_size(file_size_opts::DECIMAL).unwrap(),
             );
 
@@ -651,6 +699,32 @@ impl<'a> DirSizes<'a> {
             vec![tl1, tl2, tl3, tl4]
         } // fn regs()
 
+        // the trash is not part of the total, so show it on its own line if there is one
+        fn trash(old: &DirSizes<'_>, new: &DirSizes<'_>) -> Vec<TableLine> {
+            if old.total_trash_size() == 0 && new.total_trash_size() == 0 {
+                return Vec::new();
+            }
+            vec![TableLine::new(
+                0,
+                &format!("Trash ({} runs, not in total): ", new.numb_trash_runs()),
+                &if old.total_trash_size() == new.total_trash_size() {
+                    old.total_trash_size()
+                        .file_size(file_size_opts::DECIMAL)
+                        .unwrap()
+                } else {
+                    format!(
+                        "{} => {}",
+                        &old.total_trash_size()
+                            .file_size(file_size_opts::DECIMAL)
+                            .unwrap(),
+                        &new.total_trash_size()
+                            .file_size(file_size_opts::DECIMAL)
+                            .unwrap()
+                    )
+                },
+            )]
+        }
+
         // and requery it to let it do its thing
         let cache_sizes_new = DirSizes::new(
             bin_cache,
@@ -667,6 +741,7 @@ impl<'a> DirSizes<'a> {
         v.extend(cache_sizes_new.bin());
         v.extend(regs(cache_sizes_old, &cache_sizes_new));
         v.extend(git(cache_sizes_old, &cache_sizes_new));
+        v.extend(trash(cache_sizes_old, &cache_sizes_new));
 
         let mut summary = two_row_table(3, v, false);
 
@@ -697,6 +772,7 @@ impl<'a> fmt::Display for DirSizes<'a> {
         table.extend(self.bin());
         table.extend(self.registries_summary());
         table.extend(self.git());
+        table.extend(self.trash());
 
         let string: String = two_row_table(2, table, false);
 
@@ -711,14 +787,32 @@ pub(crate) fn per_registry_summary(
     index_caches: &mut registry_index::RegistryIndicesCache,
     pkg_caches: &mut registry_sources::RegistrySourceCaches,
     registry_sources: &mut registry_pkg_cache::RegistryPkgCaches,
+    registries: &Registries,
 ) -> String {
+    let mut unconfigured = Vec::new();
     let mut table: Vec<TableLine> = vec![];
     table.extend(dir_size.header());
     table.extend(dir_size.bin());
-    table.extend(dir_size.registries_seperate(index_caches, pkg_caches, registry_sources));
+    table.extend(dir_size.registries_seperate(
+        index_caches,
+        pkg_caches,
+        registry_sources,
+        registries,
+        &mut unconfigured,
+    ));
     table.extend(dir_size.git());
-
-    two_row_table(2, table, false)
+    table.extend(dir_size.trash());
+
+    let mut summary = two_row_table(2, table, false);
+    if !unconfigured.is_empty() {
+        // we only read the configs that apply here, projects elsewhere may have their own .cargo/config.toml
+        summary = format!(
+            "{}\nRegistries that are not configured for this directory may still be used by projects with their own\n\
+            .cargo/config.toml elsewhere, check these before removing them with \"cargo cache --remove-dir\"\n",
+            summary
+        );
+    }
+    summary
 }
 
 #[cfg(test)]
@@ -772,6 +866,9 @@ mod libtests {
 
                 total_reg_index_size: reg_index.dir_size,
                 total_reg_index_num: 1,
+                total_trash_size: 0,
+                numb_trash_runs: 0,
+                disk_usage: None,
                 root_path: path,
             }
         }
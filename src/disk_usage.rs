@@ -0,0 +1,145 @@
+// Copyright 2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// "--disk-usage": all the other sizes are apparent sizes, the length of the files. On disk, every file
+// takes whole blocks which adds up for the many small files in registry/src, compressing filesystems
+// need less than the length and hardlinked files (migrate-registry --hardlink, CARGO_HOMEs copied
+// with "cp -l") only take their space once. Like "du", we sum the allocated blocks and count each
+// inode only once, in the first component it is found in.
+// Windows does not tell us the allocated size or the inode, there this is the apparent size.
+
+use std::collections::HashSet;
+use std::fs::Metadata;
+use std::path::Path;
+
+use crate::library::CargoCachePaths;
+
+use walkdir::WalkDir;
+
+/// the space the components of the cache take on disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct DiskUsage {
+    pub(crate) bin: u64,
+    pub(crate) registry_index: u64,
+    pub(crate) registry_cache: u64,
+    pub(crate) registry_sources: u64,
+    pub(crate) git_repos_bare: u64,
+    pub(crate) git_checkouts: u64,
+}
+
+impl DiskUsage {
+    pub(crate) fn new(ccd: &CargoCachePaths) -> Self {
+        // device and inode of everything counted so far
+        let mut seen = HashSet::new();
+        let mut usage = |dir: &Path| allocated_size(dir, &mut seen);
+        Self {
+            bin: usage(&ccd.bin_dir),
+            registry_index: usage(&ccd.registry_index),
+            registry_cache: usage(&ccd.registry_pkg_cache),
+            registry_sources: usage(&ccd.registry_sources),
+            git_repos_bare: usage(&ccd.git_repos_bare),
+            git_checkouts: usage(&ccd.git_checkouts),
+        }
+    }
+
+    pub(crate) fn registry(&self) -> u64 {
+        self.registry_index + self.registry_cache + self.registry_sources
+    }
+
+    pub(crate) fn git(&self) -> u64 {
+        self.git_repos_bare + self.git_checkouts
+    }
+
+    pub(crate) fn total(&self) -> u64 {
+        self.bin + self.registry() + self.git()
+    }
+}
+
+// the allocated size of everything below dir whose inode was not seen yet
+fn allocated_size(dir: &Path, seen: &mut HashSet<(u64, u64)>) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| file_id(metadata).map_or(true, |id| seen.insert(id)))
+        .map(|metadata| allocated(&metadata))
+        .sum()
+}
+
+#[cfg(unix)]
+fn allocated(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    // always in units of 512 bytes, independent of the block size of the filesystem
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn allocated(metadata: &Metadata) -> u64 {
+    metadata.len()
+}
+
+#[cfg(unix)]
+#[allow(clippy::unnecessary_wraps)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod disk_usage_tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn disk_usage_hardlinks_counted_once() {
+        let cargo_home = PathBuf::from("target/disk_usage_hardlinks_counted_once/.cargo");
+        let _ = fs::remove_dir_all(&cargo_home);
+        let old_registry = cargo_home.join("registry/cache/github.com-1ecc6299db9ec823");
+        let new_registry = cargo_home.join("registry/cache/index.crates.io-6f17d22bba15001f");
+        fs::create_dir_all(&old_registry).unwrap();
+        fs::create_dir_all(&new_registry).unwrap();
+        fs::create_dir_all(cargo_home.join("bin")).unwrap();
+        fs::write(old_registry.join("foo-1.0.0.crate"), vec![1; 100_000]).unwrap();
+        fs::hard_link(
+            old_registry.join("foo-1.0.0.crate"),
+            new_registry.join("foo-1.0.0.crate"),
+        )
+        .unwrap();
+        // the link in bin/ is found first
+        fs::hard_link(
+            old_registry.join("foo-1.0.0.crate"),
+            cargo_home.join("bin/foo"),
+        )
+        .unwrap();
+
+        let ccd = CargoCachePaths::new(cargo_home.clone()).unwrap();
+        let usage = DiskUsage::new(&ccd);
+        let allocated_of = |path: &Path| allocated(&fs::metadata(path).unwrap());
+        assert_eq!(
+            usage.bin,
+            allocated_of(&cargo_home.join("bin")) + allocated_of(&cargo_home.join("bin/foo"))
+        );
+        // only the three directories are left in the registry cache, each with its own block count
+        let registry_cache = cargo_home.join("registry/cache");
+        assert_eq!(
+            usage.registry_cache,
+            allocated_of(&registry_cache)
+                + allocated_of(&old_registry)
+                + allocated_of(&new_registry)
+        );
+        assert_eq!(usage.git(), 0);
+    }
+}
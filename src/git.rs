@@ -7,17 +7,132 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fs;
 use std::io::{stdout, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use humansize::{file_size_opts, FileSize};
+use rayon::prelude::*;
 
+use crate::cache::caches::get_cache_name;
+use crate::cache::inventory;
 use crate::library::Error;
 use crate::library::*;
 
-fn gc_repo(path: &Path, dry_run: bool) -> Result<(u64, u64), Error> {
+/// git config key (inside the repo) which records when cargo-cache last recompressed the repo
+const LAST_GC_CONFIG_KEY: &str = "cargo-cache.lastgc";
+
+/// settings that control which repos "--gc" recompresses and how
+#[derive(Debug, Clone)]
+pub(crate) struct GcConfig<'a> {
+    /// number of repos that are recompressed in parallel
+    pub(crate) jobs: usize,
+    /// memory that all git processes together may use for packing, in bytes
+    pub(crate) memory_limit: Option<u64>,
+    /// skip repos that are smaller than this many bytes
+    pub(crate) min_size: Option<u64>,
+    /// skip repos that were recompressed less than this long ago
+    pub(crate) skip_recent: Option<Duration>,
+    /// only recompress repos with these names
+    pub(crate) only: Option<Vec<&'a str>>,
+}
+
+impl Default for GcConfig<'_> {
+    fn default() -> Self {
+        Self {
+            jobs: 1,
+            memory_limit: None,
+            min_size: None,
+            skip_recent: None,
+            only: None,
+        }
+    }
+}
+
+impl GcConfig<'_> {
+    /// check if a repo was selected via --gc-only
+    /// "cargo-e7ff1db891893a9e" can be selected by its full name or simply by "cargo"
+    fn selects(&self, repo: &Path) -> bool {
+        match &self.only {
+            None => true,
+            Some(names) => names.iter().any(|wanted| Self::matches(wanted, repo)),
+        }
+    }
+
+    fn matches(wanted: &str, repo: &Path) -> bool {
+        let dir_name = repo.file_name().unwrap().to_str().unwrap();
+        wanted == dir_name || wanted == get_cache_name(repo)
+    }
+
+    /// the names of --gc-only that select none of the `repos`, a typo would silently gc nothing
+    fn unmatched(&self, repos: &[PathBuf]) -> Vec<&str> {
+        self.only
+            .iter()
+            .flatten()
+            .filter(|wanted| !repos.iter().any(|repo| Self::matches(wanted, repo)))
+            .copied()
+            .collect()
+    }
+
+    /// "-c key=value" options passed to git to keep parallel jobs from exhausting cpus and memory
+    fn git_pack_options(&self) -> Vec<String> {
+        let jobs = self.jobs.max(1);
+        let mut options = Vec::new();
+
+        if let Some(limit) = self.memory_limit {
+            // pack.windowMemory is applied per packing thread, use a single thread per job
+            // so that the limit actually holds
+            options.push("-c".to_string());
+            options.push("pack.threads=1".to_string());
+            options.push("-c".to_string());
+            options.push(format!(
+                "pack.windowMemory={}",
+                (limit / jobs as u64).max(1)
+            ));
+        } else if jobs > 1 {
+            // share the cpus between the jobs instead of having every git use all of them
+            let threads = (rayon::current_num_threads() / jobs).max(1);
+            options.push("-c".to_string());
+            options.push(format!("pack.threads={}", threads));
+        }
+        options
+    }
+}
+
+/// print what we have so far if we are the only job, otherwise keep collecting
+/// so that the lines of parallel jobs do not get mixed up
+fn flush_progress(line: &mut String, serial: bool) {
+    if serial {
+        print!("{}", line);
+        // we need to flush stdout manually for incremental print();
+        // ignore errors
+        let _ignore = stdout().flush();
+        line.clear();
+    }
+}
+
+/// time that has passed since cargo-cache last recompressed the repo, None if it never did
+fn time_since_last_gc(path: &Path) -> Option<Duration> {
+    let repo = git2::Repository::open(path).ok()?;
+    let last_gc = repo.config().ok()?.get_i64(LAST_GC_CONFIG_KEY).ok()?;
+    #[allow(clippy::cast_sign_loss)]
+    let last_gc = UNIX_EPOCH + Duration::from_secs(last_gc.max(0) as u64);
+    SystemTime::now().duration_since(last_gc).ok()
+}
+
+/// remember when the repo was recompressed so that --gc-skip-recent can skip it next time
+fn record_last_gc(repo: &git2::Repository) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    #[allow(clippy::cast_possible_wrap)]
+    let _ignore = repo
+        .config()
+        .and_then(|mut config| config.set_i64(LAST_GC_CONFIG_KEY, now as i64));
+}
+
+fn gc_repo(path: &Path, dry_run: bool, gc_config: &GcConfig<'_>) -> Result<(u64, u64), Error> {
     // get name of the repo (last item of path)
     let repo_name = match path.iter().last() {
         Some(name) => name.to_str().unwrap().to_string(),
@@ -25,32 +140,55 @@ fn gc_repo(path: &Path, dry_run: bool) -> Result<(u64, u64), Error> {
     };
     debug_assert_ne!(repo_name, "<unknown>", "unknown repo name: '{:?}'", &path);
 
-    print!("Recompressing '{}': ", &repo_name);
+    let serial = gc_config.jobs <= 1;
+    let mut line = format!("Recompressing '{}': ", &repo_name);
+    flush_progress(&mut line, serial);
     // if something went wrong and this is not actually a directory, return an error
     if !path.is_dir() {
+        println!("{}", line);
         return Err(Error::GitRepoDirNotFound(path.into()));
     }
 
+    if let Some(skip_recent) = gc_config.skip_recent {
+        if let Some(since_last_gc) = time_since_last_gc(path) {
+            if since_last_gc < skip_recent {
+                let hours = since_last_gc.as_secs() / (60 * 60);
+                println!("{}skipped, recompressed {}h ago", line, hours);
+                return Ok((0, 0));
+            }
+        }
+    }
+
     // get size before
     let repo_size_before = cumulative_dir_size(path).dir_size;
     let sb_human_readable = repo_size_before.file_size(file_size_opts::DECIMAL).unwrap();
-    print!("{} => ", sb_human_readable);
 
-    // we need to flush stdout manually for incremental print();
-    // ignore errors
-    let _ignore = stdout().flush();
+    if let Some(min_size) = gc_config.min_size {
+        if repo_size_before < min_size {
+            println!("{}{} (skipped, too small)", line, sb_human_readable);
+            return Ok((0, 0));
+        }
+    }
+
+    line.push_str(&sb_human_readable);
+    line.push_str(" => ");
+    flush_progress(&mut line, serial);
 
     if dry_run {
         // don't do anything on dry run
-        println!("{} (+0)", sb_human_readable);
+        println!("{}{} (+0)", line, sb_human_readable);
         Ok((0, 0))
     } else {
         // validate that the directory is a git repo
         let repo = match git2::Repository::open(&path) {
             Ok(repo) => repo,
-            Err(_e) => return Err(Error::GitRepoNotOpened(path.into())),
+            Err(_e) => {
+                println!("{}", line);
+                return Err(Error::GitRepoNotOpened(path.into()));
+            }
         };
         let repo_path = repo.path();
+        let pack_options = gc_config.git_pack_options();
         // delete all history of all checkouts and so on.
         // this will enable us to remove *all* dangling commits
         if let Err(e) = Command::new("git")
@@ -61,6 +199,7 @@ fn gc_repo(path: &Path, dry_run: bool) -> Result<(u64, u64), Error> {
             .current_dir(repo_path)
             .output()
         {
+            println!("{}", line);
             return Err(Error::GitReflogFailed(path.into(), e));
         }
 
@@ -72,21 +211,25 @@ fn gc_repo(path: &Path, dry_run: bool) -> Result<(u64, u64), Error> {
             .current_dir(repo_path)
             .output()
         {
+            println!("{}", line);
             return Err(Error::GitPackRefsFailed(path.into(), e));
         }
 
         // git gc the repo get rid of unneeded objects
         if let Err(e) = Command::new("git")
+            .args(&pack_options)
             .arg("gc")
             .arg("--prune=now")
             .current_dir(repo_path)
             .output()
         {
+            println!("{}", line);
             return Err(Error::GitGCFailed(path.into(), e));
         }
 
         // git repacḱ the repo get rid of unneeded objects
         if let Err(e) = Command::new("git")
+            .args(&pack_options)
             .arg("repack")
             .arg("-a")
             .arg("-d")
@@ -100,12 +243,17 @@ fn gc_repo(path: &Path, dry_run: bool) -> Result<(u64, u64), Error> {
             .current_dir(repo_path)
             .output()
         {
+            println!("{}", line);
             return Err(Error::GitRepackFailed(path.into(), e));
         }
 
+        record_last_gc(&repo);
+        inventory::rescan(path);
+
         let repo_size_after = cumulative_dir_size(path).dir_size;
         println!(
-            "{}",
+            "{}{}",
+            line,
             size_diff_format(repo_size_before, repo_size_after, false)
         );
 
@@ -118,10 +266,16 @@ pub(crate) fn git_gc_everything(
     git_repos_bare_dir: &Path,
     registry_pkg_cache_dir: &Path,
     dry_run: bool,
+    gc_config: &GcConfig<'_>,
 ) -> Result<(), Error> {
     // gc repos and registries inside cargo cache
 
-    fn gc_subdirs(path: &Path, dry_run: bool) -> Result<(u64, u64), Error> {
+    fn gc_subdirs(
+        path: &Path,
+        dry_run: bool,
+        gc_config: &GcConfig<'_>,
+        pool: &rayon::ThreadPool,
+    ) -> Result<(u64, u64), Error> {
         if path.is_file() {
             return Err(Error::GitGCFile(path.to_path_buf()));
         } else if !path.is_dir() {
@@ -129,36 +283,40 @@ pub(crate) fn git_gc_everything(
             return Ok((0, 0));
         }
         // takes directory, finds all subdirectories and tries to gc those
-        let mut size_sum_before: u64 = 0;
-        let mut size_sum_after: u64 = 0;
-
-        let mut git_repos: Vec<_> = fs::read_dir(&path)
-            .unwrap()
-            .map(|x| x.unwrap().path())
+        let mut git_repos: Vec<_> = read_dir_paths(path)
+            .into_iter()
+            .filter(|repo| gc_config.selects(repo))
             .collect();
         // sort git repos in alphabetical order
         git_repos.sort();
 
-        for repo in git_repos {
-            // compress
-            let (size_before, size_after) = match gc_repo(&repo, dry_run) {
-                // run gc
-                Ok((before, after)) => (before, after),
-                Err(error) => match error {
-                    // Error::GitNotInstalled  should be handled before this function is called
-                    Error::GitGCFailed(_, _)
-                    | Error::GitRepoDirNotFound(_)
-                    | Error::GitRepoNotOpened(_) => {
-                        eprintln!("{}", error);
-                        continue;
-                    }
-
-                    _ => unreachable!(),
-                },
-            };
-            size_sum_before += size_before;
-            size_sum_after += size_after;
-        }
+        // compress, up to gc_config.jobs repos at the same time
+        let sizes: Vec<(u64, u64)> = pool.install(|| {
+            git_repos
+                .par_iter()
+                .filter_map(|repo| match gc_repo(repo, dry_run, gc_config) {
+                    // run gc
+                    Ok((before, after)) => Some((before, after)),
+                    Err(error) => match error {
+                        // Error::GitNotInstalled  should be handled before this function is called
+                        Error::GitGCFailed(_, _)
+                        | Error::GitRepoDirNotFound(_)
+                        | Error::GitRepoNotOpened(_)
+                        | Error::GitReflogFailed(_, _)
+                        | Error::GitPackRefsFailed(_, _)
+                        | Error::GitRepackFailed(_, _) => {
+                            eprintln!("{}", error);
+                            None
+                        }
+
+                        _ => unreachable!(),
+                    },
+                })
+                .collect()
+        });
+
+        let size_sum_before = sizes.iter().map(|(before, _)| before).sum();
+        let size_sum_after = sizes.iter().map(|(_, after)| after).sum();
         Ok((size_sum_before, size_sum_after))
     } // fn gc_subdirs
 
@@ -167,23 +325,43 @@ pub(crate) fn git_gc_everything(
         return Err(Error::GitNotInstalled);
     }
 
+    let mut repo_index = registry_pkg_cache_dir.to_path_buf();
+    // cd "../index"
+    let _ = repo_index.pop();
+    repo_index.push("index");
+
+    // report every name that matches nothing, fail with the first one
+    let candidates: Vec<PathBuf> = read_dir_paths(git_repos_bare_dir)
+        .into_iter()
+        .chain(read_dir_paths(&repo_index))
+        .collect();
+    let mut unmatched = gc_config
+        .unmatched(&candidates)
+        .into_iter()
+        .map(|name| Error::GitGCOnlyNoMatch(name.to_string()));
+    if let Some(first_unmatched) = unmatched.next() {
+        unmatched.for_each(|error| eprintln!("{}", error));
+        return Err(first_unmatched);
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(gc_config.jobs.max(1))
+        .build()
+        .expect("failed to create thread pool for recompressing repositories");
+
     // gc cloned git repos of crates and registries
     let mut total_size_before: u64 = 0;
     let mut total_size_after: u64 = 0;
 
     println!("\nRecompressing repositories. This may take some time...");
     // gc git repos of crates
-    let (repos_before, repos_after) = gc_subdirs(git_repos_bare_dir, dry_run)?;
+    let (repos_before, repos_after) = gc_subdirs(git_repos_bare_dir, dry_run, gc_config, &pool)?;
     total_size_before += repos_before;
     total_size_after += repos_after;
 
     println!("\nRecompressing registries. This may take some time...");
-    let mut repo_index = registry_pkg_cache_dir.to_path_buf();
-    // cd "../index"
-    let _ = repo_index.pop();
-    repo_index.push("index");
     // gc registries
-    let (regs_before, regs_after) = gc_subdirs(&repo_index, dry_run)?;
+    let (regs_before, regs_after) = gc_subdirs(&repo_index, dry_run, gc_config, &pool)?;
     total_size_before += regs_before;
     total_size_after += regs_after;
 
@@ -248,10 +426,7 @@ pub(crate) fn git_fsck_everything(
             return;
         }
 
-        let mut git_repos: Vec<_> = fs::read_dir(&path)
-            .unwrap()
-            .map(|x| x.unwrap().path())
-            .collect();
+        let mut git_repos: Vec<_> = read_dir_paths(path);
         // sort git repos in alphabetical order
         git_repos.sort();
 
@@ -364,6 +539,7 @@ mod gittest {
         let (dryrun_before, dryrun_after) = match gc_repo(
             &PathBuf::from("target/gitrepo_gc/"),
             true, /* dry run */
+            &GcConfig::default(),
         ) {
             Ok((x, y)) => (x, y),
             _ => (0, 0),
@@ -375,6 +551,7 @@ mod gittest {
         let (before, after) = match gc_repo(
             &PathBuf::from("target/gitrepo_gc/"),
             false, /* dry run */
+            &GcConfig::default(),
         ) {
             Ok((x, y)) => (x, y),
             _ => (0, 0),
@@ -385,6 +562,58 @@ mod gittest {
             before,
             after
         );
+
+        // gc recorded the time, so the repo is skipped if we ask for that
+        assert!(time_since_last_gc(&PathBuf::from("target/gitrepo_gc/")).is_some());
+        let skip_recent = GcConfig {
+            skip_recent: Some(Duration::from_secs(60 * 60)),
+            ..GcConfig::default()
+        };
+        let skipped = gc_repo(&PathBuf::from("target/gitrepo_gc/"), false, &skip_recent);
+        assert_eq!(skipped.unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn test_gc_config_selects() {
+        let everything = GcConfig::default();
+        assert!(everything.selects(&PathBuf::from("git/db/cargo-e7ff1db891893a9e")));
+
+        let only = GcConfig {
+            only: Some(vec!["cargo", "rust-clippy-f9a7f5bc8c0e31f8"]),
+            ..GcConfig::default()
+        };
+        assert!(only.selects(&PathBuf::from("git/db/cargo-e7ff1db891893a9e")));
+        assert!(only.selects(&PathBuf::from("git/db/rust-clippy-f9a7f5bc8c0e31f8")));
+        assert!(!only.selects(&PathBuf::from("git/db/rust-clippy-0000000000000000")));
+        assert!(!only.selects(&PathBuf::from("git/db/cargo-cache-a3c7d7d1b6cd0e35")));
+        assert_eq!(
+            only.unmatched(&[PathBuf::from("git/db/cargo-e7ff1db891893a9e")]),
+            vec!["rust-clippy-f9a7f5bc8c0e31f8"]
+        );
+        assert_eq!(GcConfig::default().unmatched(&[]), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_gc_config_pack_options() {
+        assert!(GcConfig::default().git_pack_options().is_empty());
+
+        let memory_limited = GcConfig {
+            jobs: 4,
+            memory_limit: Some(1000),
+            ..GcConfig::default()
+        };
+        assert_eq!(
+            memory_limited.git_pack_options(),
+            vec!["-c", "pack.threads=1", "-c", "pack.windowMemory=250"]
+        );
+
+        let parallel = GcConfig {
+            jobs: 2,
+            ..GcConfig::default()
+        };
+        let options = parallel.git_pack_options();
+        assert_eq!(options.len(), 2);
+        assert!(options[1].starts_with("pack.threads="));
     }
 
     #[test]
@@ -11,12 +11,18 @@
 use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
 
 use crate::cache::caches::{Cache, RegistrySuperCache};
+use crate::cache::inventory;
 use crate::cache::*;
 use crate::dirsizes::DirSizes;
 
+use cargo_metadata::VersionReq;
 use humansize::{file_size_opts, FileSize};
+use lazy_static::lazy_static;
 use rayon::iter::*;
 use walkdir::WalkDir;
 
@@ -34,7 +40,7 @@ impl<T, E: std::fmt::Display> ErrorHandling<T, E> for CargoCacheResult<T, E> {
             Ok(t) => t,
             Err(e) => {
                 eprintln!("{}", e);
-                std::process::exit(1);
+                exit(1);
             }
         }
     }
@@ -43,16 +49,65 @@ impl<T, E: std::fmt::Display> ErrorHandling<T, E> for CargoCacheResult<T, E> {
     fn exit_or_fatal_error(self) {
         match self {
             Ok(_) => {
-                std::process::exit(0);
+                exit(0);
             }
             Err(e) => {
                 eprintln!("{}", e);
-                std::process::exit(1);
+                exit(1);
             }
         }
     }
 }
 
+// Files can vanish while we scan the cache (a concurrent cargo removes a temporary file) and
+// directories may not be readable. Instead of aborting, we skip these paths, remember them here
+// and print them when we exit.
+lazy_static! {
+    static ref SCAN_ERRORS: Mutex<Vec<Error>> = Mutex::new(Vec::new());
+}
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// exit with an error if any path could not be read while scanning the cache (--strict)
+pub(crate) fn set_strict() {
+    STRICT.store(true, Ordering::SeqCst);
+}
+
+/// remember a path that could not be read, it is skipped and reported at the end
+pub(crate) fn record_scan_error(path: &Path, error: std::io::Error) {
+    SCAN_ERRORS
+        .lock()
+        .unwrap()
+        .push(Error::ScanFailed(path.to_path_buf(), error));
+}
+
+/// the number of paths that could not be read so far
+pub(crate) fn scan_error_count() -> usize {
+    SCAN_ERRORS.lock().unwrap().len()
+}
+
+/// forget the paths that could not be read, for example before scanning again
+pub(crate) fn clear_scan_errors() {
+    SCAN_ERRORS.lock().unwrap().clear();
+}
+
+/// print the paths that could not be read and exit, with an error in --strict mode
+pub(crate) fn exit(code: i32) -> ! {
+    let scan_errors = std::mem::take(&mut *SCAN_ERRORS.lock().unwrap());
+    if !scan_errors.is_empty() {
+        eprintln!(
+            "\nWarning: skipped {} paths that vanished or could not be read:",
+            scan_errors.len()
+        );
+        for error in &scan_errors {
+            eprintln!("  {}", error);
+        }
+    }
+    if code == 0 && !scan_errors.is_empty() && STRICT.load(Ordering::SeqCst) {
+        std::process::exit(1);
+    }
+    std::process::exit(code);
+}
+
 /// `DirInfo` is used so to be able to easily differentiate between size and number of files of a directory
 #[derive(Debug, Clone)]
 pub(crate) struct DirInfo {
@@ -114,14 +169,32 @@ pub(crate) enum Error {
     InvalidDeletableDirs(String),
     /// --remove-dir didn't get any args passed
     RemoveDirNoArg,
+    /// a --remove-dir selector like git-db@foo did not match anything
+    RemoveDirNoMatch(String),
     /// failed to find current working directory
     NoCWD,
     /// failed to find Cargo.toml manifest
     NoCargoManifest(PathBuf),
     /// failed to parse query regex
     QueryRegexFailedParsing(String),
+    /// failed to parse a term of a structured query (term, reason)
+    QueryInvalidTerm(String, String),
+    /// query --remove was used without any filter and would remove everything
+    QueryRemoveWithoutFilter,
+    /// the package cache is locked by cargo or another cargo-cache and --no-wait was passed
+    PackageCacheLocked(PathBuf),
+    /// failed to open or lock the package cache lock file
+    PackageCacheLockFailed(PathBuf, std::io::Error),
+    /// --trash was used on an item that is not inside the `CARGO_HOME`
+    TrashOutsideCargoHome(PathBuf),
+    /// failed to move an item into or out of the trash
+    TrashMoveFailed(PathBuf, std::io::Error),
+    /// undo was used but there is nothing in the trash
+    TrashEmpty,
     /// tried to "git gc" a file instead of a directory
     GitGCFile(PathBuf),
+    /// a name passed to --gc-only matches no git repo or registry index
+    GitGCOnlyNoMatch(String),
     // local tried to open a target dir that does not exist
     LocalNoTargetDir(PathBuf),
     // failed to parse date given to younger or older
@@ -134,12 +207,57 @@ pub(crate) enum Error {
     NoRustupHome,
     // trim failed to parse the given unit
     TrimLimitUnitParseFailure(String),
+    // failed to parse a duration such as "90d"
+    DurationParseFailure(String),
+    // a path vanished or could not be read while scanning the cache
+    ScanFailed(PathBuf, std::io::Error),
+    // failed to write the metrics to the textfile
+    MetricsWriteFailed(PathBuf, std::io::Error),
+    // failed to listen on the address to serve the metrics
+    MetricsListenFailed(String, std::io::Error),
+    // failed to watch the cache for changes
+    WatchFailed(notify::Error),
+    // failed to get the toolchain version from "rustc -vV"
+    RustcVersionFailed(String),
+    // migrate-registry did not find a registry by this name
+    MigrateRegistryNotFound(String),
+    // migrate-registry found several registries by this name
+    MigrateRegistryAmbiguous(String, Vec<String>),
+    // migrate-registry was asked to migrate a registry into itself
+    MigrateRegistrySame(String),
+    // the target of migrate-registry has no index to check the archives against
+    MigrateRegistryNoIndex(String),
+    // failed to read, move or link a .crate archive
+    MigrateRegistryFailed(PathBuf, std::io::Error),
+    // doctor found problems and did not (or could not) fix all of them
+    DoctorFoundProblems(usize),
+    // doctor failed to fix a problem
+    DoctorFixFailed(PathBuf, std::io::Error),
+    // .crates.toml or .crates2.json could not be read or parsed
+    InstallMetadataInvalid(PathBuf, String),
+    // failed to write .crates.toml or .crates2.json
+    InstallMetadataWriteFailed(PathBuf, std::io::Error),
+    // "bin --remove" was given a package that is not installed
+    BinNotInstalled(String),
+    // "bin --remove" with --trash, "undo" could restore the binaries but not the install metadata
+    BinRemoveWithTrash,
+    // "trim --ensure-free" with --trash, trashed items stay on the filesystem
+    TrimEnsureFreeWithTrash,
+    // failed to get the size or free space of the filesystem holding the path
+    FilesystemSpaceUnknown(PathBuf, std::io::Error),
+    // there is no Cargo.lock for the manifest
+    NoLockfile(PathBuf),
+    // failed to read a Cargo.lock
+    LockfileReadFailed(PathBuf, std::io::Error),
+    // failed to unpack a .crate archive
+    ExtractionFailed(PathBuf, std::io::Error),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let valid_deletable_dirs =
-            "git-db,git-repos,registry-sources,registry-crate-cache,registry-index,registry,all";
+            "git-db,git-repos,registry-sources,registry-crate-cache,registry-index,registry,all \
+            (optionally limited to one registry or git repo via @NAME) or crate:NAME[@VERSION]";
 
         match &self {
             Self::GitRepoNotOpened(path) => {
@@ -205,6 +323,12 @@ impl fmt::Display for Error {
                 dirs, valid_deletable_dirs
             ),
 
+            Self::RemoveDirNoMatch(selector) => write!(
+                f,
+                "\"{}\" does not match any registry, git repo or crate in the cache",
+                selector
+            ),
+
             Self::RemoveDirNoArg => write!(
                 f,
                 "No argument passed to \"--remove-dir\"! Chose one or several from {}",
@@ -219,11 +343,46 @@ impl fmt::Display for Error {
             Self::QueryRegexFailedParsing(regex) => {
                 write!(f, "Failed to parse regular expression \"{}\"", regex)
             }
+            Self::QueryInvalidTerm(term, reason) => {
+                write!(f, "Failed to parse query term \"{}\": {}", term, reason)
+            }
+            Self::QueryRemoveWithoutFilter => write!(
+                f,
+                "Refusing to remove everything, \"query --remove\" needs at least one filter."
+            ),
+            Self::PackageCacheLocked(path) => write!(
+                f,
+                "The package cache \"{}\" is locked by cargo or another cargo-cache, try again later or use --wait.",
+                path.display()
+            ),
+            Self::PackageCacheLockFailed(path, error) => write!(
+                f,
+                "Failed to lock the package cache \"{}\": {}",
+                path.display(),
+                error
+            ),
+            Self::TrashOutsideCargoHome(path) => write!(
+                f,
+                "Can't move \"{}\" to the trash, it is not inside the CARGO_HOME",
+                path.display()
+            ),
+            Self::TrashMoveFailed(path, error) => write!(
+                f,
+                "Failed to move \"{}\" in the trash: {}",
+                path.display(),
+                error
+            ),
+            Self::TrashEmpty => write!(f, "The trash is empty, there is nothing to undo."),
             Self::GitGCFile(path) => write!(
                 f,
                 "Tried to \"git gc\" a file instead of a directory: \"{}\"",
                 path.display()
             ),
+            Self::GitGCOnlyNoMatch(name) => write!(
+                f,
+                "\"{}\" (--gc-only) does not match any git repo or registry index in the cache",
+                name
+            ),
             Self::LocalNoTargetDir(path) => write!(
                 f,
                 "error: \"local\" subcommand tried to read \"target\" \
@@ -248,9 +407,106 @@ impl fmt::Display for Error {
             Self::TrimLimitUnitParseFailure(limit) => write!(
                 f,
                 "Failed to parse limit: \"{}\". \
-                Should be of the form 123X where X is one of B,K,M,G or T.",
+                Should be of the form 1.5X where X is one of B,K,M,G or T (binary), \
+                KB,MB,GB or TB (SI) or KiB,MiB,GiB or TiB (IEC).",
                 limit
             ),
+            Self::DurationParseFailure(duration) => write!(
+                f,
+                "Failed to parse duration: \"{}\". \
+                Should be of the form 123X where X is one of s,m,h,d or w.",
+                duration
+            ),
+            Self::ScanFailed(path, error) => write!(f, "\"{}\": {}", path.display(), error),
+            Self::MetricsWriteFailed(path, error) => write!(
+                f,
+                "Failed to write the metrics to \"{}\": {}",
+                path.display(),
+                error
+            ),
+            Self::MetricsListenFailed(address, error) => write!(
+                f,
+                "Failed to serve the metrics on \"{}\": {}",
+                address, error
+            ),
+            Self::WatchFailed(error) => {
+                write!(f, "Failed to watch the cache for changes: {}", error)
+            }
+            Self::RustcVersionFailed(error) => write!(
+                f,
+                "Failed to get the toolchain version from \"rustc -vV\": {}",
+                error
+            ),
+            Self::MigrateRegistryNotFound(name) => {
+                write!(f, "No registry \"{}\" found in the cache", name)
+            }
+            Self::MigrateRegistryAmbiguous(name, registries) => write!(
+                f,
+                "\"{}\" matches several registries, use one of: {}",
+                name,
+                registries.join(", ")
+            ),
+            Self::MigrateRegistrySame(name) => {
+                write!(f, "Can't migrate registry \"{}\" into itself", name)
+            }
+            Self::MigrateRegistryNoIndex(name) => write!(
+                f,
+                "Registry \"{}\" has no index to verify the crate archives against, run cargo to fetch it",
+                name
+            ),
+            Self::MigrateRegistryFailed(path, error) => {
+                write!(f, "Failed to migrate \"{}\": {}", path.display(), error)
+            }
+            Self::DoctorFoundProblems(count) => write!(
+                f,
+                "{} problems found in the cache, run \"cargo cache doctor --fix\" to fix them",
+                count
+            ),
+            Self::DoctorFixFailed(path, error) => {
+                write!(f, "Failed to fix \"{}\": {}", path.display(), error)
+            }
+            Self::InstallMetadataInvalid(path, error) => write!(
+                f,
+                "Failed to read the install metadata \"{}\": {}",
+                path.display(),
+                error
+            ),
+            Self::InstallMetadataWriteFailed(path, error) => write!(
+                f,
+                "Failed to update the install metadata \"{}\": {}",
+                path.display(),
+                error
+            ),
+            Self::BinNotInstalled(name) => write!(
+                f,
+                "No package \"{}\" installed, see \"cargo cache bin\" for the installed ones",
+                name
+            ),
+            Self::BinRemoveWithTrash => write!(
+                f,
+                "Uninstalled packages can't be moved to the trash, \"cargo cache undo\" would not restore their entries in .crates.toml and .crates2.json. Run it without --trash."
+            ),
+            Self::TrimEnsureFreeWithTrash => write!(
+                f,
+                "\"trim --ensure-free\" can't be combined with --trash, trashed items stay on the filesystem and free no space"
+            ),
+            Self::FilesystemSpaceUnknown(path, error) => write!(
+                f,
+                "Failed to get the space of the filesystem holding \"{}\": {}",
+                path.display(),
+                error
+            ),
+            Self::NoLockfile(manifest) => write!(
+                f,
+                "No Cargo.lock found for \"{}\", run \"cargo generate-lockfile\" first",
+                manifest.display()
+            ),
+            Self::LockfileReadFailed(path, error) => {
+                write!(f, "Failed to read \"{}\": {}", path.display(), error)
+            }
+            Self::ExtractionFailed(path, error) => {
+                write!(f, "Failed to extract \"{}\": {}", path.display(), error)
+            }
         }
     }
 }
@@ -354,7 +610,7 @@ impl std::str::FromStr for RemovableGroup {
             "git-db" => Ok(RemovableGroup::GitDB),
             "git-repos" => Ok(RemovableGroup::GitRepos),
             "registry-sources" => Ok(RemovableGroup::RegistrySources),
-            "registry-crate-cache" => Ok(RemovableGroup::RegistryCrateCache),
+            "registry-crate-cache" | "registry-cache" => Ok(RemovableGroup::RegistryCrateCache),
             "registry-index" => Ok(RemovableGroup::RegistryIndex),
             "registry" => Ok(RemovableGroup::Registry),
             other => Err(other.to_string()),
@@ -459,15 +715,196 @@ pub(crate) fn components_from_groups(input: Option<&str>) -> Result<Vec<Componen
     Ok(mapped_dirs)
 }
 
+// a part of a component that --remove-dir can remove instead of the whole component
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Selector {
+    // one registry or git repo, by directory name (github.com-1ecc6299db9ec823) or by name (github.com)
+    Item(Component, String),
+    // some or all versions of a crate, in the archives and sources of all registries
+    Crate(String, Option<VersionReq>),
+}
+
+impl std::str::FromStr for Selector {
+    type Err = String;
+
+    // registry-cache@my-registry, git-db@cargo-e7ff1db891893a9e or crate:serde@<1.0.100
+    fn from_str(s: &str) -> Result<Self, String> {
+        if let Some(krate) = s.strip_prefix("crate:") {
+            let (name, version) = match krate.split_once('@') {
+                Some((name, version)) => (
+                    name,
+                    Some(VersionReq::parse(version).map_err(|_| s.to_string())?),
+                ),
+                None => (krate, None),
+            };
+            if name.is_empty() {
+                return Err(s.to_string());
+            }
+            return Ok(Selector::Crate(name.to_string(), version));
+        }
+
+        let (group, name) = s
+            .split_once('@')
+            .filter(|(_, name)| !name.is_empty())
+            .ok_or_else(|| s.to_string())?;
+        // like the groups, the crate archives and git repos take their sources and checkouts with them
+        let component = match group.parse()? {
+            RemovableGroup::GitDB => Component::GitDB,
+            RemovableGroup::GitRepos => Component::GitRepos,
+            RemovableGroup::RegistrySources => Component::RegistrySources,
+            RemovableGroup::RegistryCrateCache | RemovableGroup::Registry => {
+                Component::RegistryCrateCache
+            }
+            RemovableGroup::RegistryIndex => Component::RegistryIndex,
+            RemovableGroup::All => return Err(s.to_string()),
+        };
+        Ok(Selector::Item(component, name.to_string()))
+    }
+}
+
+// like components_from_groups() but --remove-dir also accepts selectors for single registries,
+// git repos and crates, returns the whole components and the selectors together with their input
+#[allow(clippy::type_complexity)]
+pub(crate) fn selectors_from_groups(
+    input: Option<&str>,
+) -> Result<(Vec<Component>, Vec<(String, Selector)>), Error> {
+    let input_string = input.ok_or(Error::RemoveDirNoArg)?;
+
+    let (selector_terms, group_terms): (Vec<&str>, Vec<&str>) = input_string
+        .split(',')
+        .partition(|term| term.contains('@') || term.starts_with("crate:"));
+
+    let mut selectors = Vec::new();
+    let mut invalid = Vec::new();
+    for term in selector_terms {
+        match term.parse() {
+            Ok(selector) => selectors.push((term.to_string(), selector)),
+            Err(term) => invalid.push(term),
+        }
+    }
+    if !invalid.is_empty() {
+        return Err(Error::InvalidDeletableDirs(invalid.join(" ")));
+    }
+
+    let components = if group_terms.is_empty() {
+        Vec::new()
+    } else {
+        components_from_groups(Some(&group_terms.join(",")))?
+    };
+    Ok((components, selectors))
+}
+
+// "crates.io" is stored as "github.com" (git index) or "index.crates.io" (sparse index)
+pub(crate) fn registry_matches(registry: &str, wanted: &str) -> bool {
+    registry == wanted
+        || (wanted == "crates.io" && (registry == "github.com" || registry == "index.crates.io"))
+}
+
+/// get the size of a file, 0 if it vanished or can't be read
+pub(crate) fn size_of_file(path: &Path) -> u64 {
+    if let Some(info) = inventory::get(path) {
+        return info.size;
+    }
+    match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(error) => {
+            record_scan_error(path, error);
+            0
+        }
+    }
+}
+
+/// whether the path is a file, without touching the disk if it is in the inventory
+pub(crate) fn is_file(path: &Path) -> bool {
+    inventory::get(path).map_or_else(|| path.is_file(), |info| !info.is_dir)
+}
+
+/// get the paths inside a directory, nothing if the directory can't be read
+pub(crate) fn read_dir_paths(dir: &Path) -> Vec<PathBuf> {
+    if let Some(children) = inventory::children(dir) {
+        return children;
+    }
+    match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| match entry {
+                Ok(entry) => Some(entry.path()),
+                Err(error) => {
+                    record_scan_error(dir, error);
+                    None
+                }
+            })
+            .collect(),
+        Err(error) => {
+            record_scan_error(dir, error);
+            Vec::new()
+        }
+    }
+}
+
+/// recursively get all paths inside a directory (including itself), skipping the ones we can't read
+/// and broken symlinks
+pub(crate) fn walk_dir(dir: &Path) -> Vec<PathBuf> {
+    if let Some(entries) = inventory::walk(dir) {
+        return entries.into_iter().map(|(path, _)| path).collect();
+    }
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Ok(entry) if entry.path_is_symlink() && !entry.path().exists() => None,
+            Ok(entry) => Some(entry.into_path()),
+            Err(error) => {
+                if let Some(path) = error.path().map(Path::to_path_buf) {
+                    if let Some(io_error) = error.into_io_error() {
+                        record_scan_error(&path, io_error);
+                    }
+                }
+                None
+            }
+        })
+        .collect()
+}
+
+/// same as `walk_dir` but only the files
+pub(crate) fn walk_files(dir: &Path) -> Vec<PathBuf> {
+    if let Some(entries) = inventory::walk(dir) {
+        return entries
+            .into_iter()
+            .filter(|(_, info)| !info.is_dir)
+            .map(|(path, _)| path)
+            .collect();
+    }
+    walk_dir(dir).into_iter().filter(|f| f.is_file()).collect()
+}
+
+/// the total size of the files inside a directory, without the directories themselves
+pub(crate) fn size_of_files(dir: &Path) -> u64 {
+    if let Some(totals) = inventory::totals(dir) {
+        return totals.file_size;
+    }
+    walk_files(dir).par_iter().map(|f| size_of_file(f)).sum()
+}
+
+/// get the last access time of a file, None if it vanished or can't be read
+pub(crate) fn last_access(path: &Path) -> Option<SystemTime> {
+    if let Some(accessed) = inventory::get(path).and_then(|info| info.accessed) {
+        return Some(accessed);
+    }
+    match fs::metadata(path).and_then(|metadata| metadata.accessed()) {
+        Ok(time) => Some(time),
+        Err(error) => {
+            record_scan_error(path, error);
+            None
+        }
+    }
+}
+
 /// get the total size of a directory or a file
 pub(crate) fn size_of_path(path: &Path) -> u64 {
     // if the path is a directory, use cumulative_dir_size
     if path.is_dir() {
         cumulative_dir_size(path).dir_size
     } else {
-        fs::metadata(&path)
-            .unwrap_or_else(|_| panic!("Failed to get metadata of file '{}'", &path.display()))
-            .len()
+        size_of_file(path)
     }
 }
 
@@ -481,36 +918,42 @@ pub(crate) fn cumulative_dir_size(dir: &Path) -> DirInfo {
         };
     }
 
-    // traverse recursively and sum filesizes, parallelized by rayon
-    let walkdir_start = dir.display().to_string();
-
-    let dir_size = WalkDir::new(&walkdir_start)
-        .into_iter()
-        .map(|e| e.unwrap().path().to_owned())
-        .filter(|f| f.exists()) // avoid broken symlinks
-        .collect::<Vec<_>>() // @TODO perhaps WalkDir will impl ParallelIterator one day
-        .par_iter()
-        .filter(|f| f.exists()) // check if the file still exists. Since collecting and processing a
-        // path, some time may have passed and if we have a "cargo build" operation
-        // running in the directory, a temporary file may be gone already and failing to unwrap() (#43)
-        .map(|f| {
-            fs::metadata(f)
-                .unwrap_or_else(|_| panic!("Failed to get metadata of file '{}'", &f.display()))
-                .len()
-        })
-        .sum();
-
     // for the file number, we don't want the actual number of files but only the number of
     // files in the current directory, limit search depth
+    let registry = dir.display().to_string().contains("registry");
+
+    // the inventory knows the sizes even of the items it took from the size index
+    if let Some(totals) = inventory::totals(dir) {
+        let file_number = if registry {
+            read_dir_paths(dir)
+                .iter()
+                .filter(|path| !is_file(path))
+                .map(|path| read_dir_paths(path).len())
+                .sum::<usize>()
+        } else {
+            read_dir_paths(dir).len()
+        } as u64;
+        return DirInfo {
+            dir_size: totals.size,
+            file_number,
+        };
+    }
 
-    let file_number = if walkdir_start.contains("registry") {
-        WalkDir::new(&walkdir_start)
-            .max_depth(2)
-            .min_depth(2)
-            .into_iter()
+    // traverse recursively and sum filesizes, parallelized by rayon
+    // (walk_dir() skips broken symlinks and size_of_file() files that are gone already (#43))
+    let paths = walk_dir(dir);
+    let dir_size = paths.par_iter().map(|f| size_of_file(f)).sum();
+
+    let file_number = if registry {
+        paths
+            .iter()
+            .filter(|path| {
+                path.strip_prefix(dir)
+                    .map_or(false, |relative| relative.components().count() == 2)
+            })
             .count()
     } else {
-        fs::read_dir(&dir).unwrap().count()
+        read_dir_paths(dir).len()
     } as u64;
 
     DirInfo {
@@ -778,6 +1221,38 @@ mod libtests {
         assert_eq!(x.file_number, 20);
     }
 
+    #[test]
+    fn remove_dir_selectors() {
+        let (components, selectors) = selectors_from_groups(Some(
+            "git-repos,registry-cache@my-registry,git-db@cargo-e7ff1db891893a9e,crate:serde@<1.0.100,crate:rand",
+        ))
+        .unwrap();
+        assert_eq!(components, vec![Component::GitRepos]);
+        let selectors: Vec<Selector> = selectors
+            .into_iter()
+            .map(|(_term, selector)| selector)
+            .collect();
+        assert_eq!(
+            selectors,
+            vec![
+                Selector::Item(Component::RegistryCrateCache, String::from("my-registry")),
+                Selector::Item(Component::GitDB, String::from("cargo-e7ff1db891893a9e")),
+                Selector::Crate(
+                    String::from("serde"),
+                    Some(VersionReq::parse("<1.0.100").unwrap())
+                ),
+                Selector::Crate(String::from("rand"), None),
+            ]
+        );
+
+        match selectors_from_groups(Some("all@foo,git-db@,crate:@1,registry-sources")) {
+            Err(Error::InvalidDeletableDirs(invalid)) => {
+                assert_eq!(invalid, "all@foo git-db@ crate:@1");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
     #[allow(non_snake_case)]
     #[test]
     fn test_CargoCachePaths_gen() {
@@ -967,6 +1442,26 @@ mod libtests {
         let last = iter.next();
         assert!(!last.is_some(), "found another directory?!: '{:?}'", last);
     }
+
+    #[test]
+    fn vanished_paths_are_skipped() {
+        let vanished = PathBuf::from("target/this/path/vanished");
+
+        assert_eq!(size_of_file(&vanished), 0);
+        assert_eq!(size_of_path(&vanished), 0);
+        assert!(read_dir_paths(&vanished).is_empty());
+        assert!(walk_dir(&vanished).is_empty());
+        assert!(last_access(&vanished).is_none());
+
+        // other tests may record errors concurrently, only look at ours
+        let recorded = SCAN_ERRORS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|error| matches!(error, Error::ScanFailed(path, _) if path == &vanished))
+            .count();
+        assert_eq!(recorded, 5);
+    }
 }
 
 #[cfg(all(test, feature = "bench"))]
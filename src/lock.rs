@@ -0,0 +1,110 @@
+// Copyright 2017-2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// cargo takes an exclusive lock on $CARGO_HOME/.package-cache while it downloads and extracts crates.
+// We take the same lock before removing anything so that we never remove a crate that cargo is
+// extracting right now. Since every removing cargo-cache takes it, it also keeps concurrent
+// cargo-cache invocations from stepping on each others toes.
+
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use crate::library::Error;
+
+use fs2::FileExt;
+
+/// what to do if the package cache is already locked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LockMode {
+    Wait,   // block until the lock is released
+    NoWait, // fail right away
+}
+
+/// the lock on the package cache, it is released when this is dropped
+#[derive(Debug)]
+pub(crate) struct PackageCacheLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl PackageCacheLock {
+    pub(crate) fn acquire(cargo_home: &Path, mode: LockMode) -> Result<Self, Error> {
+        let path = cargo_home.join(".package-cache");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| Error::PackageCacheLockFailed(path.clone(), e))?;
+
+        match file.try_lock_exclusive() {
+            Ok(()) => {}
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                if mode == LockMode::NoWait {
+                    return Err(Error::PackageCacheLocked(path));
+                }
+                // same message as cargo
+                eprintln!("Blocking waiting for file lock on package cache");
+                file.lock_exclusive()
+                    .map_err(|error| Error::PackageCacheLockFailed(path.clone(), error))?;
+            }
+            Err(e) => {
+                // cargo does not lock either if the filesystem does not support it (nfs etc)
+                eprintln!(
+                    "Warning: failed to lock \"{}\", continuing without lock: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+
+        Ok(Self { file, path })
+    }
+}
+
+impl Drop for PackageCacheLock {
+    fn drop(&mut self) {
+        // std has an inherent File::unlock nowadays, make sure we use the fs2 one
+        if let Err(e) = FileExt::unlock(&self.file) {
+            eprintln!(
+                "Warning: failed to unlock \"{}\": {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod lock_tests {
+    use super::*;
+
+    #[test]
+    fn package_cache_lock_excludes() {
+        let cargo_home = PathBuf::from("target/package_cache_lock_test");
+        std::fs::create_dir_all(&cargo_home).unwrap();
+
+        let lock = PackageCacheLock::acquire(&cargo_home, LockMode::NoWait).unwrap();
+        assert!(cargo_home.join(".package-cache").is_file());
+
+        // a second lock (as taken by cargo or another cargo-cache) fails while we hold ours
+        let other = File::open(cargo_home.join(".package-cache")).unwrap();
+        assert!(other.try_lock_exclusive().is_err());
+        assert!(matches!(
+            PackageCacheLock::acquire(&cargo_home, LockMode::NoWait),
+            Err(Error::PackageCacheLocked(_))
+        ));
+
+        // and succeeds once we drop it
+        drop(lock);
+        assert!(other.try_lock_exclusive().is_ok());
+        FileExt::unlock(&other).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright 2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// read the packages of a Cargo.lock without asking cargo: "cargo metadata" would download
+// and extract every package that is missing from the cache, which is exactly what the users of this
+// want to avoid or do themselves.
+// This is also used by the ci-autoclean build, which has neither cargo_metadata nor a toml parser.
+
+use std::path::{Path, PathBuf};
+
+#[cfg(not(feature = "ci-autoclean"))]
+use crate::library::Error;
+#[cfg(not(feature = "ci-autoclean"))]
+use std::fs;
+
+/// a package of a Cargo.lock that comes from a registry or a git repo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LockedPackage {
+    pub(crate) name: String,
+    pub(crate) version: String,
+    /// `registry+https://github.com/rust-lang/crates.io-index`, `sparse+https://...` or `git+https://...`
+    pub(crate) source: String,
+}
+
+impl LockedPackage {
+    /// the file name of the archive inside registry/cache/<registry>/
+    pub(crate) fn archive_name(&self) -> String {
+        format!("{}-{}.crate", self.name, self.version)
+    }
+
+    /// does the package come from a registry (and not from git)?
+    pub(crate) fn is_registry(&self) -> bool {
+        self.source.starts_with("registry+") || self.source.starts_with("sparse+")
+    }
+
+    /// the (lowercase) name cargo uses for the db of a git source like
+    /// `git+https://github.com/rust-lang/cargo?branch=master#0123abc`, git/db/<name>-<hash>
+    #[cfg(any(test, feature = "ci-autoclean"))]
+    pub(crate) fn git_repo_name(&self) -> Option<String> {
+        let url = self.source.strip_prefix("git+")?;
+        let url = url
+            .split(|c| c == '?' || c == '#')
+            .next()?
+            .trim_end_matches('/');
+        let name = url.rsplit('/').next()?;
+        let name = name.strip_suffix(".git").unwrap_or(name);
+        Some(name.to_lowercase())
+    }
+}
+
+/// the registry and git packages of the content of a Cargo.lock, local packages (without a source) are skipped
+pub(crate) fn packages(lockfile: &str) -> Vec<LockedPackage> {
+    fn finish(
+        package: &mut (Option<String>, Option<String>, Option<String>),
+        packages: &mut Vec<LockedPackage>,
+    ) {
+        if let (Some(name), Some(version), Some(source)) = package.clone() {
+            packages.push(LockedPackage {
+                name,
+                version,
+                source,
+            });
+        }
+        *package = (None, None, None);
+    }
+
+    let mut packages = Vec::new();
+    // name, version and source of the [[package]] we are currently in
+    let mut package = (None, None, None);
+    for line in lockfile.lines().map(str::trim) {
+        if line.starts_with('[') {
+            finish(&mut package, &mut packages);
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            let value = Some(value.trim().trim_matches('"').to_string());
+            match key.trim() {
+                "name" => package.0 = value,
+                "version" => package.1 = value,
+                "source" => package.2 = value,
+                _ => {}
+            }
+        }
+    }
+    finish(&mut package, &mut packages);
+    packages
+}
+
+/// the registry packages of the content of a Cargo.lock, local and git packages are skipped
+#[cfg(not(feature = "ci-autoclean"))]
+pub(crate) fn registry_packages(lockfile: &str) -> Vec<LockedPackage> {
+    packages(lockfile)
+        .into_iter()
+        .filter(LockedPackage::is_registry)
+        .collect()
+}
+
+/// the Cargo.lock in `dir` or the closest directory above it
+pub(crate) fn lockfile_above(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|lockfile| lockfile.is_file())
+}
+
+/// the Cargo.lock of the crate of `manifest`, next to it or in the root of its workspace
+#[cfg(not(feature = "ci-autoclean"))]
+pub(crate) fn find_lockfile(manifest: &Path) -> Result<PathBuf, Error> {
+    lockfile_above(manifest.parent().unwrap_or(manifest))
+        .ok_or_else(|| Error::NoLockfile(manifest.to_path_buf()))
+}
+
+/// read the registry packages of the `lockfile`
+#[cfg(not(feature = "ci-autoclean"))]
+pub(crate) fn read_lockfile(lockfile: &Path) -> Result<Vec<LockedPackage>, Error> {
+    fs::read_to_string(lockfile)
+        .map(|content| registry_packages(&content))
+        .map_err(|e| Error::LockfileReadFailed(lockfile.to_path_buf(), e))
+}
+
+#[cfg(test)]
+mod lockfile_tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn lockfile_registry_packages() {
+        let lockfile = r#"# This file is automatically @generated by Cargo.
+# It is not intended for manual editing.
+version = 3
+
+[[package]]
+name = "cargo-cache"
+version = "0.7.0"
+dependencies = [
+ "semver",
+]
+
+[[package]]
+name = "semver"
+version = "1.0.4"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "568a8e6258aa33c13358f81fd834adb854c6f7c9468520910a9b1e8fac068012"
+
+[[package]]
+name = "git2"
+version = "0.13.22"
+source = "git+https://github.com/rust-lang/git2-rs?branch=master#0123abc"
+
+[[package]]
+name = "private"
+version = "0.1.0"
+source = "sparse+https://crates.example.com/index/"
+"#;
+        assert_eq!(
+            registry_packages(lockfile),
+            vec![
+                LockedPackage {
+                    name: "semver".into(),
+                    version: "1.0.4".into(),
+                    source: "registry+https://github.com/rust-lang/crates.io-index".into(),
+                },
+                LockedPackage {
+                    name: "private".into(),
+                    version: "0.1.0".into(),
+                    source: "sparse+https://crates.example.com/index/".into(),
+                },
+            ]
+        );
+        assert_eq!(
+            registry_packages(lockfile)[0].archive_name(),
+            "semver-1.0.4.crate"
+        );
+
+        let git_repos: Vec<Option<String>> = packages(lockfile)
+            .iter()
+            .map(LockedPackage::git_repo_name)
+            .collect();
+        assert_eq!(git_repos, vec![None, Some("git2-rs".into()), None]);
+    }
+}
@@ -62,35 +62,45 @@
 cfg_if::cfg_if! {
     if #[cfg(not(feature = "ci-autoclean"))] {
         // mods
+        mod audit;
         mod cache;
         mod cli;
         mod commands;
         mod dirsizes;
+        mod disk_usage;
         mod tables;
         mod git;
         mod library;
+        mod lock;
+        mod metrics;
+        mod registries;
         mod remove;
         mod top_items;
         mod top_items_summary;
+        mod trash;
         mod date;
         mod clean_unref;
 
         // use
         use crate::cache::caches::{Cache, RegistrySuperCache};
-        use std::process;
         use std::time::SystemTime;
-        use walkdir::WalkDir;
         use crate::cache::*;
-        use crate::commands::{local, query, sccache, trim, toolchains};
+        use crate::commands::{ci_key, doctor, extract, installed, local, migrate_registry, prune_index, query, sccache, trim, toolchains, watch};
         use crate::git::*;
         use crate::library::*;
+        use crate::lock::PackageCacheLock;
         use crate::remove::*;
         use crate::top_items_summary::*;
         use crate::clean_unref::*;
         use crate::cli::{CargoCacheCommands};
+    } else {
+        mod ci_autoclean;
     }
 }
 
+// shared with the ci-autoclean build
+mod lockfile;
+
 #[cfg(all(any(test, feature = "bench", not(feature = "ci-autoclean"))))]
 mod test_helpers;
 
@@ -109,10 +119,14 @@ fn main() {
 
     let config_enum = cli::clap_to_enum(config);
 
+    if cli::strict_from_clap(config) {
+        set_strict();
+    }
+
     // handle hidden "version" subcommand
     if config.is_present("version") {
         println!("cargo-cache {}", cli::get_version());
-        process::exit(0);
+        exit(0);
     }
 
     let debug_mode: bool = config.is_present("debug");
@@ -128,7 +142,7 @@ fn main() {
         CargoCacheCommands::SCCache => sccache::sccache_stats().exit_or_fatal_error(),
         CargoCacheCommands::Toolchain => {
             toolchains::toolchain_stats();
-            process::exit(0);
+            exit(0);
         }
         _ => {}
     }
@@ -141,9 +155,60 @@ fn main() {
     if let CargoCacheCommands::ListDirs = config_enum {
         // only print the directories and exit, don't calculate anything else
         println!("{}", cargo_cache);
-        process::exit(0);
+        exit(0);
+    }
+
+    if let CargoCacheCommands::Log {
+        since,
+        until,
+        krate,
+    } = config_enum
+    {
+        // only read the audit log, no need to look at the cache
+        audit::print_log(&cargo_cache, since, until, krate).exit_or_fatal_error();
+    }
+
+    if let CargoCacheCommands::Metrics { textfile, listen } = config_enum {
+        // scans the cache itself, again for every request when serving the metrics
+        metrics::metrics(&cargo_cache, textfile, listen).exit_or_fatal_error();
+    }
+
+    if let CargoCacheCommands::Watch {
+        dry_run,
+        limit,
+        interval,
+    } = config_enum
+    {
+        // only takes the package cache lock while it trims, so cargo can keep working in between
+        watch::watch(&cargo_cache, limit, interval, dry_run).exit_or_fatal_error();
     }
 
+    if let CargoCacheCommands::CiKey {
+        manifest_path,
+        prefix,
+        restore_keys,
+    } = config_enum
+    {
+        // only looks at the dependencies and the toolchain, not at the cache
+        ci_key::ci_key(&cargo_cache, manifest_path, prefix, restore_keys).exit_or_fatal_error();
+    }
+
+    let lock_mode = cli::lock_mode_from_clap(config);
+    // hold cargo's package cache lock while we remove anything so we don't remove crates
+    // that a concurrent cargo is extracting right now
+    let _package_cache_lock = if config_enum.removes_files() {
+        Some(PackageCacheLock::acquire(&cargo_cache.cargo_home, lock_mode).unwrap_or_fatal_error())
+    } else {
+        None
+    };
+
+    if cli::trash_from_clap(config) {
+        trash::enable();
+    }
+
+    // scan the whole CARGO_HOME once, all the caches below read from this
+    inventory::scan(&cargo_cache.cargo_home);
+
     // create cache
     let p = CargoCachePaths::default().unwrap();
 
@@ -179,9 +244,12 @@ fn main() {
         CargoCacheCommands::Trim {
             dry_run,
             trim_limit,
+            ensure_free,
         } => {
             let trim_result = trim::trim_cache(
+                &cargo_cache,
                 trim_limit,
+                ensure_free,
                 &mut checkouts_cache,
                 &mut bare_repos_cache,
                 &mut registry_pkgs_cache,
@@ -208,6 +276,7 @@ fn main() {
             let clean_unref_result = clean_unref(
                 &cargo_cache,
                 manifest_path,
+                lock_mode,
                 &mut bin_cache,
                 &mut checkouts_cache,
                 &mut bare_repos_cache,
@@ -229,12 +298,169 @@ fn main() {
             );
             clean_unref_result.exit_or_fatal_error();
         }
-        CargoCacheCommands::TopCacheItems { limit } => {
+        CargoCacheCommands::MigrateRegistry {
+            dry_run,
+            from,
+            to,
+            hardlink,
+            remove_old,
+        } => {
+            let migrate_result = migrate_registry::migrate_registry(
+                &cargo_cache,
+                from,
+                to,
+                hardlink,
+                remove_old,
+                dry_run,
+                &mut size_changed,
+            );
+            // registry/cache/<to> may have been created just now
+            registry_pkgs_cache =
+                registry_pkg_cache::RegistryPkgCaches::new(cargo_cache.registry_pkg_cache.clone());
+            registry_sources_caches.invalidate();
+            registry_index_caches.invalidate();
+            dirsizes::DirSizes::print_size_difference(
+                &dir_sizes_original,
+                &cargo_cache,
+                &mut bin_cache,
+                &mut checkouts_cache,
+                &mut bare_repos_cache,
+                &mut registry_pkgs_cache,
+                &mut registry_index_caches,
+                &mut registry_sources_caches,
+            );
+            migrate_result.exit_or_fatal_error();
+        }
+        CargoCacheCommands::Doctor { dry_run, fix } => {
+            let doctor_result = doctor::doctor(&cargo_cache, fix, dry_run, &mut size_changed);
+            bin_cache.invalidate();
+            checkouts_cache.invalidate();
+            bare_repos_cache.invalidate();
+            registry_pkgs_cache.invalidate();
+            registry_sources_caches.invalidate();
+            registry_index_caches.invalidate();
+            if size_changed {
+                dirsizes::DirSizes::print_size_difference(
+                    &dir_sizes_original,
+                    &cargo_cache,
+                    &mut bin_cache,
+                    &mut checkouts_cache,
+                    &mut bare_repos_cache,
+                    &mut registry_pkgs_cache,
+                    &mut registry_index_caches,
+                    &mut registry_sources_caches,
+                );
+            }
+            doctor_result.exit_or_fatal_error();
+        }
+        CargoCacheCommands::Extract {
+            dry_run,
+            manifest_path,
+        } => {
+            let extract_result = extract::extract(
+                &cargo_cache,
+                manifest_path,
+                &mut registry_pkgs_cache,
+                dry_run,
+                &mut size_changed,
+            );
+            // registry/src/<registry> may have been created just now
+            registry_sources_caches =
+                registry_sources::RegistrySourceCaches::new(cargo_cache.registry_sources.clone());
+            if size_changed {
+                dirsizes::DirSizes::print_size_difference(
+                    &dir_sizes_original,
+                    &cargo_cache,
+                    &mut bin_cache,
+                    &mut checkouts_cache,
+                    &mut bare_repos_cache,
+                    &mut registry_pkgs_cache,
+                    &mut registry_index_caches,
+                    &mut registry_sources_caches,
+                );
+            }
+            extract_result.exit_or_fatal_error();
+        }
+        CargoCacheCommands::PruneIndex {
+            dry_run,
+            unused_for,
+            lockfiles,
+        } => {
+            let keep = match lockfiles {
+                Some(lockfiles) => prune_index::Keep::Locked(
+                    lockfiles.split(',').map(std::path::PathBuf::from).collect(),
+                ),
+                None => prune_index::Keep::CachedOrUsedWithin(
+                    date::parse_duration(unused_for).unwrap_or_fatal_error(),
+                ),
+            };
+            let prune_result = prune_index::prune_index(
+                &cargo_cache,
+                &keep,
+                &mut registry_pkgs_cache,
+                &mut registry_index_caches,
+                dry_run,
+                &mut size_changed,
+            );
+            if size_changed {
+                dirsizes::DirSizes::print_size_difference(
+                    &dir_sizes_original,
+                    &cargo_cache,
+                    &mut bin_cache,
+                    &mut checkouts_cache,
+                    &mut bare_repos_cache,
+                    &mut registry_pkgs_cache,
+                    &mut registry_index_caches,
+                    &mut registry_sources_caches,
+                );
+            }
+            prune_result.exit_or_fatal_error();
+        }
+        CargoCacheCommands::Bin {
+            remove: None,
+            remove_unused_since: None,
+            ..
+        } => {
+            installed::bin_inventory(&cargo_cache, &mut bin_cache).exit_or_fatal_error();
+        }
+        CargoCacheCommands::Bin {
+            dry_run,
+            remove,
+            remove_unused_since,
+        } => {
+            let remove_result = installed::remove_installed(
+                &cargo_cache,
+                &mut bin_cache,
+                remove,
+                remove_unused_since,
+                dry_run,
+                &mut size_changed,
+            );
+            bin_cache.invalidate();
+            if size_changed {
+                dirsizes::DirSizes::print_size_difference(
+                    &dir_sizes_original,
+                    &cargo_cache,
+                    &mut bin_cache,
+                    &mut checkouts_cache,
+                    &mut bare_repos_cache,
+                    &mut registry_pkgs_cache,
+                    &mut registry_index_caches,
+                    &mut registry_sources_caches,
+                );
+            }
+            remove_result.exit_or_fatal_error();
+        }
+        CargoCacheCommands::TopCacheItems {
+            limit,
+            group_by_crate,
+        } => {
             if limit > 0 {
                 println!(
                     "{}",
                     get_top_crates(
                         limit,
+                        group_by_crate,
                         &cargo_cache,
                         &mut bin_cache,
                         &mut checkouts_cache,
@@ -245,18 +471,29 @@ fn main() {
                     )
                 );
             }
-            process::exit(0);
+            exit(0);
         }
         CargoCacheCommands::Query { query_config } => {
             query::run_query(
                 query_config,
+                &mut size_changed,
                 &mut bin_cache,
                 &mut checkouts_cache,
                 &mut bare_repos_cache,
                 &mut registry_pkgs_cache,
                 &mut registry_sources_caches,
             )
-            .exit_or_fatal_error();
+            .unwrap_or_fatal_error();
+
+            if !size_changed {
+                exit(0);
+            }
+            // "query --remove" removed something, print the size difference below
+            bin_cache.invalidate();
+            checkouts_cache.invalidate();
+            bare_repos_cache.invalidate();
+            registry_pkgs_cache.invalidate();
+            registry_sources_caches.invalidate();
         }
         CargoCacheCommands::Local => {
             local::local_subcmd().exit_or_fatal_error();
@@ -296,7 +533,25 @@ fn main() {
         }
         CargoCacheCommands::Info => {
             println!("{}", get_info(&cargo_cache, &dir_sizes_original));
-            process::exit(0);
+            exit(0);
+        }
+        CargoCacheCommands::Undo => {
+            trash::undo(&cargo_cache, &mut size_changed).unwrap_or_fatal_error();
+            // anything could have been restored, print the size difference below
+            bin_cache.invalidate();
+            checkouts_cache.invalidate();
+            bare_repos_cache.invalidate();
+            registry_pkgs_cache.invalidate();
+            registry_index_caches.invalidate();
+            registry_sources_caches.invalidate();
+        }
+        CargoCacheCommands::EmptyTrash {
+            dry_run,
+            older_than,
+        } => {
+            let older_than =
+                older_than.map(|duration| date::parse_duration(duration).unwrap_or_fatal_error());
+            trash::empty_trash(&cargo_cache, older_than, dry_run).exit_or_fatal_error();
         }
         // This one must come BEFORE RemoveIfDate because that one also uses --remove dir
         CargoCacheCommands::RemoveDir { dry_run } => {
@@ -328,12 +583,16 @@ fn main() {
             git_fsck_everything(&cargo_cache.git_repos_bare, &cargo_cache.registry_pkg_cache)
                 .exit_or_fatal_error();
         }
-        CargoCacheCommands::GitGCRepos { dry_run } => {
+        CargoCacheCommands::GitGCRepos {
+            dry_run,
+            ref gc_config,
+        } => {
             //@TODO deduplicate between autoclean-expensive!
             let res = git_gc_everything(
                 &cargo_cache.git_repos_bare,
                 &cargo_cache.registry_pkg_cache,
                 dry_run,
+                gc_config,
             );
 
             if !dry_run {
@@ -379,13 +638,17 @@ fn main() {
                 &mut registry_index_caches,
                 &mut registry_sources_caches,
             );
-            std::process::exit(0);
+            exit(0);
         }
-        CargoCacheCommands::AutoCleanExpensive { dry_run } => {
+        CargoCacheCommands::AutoCleanExpensive {
+            dry_run,
+            ref gc_config,
+        } => {
             let res = git_gc_everything(
                 &cargo_cache.git_repos_bare,
                 &cargo_cache.registry_pkg_cache,
                 dry_run,
+                gc_config,
             );
 
             if !dry_run {
@@ -429,13 +692,19 @@ fn main() {
                 &mut registry_index_caches,
                 &mut registry_sources_caches,
             );
-            std::process::exit(0);
+            exit(0);
         }
-        CargoCacheCommands::KeepDuplicateCrates { dry_run, limit } => {
+        CargoCacheCommands::KeepDuplicateCrates {
+            dry_run,
+            limit,
+            scope,
+        } => {
             let res = rm_old_crates(
                 limit,
+                scope,
                 dry_run,
-                &cargo_cache.registry_pkg_cache,
+                &mut registry_pkgs_cache,
+                &mut registry_sources_caches,
                 &mut size_changed,
             );
             registry_pkgs_cache.invalidate();
@@ -462,6 +731,31 @@ fn main() {
                 };
             }
         }
+        CargoCacheCommands::KeepGitRevisions {
+            dry_run,
+            limit,
+            order,
+        } => {
+            rm_old_git_checkouts(
+                limit,
+                order,
+                dry_run,
+                &mut checkouts_cache,
+                &mut size_changed,
+            );
+            checkouts_cache.invalidate();
+
+            dirsizes::DirSizes::print_size_difference(
+                &dir_sizes_original,
+                &cargo_cache,
+                &mut bin_cache,
+                &mut checkouts_cache,
+                &mut bare_repos_cache,
+                &mut registry_pkgs_cache,
+                &mut registry_index_caches,
+                &mut registry_sources_caches,
+            );
+        }
         CargoCacheCommands::OnlyDryRun => {
             if !size_changed {
                 eprintln!("Warning: there is nothing to be dry run!");
@@ -494,11 +788,16 @@ fn main() {
             &mut registry_index_caches,
             &mut registry_sources_caches,
             &mut registry_pkgs_cache,
+            &registries::Registries::load(&cargo_cache.cargo_home),
         );
         print!("{}", output);
     } else if matches!(config_enum, CargoCacheCommands::DefaultSummary) {
         // default summary
-        print!("{}", dir_sizes_original);
+        let mut dir_sizes = dir_sizes_original;
+        if cli::disk_usage_from_clap(config) {
+            dir_sizes.add_disk_usage(&cargo_cache);
+        }
+        print!("{}", dir_sizes);
     }
 
     if debug_mode {
@@ -508,8 +807,7 @@ fn main() {
 
         let cache_root = CargoCachePaths::default().unwrap().cargo_home;
 
-        let wd = WalkDir::new(cache_root.display().to_string());
-        let file_count = wd.into_iter().count();
+        let file_count = walk_dir(&cache_root).len();
         let time_as_milis = time_elasped.as_millis();
         let time_as_nanos = time_elasped.as_nanos();
         println!("processed {} files in {} ms", file_count, time_as_milis);
@@ -518,70 +816,13 @@ fn main() {
         println!("{} files per ms", files_per_ms);
         println!("{} ns per file", ns_per_file);
     }
+
+    // report paths we failed to read, if any
+    exit(0);
 }
 
 // the main function when using the ci-autoclean feature
-// this is a very stripped-down version of cargo-cache which has minimal external dependencies and should
-// compile within a couple of seconds in order to be used on CI to clean the cargo-home for caching on CI-cache (travis/azure etc)
 #[cfg(feature = "ci-autoclean")]
 fn main() {
-    use std::path::{Path, PathBuf};
-
-    #[derive(Debug, Clone)]
-    struct CargoCachePaths {
-        /// path where registry sources (.rs files / extracted .crate archives) are stored
-        registry_sources: PathBuf,
-
-        /// git repository checkouts are stored here
-        git_checkouts: PathBuf,
-    }
-
-    impl CargoCachePaths {
-        /// returns `CargoCachePaths` object which makes all the subpaths accessible to the crate
-        pub(crate) fn default() -> Self {
-            let cargo_home = if let Ok(cargo_home) = home::cargo_home() {
-                cargo_home
-            } else {
-                std::process::exit(1);
-            };
-
-            if !cargo_home.is_dir() {
-                std::process::exit(1);
-            }
-            // get the paths to the relevant directories
-            let registry = cargo_home.join("registry");
-            let reg_src = registry.join("src");
-            let git_checkouts = cargo_home.join("git").join("checkouts");
-
-            Self {
-                registry_sources: reg_src,
-                git_checkouts,
-            }
-        }
-    } // impl CargoCachePaths
-
-    pub(crate) fn remove_file(path: &Path) {
-        if path.is_file() && std::fs::remove_file(&path).is_err() {
-            eprintln!("Warning: failed to remove file \"{}\".", path.display());
-        }
-
-        if path.is_dir() && remove_dir_all::remove_dir_all(&path).is_err() {
-            eprintln!(
-                "Warning: failed to recursively remove directory \"{}\".",
-                path.display()
-            );
-        }
-    }
-
-    let cargo_cache = CargoCachePaths::default();
-
-    println!("cargo-cache: running \"cargo cache --autoclean\"");
-
-    let reg_srcs = &cargo_cache.registry_sources;
-    let git_checkouts = &cargo_cache.git_checkouts;
-    for dir in &[reg_srcs, git_checkouts] {
-        if dir.is_dir() {
-            remove_file(dir);
-        }
-    }
+    ci_autoclean::run();
 }
@@ -0,0 +1,360 @@
+// Copyright 2017-2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// "cargo cache metrics" prints the sizes and item counts of the summary (DirSizes) and of each
+// registry ("cargo cache registry") in the OpenMetrics text format so that they can be scraped by
+// prometheus. --textfile writes them to a file for the node-exporter textfile collector,
+// --listen serves them on http://ADDRESS/metrics and scans the cache again for every request.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+
+use crate::cache::caches::{Cache, RegistrySubCache, RegistrySuperCache};
+use crate::cache::*;
+use crate::dirsizes::DirSizes;
+use crate::library::{clear_scan_errors, scan_error_count, CargoCachePaths, Error};
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// one line of a metric family: the labels and the value
+struct Sample {
+    labels: Vec<(&'static str, String)>,
+    value: u64,
+}
+
+impl Sample {
+    fn new(labels: &[(&'static str, &str)], value: u64) -> Self {
+        Self {
+            labels: labels
+                .iter()
+                .map(|(label, text)| (*label, (*text).to_string()))
+                .collect(),
+            value,
+        }
+    }
+}
+
+// label values are quoted, backslashes, quotes and newlines have to be escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// the lines of a gauge together with its metadata, unit is the suffix of the name ("bytes") if it has one
+fn gauge(name: &str, help: &str, unit: Option<&str>, samples: &[Sample]) -> Vec<String> {
+    let mut lines = vec![format!("# TYPE {} gauge", name)];
+    if let Some(unit) = unit {
+        lines.push(format!("# UNIT {} {}", name, unit));
+    }
+    lines.push(format!("# HELP {} {}", name, help));
+    for sample in samples {
+        let labels: Vec<String> = sample
+            .labels
+            .iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+            .collect();
+        if labels.is_empty() {
+            lines.push(format!("{} {}", name, sample.value));
+        } else {
+            lines.push(format!("{}{{{}}} {}", name, labels.join(","), sample.value));
+        }
+    }
+    lines
+}
+
+// the directory name of a registry (github.com-1ecc6299db9ec823), this is what identifies it
+fn directory_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// the metrics of the summary and of each registry
+fn render(
+    dir_sizes: &DirSizes<'_>,
+    index_caches: &mut registry_index::RegistryIndicesCache,
+    registry_sources: &mut registry_sources::RegistrySourceCaches,
+    pkg_caches: &mut registry_pkg_cache::RegistryPkgCaches,
+    skipped_paths: usize,
+) -> String {
+    let mut lines = Vec::new();
+
+    lines.extend(gauge(
+        "cargo_cache_total_size_bytes",
+        "Total size of the cargo cache (without the trash).",
+        Some("bytes"),
+        &[Sample::new(&[], dir_sizes.total_size())],
+    ));
+
+    // the names of the components are the ones --remove-dir accepts
+    let components = [
+        (
+            "bin",
+            dir_sizes.total_bin_size(),
+            dir_sizes.numb_bins() as u64,
+        ),
+        (
+            "registry-index",
+            dir_sizes.total_reg_index_size(),
+            dir_sizes.total_reg_index_num(),
+        ),
+        (
+            "registry-crate-cache",
+            dir_sizes.total_reg_cache_size(),
+            dir_sizes.numb_reg_cache_entries() as u64,
+        ),
+        (
+            "registry-sources",
+            dir_sizes.total_reg_src_size(),
+            dir_sizes.numb_reg_src_checkouts() as u64,
+        ),
+        (
+            "git-db",
+            dir_sizes.total_git_repos_bare_size(),
+            dir_sizes.numb_git_repos_bare_repos() as u64,
+        ),
+        (
+            "git-repos",
+            dir_sizes.total_git_chk_size(),
+            dir_sizes.numb_git_checkouts() as u64,
+        ),
+        (
+            "trash",
+            dir_sizes.total_trash_size(),
+            dir_sizes.numb_trash_runs() as u64,
+        ),
+    ];
+    lines.extend(gauge(
+        "cargo_cache_size_bytes",
+        "Size of a component of the cargo cache.",
+        Some("bytes"),
+        &components
+            .iter()
+            .map(|(component, size, _)| Sample::new(&[("component", component)], *size))
+            .collect::<Vec<_>>(),
+    ));
+    lines.extend(gauge(
+        "cargo_cache_items",
+        "Number of items (binaries, indices, crates, repos, checkouts, trash runs) in a component of the cargo cache.",
+        None,
+        &components
+            .iter()
+            .map(|(component, _, items)| Sample::new(&[("component", component)], *items))
+            .collect::<Vec<_>>(),
+    ));
+
+    let mut registry_sizes = Vec::new();
+    let mut registry_items = Vec::new();
+    for index in index_caches.caches() {
+        let size = index.total_size();
+        let directory = directory_name(index.path());
+        let labels = [
+            ("registry", index.name()),
+            ("directory", &directory),
+            ("component", "registry-index"),
+        ];
+        registry_sizes.push(Sample::new(&labels, size));
+    }
+    for pkg_cache in pkg_caches.caches() {
+        let (size, items) = (pkg_cache.total_size(), pkg_cache.number_of_files());
+        let directory = directory_name(pkg_cache.path());
+        let labels = [
+            ("registry", pkg_cache.name()),
+            ("directory", &directory),
+            ("component", "registry-crate-cache"),
+        ];
+        registry_sizes.push(Sample::new(&labels, size));
+        registry_items.push(Sample::new(&labels, items as u64));
+    }
+    for source in registry_sources.caches() {
+        let (size, items) = (source.total_size(), source.number_of_items());
+        let directory = directory_name(source.path());
+        let labels = [
+            ("registry", source.name()),
+            ("directory", &directory),
+            ("component", "registry-sources"),
+        ];
+        registry_sizes.push(Sample::new(&labels, size));
+        registry_items.push(Sample::new(&labels, items as u64));
+    }
+    lines.extend(gauge(
+        "cargo_cache_registry_size_bytes",
+        "Size of a component of a single registry.",
+        Some("bytes"),
+        &registry_sizes,
+    ));
+    lines.extend(gauge(
+        "cargo_cache_registry_items",
+        "Number of crate archives or extracted crates of a single registry.",
+        None,
+        &registry_items,
+    ));
+
+    lines.extend(gauge(
+        "cargo_cache_skipped_paths",
+        "Number of paths that vanished or could not be read while scanning the cache.",
+        None,
+        &[Sample::new(&[], skipped_paths as u64)],
+    ));
+
+    lines.push(String::from("# EOF"));
+    let mut output = lines.join("\n");
+    output.push('\n');
+    output
+}
+
+/// scan the cache and return its metrics
+fn collect(ccd: &CargoCachePaths) -> String {
+    inventory::scan(&ccd.cargo_home);
+
+    let mut bin_cache = bin::BinaryCache::new(ccd.bin_dir.clone());
+    let mut checkouts_cache = git_checkouts::GitCheckoutCache::new(ccd.git_checkouts.clone());
+    let mut bare_repos_cache = git_bare_repos::GitRepoCache::new(ccd.git_repos_bare.clone());
+    let mut registry_pkgs_cache =
+        registry_pkg_cache::RegistryPkgCaches::new(ccd.registry_pkg_cache.clone());
+    let mut registry_sources_caches =
+        registry_sources::RegistrySourceCaches::new(ccd.registry_sources.clone());
+    let mut registry_index_caches =
+        registry_index::RegistryIndicesCache::new(ccd.registry_index.clone());
+
+    let dir_sizes = DirSizes::new(
+        &mut bin_cache,
+        &mut checkouts_cache,
+        &mut bare_repos_cache,
+        &mut registry_pkgs_cache,
+        &mut registry_index_caches,
+        &mut registry_sources_caches,
+        ccd,
+    );
+
+    render(
+        &dir_sizes,
+        &mut registry_index_caches,
+        &mut registry_sources_caches,
+        &mut registry_pkgs_cache,
+        scan_error_count(),
+    )
+}
+
+// answer a single http request, only GET /metrics is known
+fn respond(ccd: &CargoCachePaths, mut stream: TcpStream) -> std::io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    let _ = reader.read_line(&mut request_line)?;
+    // skip the headers, closing the connection with unread data would reset it
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+
+    let (status, content_type, body) =
+        if let (Some("GET"), Some("/metrics")) = (parts.next(), parts.next()) {
+            // every scrape scans the cache again, don't report the paths of earlier scans
+            clear_scan_errors();
+            ("200 OK", CONTENT_TYPE, collect(ccd))
+        } else {
+            (
+                "404 Not Found",
+                "text/plain; charset=utf-8",
+                String::from("cargo-cache only serves /metrics\n"),
+            )
+        };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// "cargo cache metrics": print the metrics, write them to `textfile` or serve them on `listen`
+pub(crate) fn metrics(
+    ccd: &CargoCachePaths,
+    textfile: Option<&str>,
+    listen: Option<&str>,
+) -> Result<(), Error> {
+    if let Some(address) = listen {
+        let listener = TcpListener::bind(address)
+            .map_err(|error| Error::MetricsListenFailed(address.to_string(), error))?;
+        println!("Serving metrics on http://{}/metrics", address);
+        for stream in listener.incoming() {
+            // a broken connection only affects that request
+            if let Err(error) = stream.and_then(|stream| respond(ccd, stream)) {
+                eprintln!("Warning: failed to serve metrics: {}", error);
+            }
+        }
+        return Ok(());
+    }
+
+    let output = collect(ccd);
+    if let Some(path) = textfile {
+        // the textfile collector may read the file at any time, never let it see half of it
+        let path = Path::new(path);
+        let temporary = path.with_extension("prom.tmp");
+        fs::write(&temporary, output)
+            .and_then(|()| fs::rename(&temporary, path))
+            .map_err(|error| Error::MetricsWriteFailed(path.to_path_buf(), error))
+    } else {
+        print!("{}", output);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod metricstests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn metrics_gauge_format() {
+        let mut lines = gauge(
+            "cargo_cache_registry_size_bytes",
+            "Size of a component of a single registry.",
+            Some("bytes"),
+            &[
+                Sample::new(
+                    &[
+                        ("registry", "github.com"),
+                        ("directory", "github.com-1ecc6299db9ec823"),
+                        ("component", "registry-index"),
+                    ],
+                    1234,
+                ),
+                Sample::new(&[("registry", "my \"odd\"\\registry")], 0),
+            ],
+        );
+        lines.extend(gauge(
+            "cargo_cache_skipped_paths",
+            "Number of paths that vanished or could not be read while scanning the cache.",
+            None,
+            &[Sample::new(&[], 3)],
+        ));
+
+        assert_eq!(
+            lines.join("\n"),
+            "# TYPE cargo_cache_registry_size_bytes gauge
+# UNIT cargo_cache_registry_size_bytes bytes
+# HELP cargo_cache_registry_size_bytes Size of a component of a single registry.
+cargo_cache_registry_size_bytes{registry=\"github.com\",directory=\"github.com-1ecc6299db9ec823\",component=\"registry-index\"} 1234
+cargo_cache_registry_size_bytes{registry=\"my \\\"odd\\\"\\\\registry\"} 0
+# TYPE cargo_cache_skipped_paths gauge
+# HELP cargo_cache_skipped_paths Number of paths that vanished or could not be read while scanning the cache.
+cargo_cache_skipped_paths 3"
+        );
+    }
+}
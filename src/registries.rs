@@ -0,0 +1,556 @@
+// Copyright 2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// cargo names the directories of a registry inside registry/index, registry/cache and registry/src
+// "<host>-<hash>", for example "index.crates.io-1949cf8c6b5b557f", where the hash is derived from the
+// url of the index. We compute these names for all the registries in the cargo configs to find out which
+// registry a directory belongs to. Directories of no configured registry are not necessarily unused: the
+// registry may have been removed from the config (or its url changed), but it may as well be configured by
+// a project somewhere else whose .cargo/config.toml we don't read.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+// the crates.io index, cargo knows it even if it is not configured anywhere
+const CRATES_IO_NAME: &str = "crates-io";
+const CRATES_IO_GIT_INDEX: &str = "https://github.com/rust-lang/crates.io-index";
+const CRATES_IO_SPARSE_INDEX: &str = "sparse+https://index.crates.io/";
+
+/// a registry that is configured in a cargo config file or built into cargo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ConfiguredRegistry {
+    /// the name used in the config, `registries.<name>` or `source.<name>`
+    pub(crate) name: String,
+    /// the url of the index, "sparse+https://..." for sparse registries
+    pub(crate) index: String,
+}
+
+impl ConfiguredRegistry {
+    fn new(name: &str, index: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            index: normalize_url(index),
+        }
+    }
+
+    /// the host of the index, the first part of the directory names
+    fn host(&self) -> String {
+        let url = self.index.strip_prefix("sparse+").unwrap_or(&self.index);
+        let authority = url
+            .split_once("://")
+            .map_or(url, |(_scheme, rest)| rest)
+            .split('/')
+            .next()
+            .unwrap_or_default();
+        // strip the user and the port
+        let host = authority.rsplit('@').next().unwrap_or_default();
+        host.split(':').next().unwrap_or_default().to_lowercase()
+    }
+
+    /// the directory names cargo uses for this registry, the hash changed with cargo 1.85
+    pub(crate) fn directory_names(&self) -> [String; 2] {
+        // the discriminants of cargo's `SourceKind::Registry` and `SourceKind::SparseRegistry`
+        let kind: u8 = if self.index.starts_with("sparse+") {
+            3
+        } else {
+            2
+        };
+        // the source id is hashed as its kind followed by the url as str (terminated by 0xff)
+        let mut legacy = u64::from(kind).to_le_bytes().to_vec();
+        legacy.extend_from_slice(self.index.as_bytes());
+        legacy.push(0xff);
+        let (legacy_hash, _) = siphash(&legacy, 2, 4, false);
+
+        // the stable hasher of newer cargo writes small integers as a single byte
+        let mut stable = vec![kind];
+        stable.extend_from_slice(self.index.as_bytes());
+        stable.push(0xff);
+        let (low, high) = siphash(&stable, 1, 3, true);
+        let stable_hash = low.wrapping_mul(3).wrapping_add(high);
+
+        let host = self.host();
+        [legacy_hash, stable_hash].map(|hash| format!("{}-{}", host, short_hash(hash)))
+    }
+}
+
+// like the url crate, add the "/" after the host if the url has no path
+fn normalize_url(url: &str) -> String {
+    let url = url.trim();
+    match url.split_once("://") {
+        Some((_scheme, rest)) if !rest.contains('/') => format!("{}/", url),
+        _ => url.to_string(),
+    }
+}
+
+// cargo prints the u64 hash as hex of its little endian bytes
+fn short_hash(hash: u64) -> String {
+    format!("{:016x}", hash.swap_bytes())
+}
+
+fn sip_rounds(v: &mut [u64; 4], rounds: usize) {
+    for _ in 0..rounds {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+}
+
+/// `SipHash` with the keys (0, 0), this is std's `SipHasher` with (2, 4) rounds and
+/// rustc's `StableSipHasher128` with (1, 3) rounds and the 128 bit output
+fn siphash(message: &[u8], c_rounds: usize, d_rounds: usize, wide: bool) -> (u64, u64) {
+    let mut v = [
+        0x736f_6d65_7073_6575,
+        0x646f_7261_6e64_6f6d,
+        0x6c79_6765_6e65_7261,
+        0x7465_6462_7974_6573,
+    ];
+    if wide {
+        v[1] ^= 0xee;
+    }
+
+    let mut blocks = message.chunks_exact(8);
+    for block in &mut blocks {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(block);
+        let m = u64::from_le_bytes(bytes);
+        v[3] ^= m;
+        sip_rounds(&mut v, c_rounds);
+        v[0] ^= m;
+    }
+    let mut last = [0; 8];
+    last[..blocks.remainder().len()].copy_from_slice(blocks.remainder());
+    let m = ((message.len() as u64 & 0xff) << 56) | u64::from_le_bytes(last);
+    v[3] ^= m;
+    sip_rounds(&mut v, c_rounds);
+    v[0] ^= m;
+
+    v[2] ^= if wide { 0xee } else { 0xff };
+    sip_rounds(&mut v, d_rounds);
+    let first = v[0] ^ v[1] ^ v[2] ^ v[3];
+    if !wide {
+        return (first, 0);
+    }
+    v[1] ^= 0xdd;
+    sip_rounds(&mut v, d_rounds);
+    (first, v[0] ^ v[1] ^ v[2] ^ v[3])
+}
+
+/// the keys and values of a cargo config as "table.key" = "value", we have no toml parser so this only
+/// knows about simple tables, dotted keys and inline tables which is what cargo configs consist of
+pub(crate) fn config_values(config: &str) -> Vec<(String, String)> {
+    let mut values = Vec::new();
+    let mut table = String::new();
+    for line in config.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.starts_with('[') {
+            table = line.trim_matches(|c| c == '[' || c == ']').replace('"', "");
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim().replace('"', ""), value.trim()),
+            None => continue,
+        };
+        let key = if table.is_empty() {
+            key
+        } else {
+            format!("{}.{}", table, key)
+        };
+        // my-registry = { index = "https://..." }
+        match value.strip_prefix('{').and_then(|v| v.strip_suffix('}')) {
+            Some(inline_table) => values.extend(
+                inline_table
+                    .split(',')
+                    .filter_map(|pair| pair.split_once('='))
+                    .map(|(inner_key, inner_value)| {
+                        (
+                            format!("{}.{}", key, inner_key.trim().replace('"', "")),
+                            inner_value.trim().trim_matches('"').to_string(),
+                        )
+                    }),
+            ),
+            None => values.push((key, value.trim_matches('"').to_string())),
+        }
+    }
+    values
+}
+
+/// the registries of a cargo config: `[registries.<name>] index = ...` and the
+/// replacement sources `[source.<name>] registry = ...`
+fn configured_registries(config: &str) -> Vec<ConfiguredRegistry> {
+    config_values(config)
+        .iter()
+        .filter_map(|(key, url)| {
+            let name = key
+                .strip_prefix("registries.")
+                .and_then(|key| key.strip_suffix(".index"))
+                .or_else(|| {
+                    key.strip_prefix("source.")
+                        .and_then(|key| key.strip_suffix(".registry"))
+                })?;
+            Some(ConfiguredRegistry::new(name, url))
+        })
+        .collect()
+}
+
+/// the cargo config files that apply in `dir`, most specific first
+pub(crate) fn config_files(dir: &Path, cargo_home: &Path) -> Vec<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(".cargo"))
+        .chain(std::iter::once(cargo_home.to_path_buf()))
+        .flat_map(|dir| vec![dir.join("config.toml"), dir.join("config")])
+        .filter(|file| file.is_file())
+        .collect()
+}
+
+/// a file of an index like "config.json" or "se/rd/serde", sparse indices (and the git indices of old
+/// cargo versions) have it on disk, git indices are bare repos that cargo does not check out
+pub(crate) fn read_index_file(index_dir: &Path, path: &str) -> Option<Vec<u8>> {
+    fs::read(index_dir.join(path)).ok().or_else(|| {
+        let repo = git2::Repository::open(index_dir).ok()?;
+        let blob = repo
+            .revparse_single(&format!("refs/remotes/origin/HEAD:{}", path))
+            .or_else(|_| repo.revparse_single(&format!("FETCH_HEAD:{}", path)))
+            .ok()?
+            .peel_to_blob()
+            .ok()?;
+        Some(blob.content().to_vec())
+    })
+}
+
+/// the string value of the first `key` in a json object, we only need a few fields of the index
+/// and don't have a json parser
+pub(crate) fn json_string(json: &str, key: &str) -> Option<String> {
+    let (_, after_key) = json.split_once(&format!("\"{}\"", key))?;
+    let value = after_key.trim_start().strip_prefix(':')?.trim_start();
+    let value = value.strip_prefix('"')?;
+    Some(value[..value.find('"')?].to_string())
+}
+
+/// the path of the index file of a crate: "1/a", "3/f/foo", "se/rd/serde"
+fn index_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", &name[..1], name),
+        _ => format!("{}/{}/{}", &name[..2], &name[2..4], name),
+    }
+}
+
+/// the json lines of the versions in cargo's .cache of an index file:
+/// cache version (u8), index version (u32), the etag of the file and then version and json line, all 0-terminated
+fn index_cache_entries(cache: &[u8]) -> Vec<String> {
+    cache
+        .get(5..)
+        .unwrap_or_default()
+        .split(|byte| *byte == 0)
+        // skip the etag, then every other entry is a version followed by its json line
+        .skip(2)
+        .step_by(2)
+        .map(|json| String::from_utf8_lossy(json).into_owned())
+        .collect()
+}
+
+/// version => sha256 of the .crate of all the versions of a crate in an index
+pub(crate) fn index_checksums(index_dir: &Path, name: &str) -> HashMap<String, String> {
+    let path = index_path(name);
+    // cargo keeps a .cache for git and sparse indices, the index file itself is one json line per version
+    let entries = read_index_file(index_dir, &format!(".cache/{}", path))
+        .map(|cache| index_cache_entries(&cache))
+        .or_else(|| {
+            read_index_file(index_dir, &path).map(|file| {
+                String::from_utf8_lossy(&file)
+                    .lines()
+                    .map(String::from)
+                    .collect()
+            })
+        })
+        .unwrap_or_default();
+
+    entries
+        .iter()
+        .filter_map(|json| Some((json_string(json, "vers")?, json_string(json, "cksum")?)))
+        .collect()
+}
+
+/// the sha256 of a .crate archive as hex, like the "cksum" in the index
+pub(crate) fn archive_checksum(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let _ = io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// the url that crates of a registry are downloaded from, according to the config.json of its index
+pub(crate) fn download_url(index_dir: &Path) -> Option<String> {
+    let config_json = read_index_file(index_dir, "config.json")?;
+    // {"dl": "https://crates.io/api/v1/crates", "api": "https://crates.io"}
+    json_string(&String::from_utf8_lossy(&config_json), "dl")
+}
+
+/// all the registries cargo knows about in the current directory
+#[derive(Debug, Clone)]
+pub(crate) struct Registries {
+    configured: Vec<ConfiguredRegistry>,
+    /// `[source.<name>] replace-with = "<replacement>"`
+    replacements: HashMap<String, String>,
+}
+
+impl Registries {
+    /// the registries from the cargo configs that apply in the current directory and the environment
+    pub(crate) fn load(cargo_home: &Path) -> Self {
+        Self::load_in(&env::current_dir().unwrap_or_default(), cargo_home)
+    }
+
+    /// the registries from the cargo configs that apply in `dir` and the environment
+    pub(crate) fn load_in(dir: &Path, cargo_home: &Path) -> Self {
+        let configs: Vec<String> = config_files(dir, cargo_home)
+            .iter()
+            .filter_map(|file| fs::read_to_string(file).ok())
+            .collect();
+        let mut registries = Self::from_configs(&configs);
+        // CARGO_REGISTRIES_MY_REGISTRY_INDEX, we can't know whether the name contained - or _
+        registries
+            .configured
+            .extend(env::vars().filter_map(|(key, url)| {
+                let name = key
+                    .strip_prefix("CARGO_REGISTRIES_")?
+                    .strip_suffix("_INDEX")?
+                    .to_lowercase()
+                    .replace('_', "-");
+                Some(ConfiguredRegistry::new(&name, &url))
+            }));
+        registries
+    }
+
+    pub(crate) fn from_configs(configs: &[String]) -> Self {
+        let mut configured = vec![
+            ConfiguredRegistry::new(CRATES_IO_NAME, CRATES_IO_SPARSE_INDEX),
+            ConfiguredRegistry::new(CRATES_IO_NAME, CRATES_IO_GIT_INDEX),
+        ];
+        configured.extend(
+            configs
+                .iter()
+                .flat_map(|config| configured_registries(config)),
+        );
+        // the most specific config comes first and wins
+        let mut replacements = HashMap::new();
+        for (key, replacement) in configs
+            .iter()
+            .rev()
+            .flat_map(|config| config_values(config))
+        {
+            if let Some(name) = key
+                .strip_prefix("source.")
+                .and_then(|key| key.strip_suffix(".replace-with"))
+            {
+                let _ = replacements.insert(name.to_string(), replacement);
+            }
+        }
+        Self {
+            configured,
+            replacements,
+        }
+    }
+
+    /// the configured registry a directory like "index.crates.io-1949cf8c6b5b557f" belongs to
+    pub(crate) fn lookup(&self, directory_name: &str) -> Option<&ConfiguredRegistry> {
+        self.configured.iter().find(|registry| {
+            registry
+                .directory_names()
+                .iter()
+                .any(|name| name == directory_name)
+        })
+    }
+
+    /// the configured registry of a package id source like `registry+https://github.com/rust-lang/crates.io-index`
+    /// or `sparse+https://index.crates.io/`
+    pub(crate) fn by_source(&self, source: &str) -> Option<&ConfiguredRegistry> {
+        let index = normalize_url(source.strip_prefix("registry+").unwrap_or(source));
+        self.configured
+            .iter()
+            .find(|registry| registry.index == index)
+    }
+
+    /// the directory names cargo uses for the packages of a package id source, `None` if we don't know the registry.
+    /// Cargo.lock has the url of the git index for crates.io packages even if they come from the sparse index,
+    /// so these are the directories of every index configured under the name of the registry of the source
+    /// (or of its replacement)
+    pub(crate) fn directory_names(&self, source: &str) -> Option<Vec<String>> {
+        let mut name = &self.by_source(source)?.name;
+        // a replacement can be replaced again, don't loop forever on a cycle
+        for _ in 0..self.replacements.len() {
+            match self.replacements.get(name) {
+                Some(replacement) => name = replacement,
+                None => break,
+            }
+        }
+        if !self
+            .configured
+            .iter()
+            .any(|registry| &registry.name == name)
+        {
+            // replaced by a directory or something we don't know
+            return None;
+        }
+        Some(
+            self.configured
+                .iter()
+                .filter(|registry| &registry.name == name)
+                .flat_map(|registry| registry.directory_names().to_vec())
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod registriestests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn registries_directory_names() {
+        let crates_io_git = ConfiguredRegistry::new(CRATES_IO_NAME, CRATES_IO_GIT_INDEX);
+        assert_eq!(
+            crates_io_git.directory_names(),
+            [
+                "github.com-1ecc6299db9ec823",
+                // cargo >= 1.85
+                "github.com-25cdd57fae9f0462"
+            ]
+        );
+        let crates_io_sparse = ConfiguredRegistry::new(CRATES_IO_NAME, CRATES_IO_SPARSE_INDEX);
+        assert_eq!(
+            crates_io_sparse.directory_names(),
+            [
+                "index.crates.io-6f17d22bba15001f",
+                "index.crates.io-1949cf8c6b5b557f"
+            ]
+        );
+        assert_eq!(
+            ConfiguredRegistry::new("my", "sparse+https://user@Example.com:8080").host(),
+            "example.com"
+        );
+    }
+
+    #[test]
+    fn registries_from_config() {
+        let config = r#"
+[registries]
+inline = { index = "https://inline.example.com/git/index", token = "secret" }
+
+[registries.my-registry]
+index = "sparse+https://my.example.com/index/" # the mirror
+
+[source.crates-io]
+replace-with = "vendored"
+
+[source.vendored]
+registry = "https://vendored.example.com"
+
+[net]
+git-fetch-with-cli = true
+"#;
+        let registries = Registries::from_configs(&[config.to_string()]);
+        let names: Vec<(&str, &str)> = registries
+            .configured
+            .iter()
+            .map(|registry| (registry.name.as_str(), registry.index.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("crates-io", "sparse+https://index.crates.io/"),
+                ("crates-io", "https://github.com/rust-lang/crates.io-index"),
+                ("inline", "https://inline.example.com/git/index"),
+                ("my-registry", "sparse+https://my.example.com/index/"),
+                ("vendored", "https://vendored.example.com/"),
+            ]
+        );
+
+        assert_eq!(
+            registries
+                .lookup("index.crates.io-1949cf8c6b5b557f")
+                .map(|registry| registry.name.as_str()),
+            Some("crates-io")
+        );
+        let vendored = &registries.configured[4];
+        assert_eq!(
+            registries.lookup(&vendored.directory_names()[1]),
+            Some(vendored)
+        );
+        assert_eq!(registries.lookup("dl.cloudsmith.io-b1ad4b6d4b0d5d2b"), None);
+        assert!(registries.lookup("github.com-1ecc6299db9ec823").is_some());
+
+        // crates.io is replaced by the vendored registry
+        let crates_io = "registry+https://github.com/rust-lang/crates.io-index";
+        assert_eq!(
+            registries.directory_names(crates_io),
+            Some(vendored.directory_names().to_vec())
+        );
+        assert_eq!(
+            Registries::from_configs(&[]).directory_names(crates_io),
+            Some(vec![
+                "index.crates.io-6f17d22bba15001f".to_string(),
+                "index.crates.io-1949cf8c6b5b557f".to_string(),
+                "github.com-1ecc6299db9ec823".to_string(),
+                "github.com-25cdd57fae9f0462".to_string(),
+            ])
+        );
+        assert_eq!(
+            registries.directory_names("sparse+https://my.example.com/index/"),
+            Some(registries.configured[3].directory_names().to_vec())
+        );
+        assert_eq!(
+            registries.directory_names("sparse+https://unknown.example.com/"),
+            None
+        );
+    }
+
+    #[test]
+    fn registries_index_cache() {
+        assert_eq!(index_path("a"), "1/a");
+        assert_eq!(index_path("cc"), "2/cc");
+        assert_eq!(index_path("Syn"), "3/s/syn");
+        assert_eq!(index_path("serde_json"), "se/rd/serde_json");
+
+        let mut cache = vec![3, 2, 0, 0, 0];
+        cache.extend_from_slice(b"\"etag\"\0");
+        cache.extend_from_slice(
+            b"1.0.0\0{\"name\":\"foo\",\"vers\":\"1.0.0\",\"deps\":[],\"cksum\":\"abc\"}\0",
+        );
+        cache.extend_from_slice(
+            b"1.0.1\0{\"name\": \"foo\", \"vers\": \"1.0.1\", \"deps\": [{\"name\": \"bar\", \"req\": \"^1\"}], \"cksum\": \"def\"}\0",
+        );
+        let entries = index_cache_entries(&cache);
+        assert_eq!(entries.len(), 2);
+        let checksums: Vec<(Option<String>, Option<String>)> = entries
+            .iter()
+            .map(|json| (json_string(json, "vers"), json_string(json, "cksum")))
+            .collect();
+        assert_eq!(
+            checksums,
+            vec![
+                (Some(String::from("1.0.0")), Some(String::from("abc"))),
+                (Some(String::from("1.0.1")), Some(String::from("def")))
+            ]
+        );
+    }
+}
@@ -7,13 +7,21 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::cache::caches::{Cache, RegistrySuperCache};
+use crate::audit;
+use crate::cache::caches::{get_cache_name, Cache, RegistrySubCache, RegistrySuperCache};
 use crate::cache::*;
+use crate::commands::trim::get_last_access_of_item;
 use crate::library::*;
+use crate::registries::Registries;
+use crate::trash;
 
+use cargo_metadata::Version;
 use humansize::{file_size_opts, FileSize};
 
 /// dry run message setting
@@ -24,10 +32,12 @@ pub(crate) enum DryRunMessage<'a> {
     None, // no message
 }
 
-fn parse_version(path: &Path) -> Result<(String, String), Error> {
+pub(crate) fn parse_version(path: &Path) -> Result<(String, String), Error> {
+    // use the file name and strip the ".crate" extension manually:
+    // file_stem() would turn the source dir "foo-1.0.0" into "foo-1.0"
     #[allow(clippy::single_match_else)]
-    let filename = match path.file_stem() {
-        Some(name) => name.to_str().unwrap().to_string(),
+    let filename = match path.file_name().and_then(std::ffi::OsStr::to_str) {
+        Some(name) => name.strip_suffix(".crate").unwrap_or(name).to_string(),
         None => {
             return Err(Error::MalformedPackageName(path.display().to_string()));
         }
@@ -61,98 +71,272 @@ fn parse_version(path: &Path) -> Result<(String, String), Error> {
     Ok((name, version))
 }
 
+/// which versions of a crate compete with each other when deciding which ones to keep
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RetentionScope {
+    Crate, // keep the newest N versions of each crate
+    Major, // keep the newest N versions of each semver compatible series (1.x, 0.3.x, 0.0.4)
+}
+
+// the semver compatible series a version belongs to, same rules as cargo uses for caret requirements
+fn semver_series(version: &Version) -> String {
+    if version.major > 0 {
+        version.major.to_string()
+    } else if version.minor > 0 {
+        format!("0.{}", version.minor)
+    } else {
+        format!("0.0.{}", version.patch)
+    }
+}
+
+/// out of a list of .crate archives or extracted source directories, select all but the
+/// `amount_to_keep` newest versions (according to semver, including pre-releases) of each crate
+/// in each registry
+fn outdated_crate_versions(
+    paths: &[PathBuf],
+    amount_to_keep: u64,
+    scope: RetentionScope,
+) -> Result<Vec<&PathBuf>, Error> {
+    // (registry, crate name, series) => [(version, path), ...]
+    #[allow(clippy::type_complexity)]
+    let mut groups: BTreeMap<(&Path, String, String), Vec<(Option<Version>, &PathBuf)>> =
+        BTreeMap::new();
+
+    for path in paths {
+        let (name, version) = parse_version(path)?;
+        // versions that are not valid semver are treated as the oldest ones
+        let version = Version::parse(&version).ok();
+        let series = match (scope, &version) {
+            (RetentionScope::Major, Some(version)) => semver_series(version),
+            _ => String::new(),
+        };
+        let registry = path.parent().unwrap_or_else(|| Path::new(""));
+        groups
+            .entry((registry, name, series))
+            .or_default()
+            .push((version, path));
+    }
+
+    let amount_to_keep = usize::try_from(amount_to_keep).unwrap_or(usize::MAX);
+    let mut outdated = Vec::new();
+    for versions in groups.values_mut() {
+        // sort from newest to oldest
+        versions.sort();
+        versions.reverse();
+        outdated.extend(versions.iter().skip(amount_to_keep).map(|(_, path)| *path));
+    }
+    outdated.sort();
+    Ok(outdated)
+}
+
+fn rm_crate_versions(paths: &[&PathBuf], dry_run: bool, size_changed: &mut bool) -> u64 {
+    let mut removed_size = 0;
+    for path in paths {
+        let (pkgname, pkgver) = parse_version(path).unwrap();
+        let size = size_of_path(path);
+        removed_size += size;
+
+        let dryrun_msg = format!(
+            "dry run: not actually deleting {} {} at {}",
+            pkgname,
+            pkgver,
+            path.display()
+        );
+        remove_file(
+            path,
+            dry_run,
+            size_changed,
+            None,
+            &DryRunMessage::Custom(&dryrun_msg),
+            Some(size),
+        );
+    }
+    removed_size
+}
+
+/// remove all but the `amount_to_keep` newest versions of each crate, both from the .crate
+/// archives and the extracted sources
 pub(crate) fn rm_old_crates(
     amount_to_keep: u64,
+    scope: RetentionScope,
     dry_run: bool,
-    registry_src_path: &Path,
+    registry_pkg_cache: &mut registry_pkg_cache::RegistryPkgCaches,
+    registry_sources_cache: &mut registry_sources::RegistrySourceCaches,
     size_changed: &mut bool,
 ) -> Result<(), Error> {
     println!();
 
-    // remove crate sources from cache
-    // src can be completely removed since we can always rebuilt it from cache (by extracting packages)
-    let mut removed_size = 0;
-    // walk registry repos
-    for repo in fs::read_dir(&registry_src_path).unwrap() {
-        let mut crate_list = fs::read_dir(&repo.unwrap().path())
-            .unwrap()
-            .map(|cratepath| cratepath.unwrap().path())
-            .collect::<Vec<PathBuf>>();
-        crate_list.sort();
-        crate_list.reverse();
-
-        let mut versions_of_this_package = 0;
-        let mut last_pkgname = String::new();
-
-        // iterate over all crates and extract name and version
-        for pkgpath in &crate_list {
-            let (pkgname, pkgver) = parse_version(pkgpath)?;
-
-            if amount_to_keep == 0 {
-                removed_size += fs::metadata(pkgpath)
-                    .unwrap_or_else(|_| {
-                        panic!("Failed to get metadata of file '{}'", &pkgpath.display())
-                    })
-                    .len();
+    let archives = registry_pkg_cache.items().to_vec();
+    let outdated_archives = outdated_crate_versions(&archives, amount_to_keep, scope)?;
+    let sources = registry_sources_cache.items().to_vec();
+    let outdated_sources = outdated_crate_versions(&sources, amount_to_keep, scope)?;
 
-                let dryrun_msg = format!(
-                    "dry run: not actually deleting {} {} at {}",
-                    pkgname,
-                    pkgver,
-                    pkgpath.display()
-                );
-                remove_file(
-                    pkgpath,
-                    dry_run,
-                    size_changed,
-                    None,
-                    &DryRunMessage::Custom(&dryrun_msg),
-                    None,
-                );
+    let removed_archives = rm_crate_versions(&outdated_archives, dry_run, size_changed);
+    let removed_sources = rm_crate_versions(&outdated_sources, dry_run, size_changed);
 
-                continue;
-            }
-            // println!("pkgname: {:?}, pkgver: {:?}", pkgname, pkgver);
-
-            if last_pkgname == pkgname {
-                versions_of_this_package += 1;
-                if versions_of_this_package == amount_to_keep {
-                    // we have seen this package too many times, queue for deletion
-                    removed_size += fs::metadata(pkgpath)
-                        .unwrap_or_else(|_| {
-                            panic!("Failed to get metadata of file '{}'", &pkgpath.display())
-                        })
-                        .len();
-
-                    let dryrun_msg = format!(
-                        "dry run: not actually deleting {} {} at {}",
-                        pkgname,
-                        pkgver,
-                        pkgpath.display()
-                    );
-                    remove_file(
-                        pkgpath,
-                        dry_run,
-                        size_changed,
-                        None,
-                        &DryRunMessage::Custom(&dryrun_msg),
-                        None,
-                    );
-                }
-            } else {
-                // last_pkgname != pkgname, we got to a new package, reset counter
-                versions_of_this_package = 0;
-                last_pkgname = pkgname;
-            } // if last_pkgname == pkgname
-        } // for pkgpath in &crate_list
-    }
     println!(
         "Removed {} of compressed crate sources.",
-        removed_size.file_size(file_size_opts::DECIMAL).unwrap()
+        removed_archives.file_size(file_size_opts::DECIMAL).unwrap()
+    );
+    println!(
+        "Removed {} of extracted crate sources.",
+        removed_sources.file_size(file_size_opts::DECIMAL).unwrap()
     );
     Ok(())
 }
 
+/// how the revisions of a git checkout are ranked when deciding which ones to keep
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RevisionOrder {
+    Accessed,  // most recently used revisions first
+    Committed, // revisions with the most recent commit date first
+}
+
+// get the commit date of the revision that is checked out at the path
+// returns None if the checkout is not a valid git repository
+fn commit_time_of_checkout(path: &Path) -> Option<SystemTime> {
+    let repo = git2::Repository::open(path).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    let seconds = u64::try_from(commit.time().seconds()).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+fn revision_timestamp(path: &Path, order: RevisionOrder) -> SystemTime {
+    match order {
+        RevisionOrder::Accessed => get_last_access_of_item(path),
+        // fall back to the access time if we can't read the commit
+        RevisionOrder::Committed => {
+            commit_time_of_checkout(path).unwrap_or_else(|| get_last_access_of_item(path))
+        }
+    }
+}
+
+/// keep only the `amount_to_keep` newest revisions of each repo in git/checkouts
+/// the bare repos in git/db are not touched so removed revisions can be checked out again
+pub(crate) fn rm_old_git_checkouts(
+    amount_to_keep: u64,
+    order: RevisionOrder,
+    dry_run: bool,
+    checkouts_cache: &mut git_checkouts::GitCheckoutCache,
+    size_changed: &mut bool,
+) {
+    println!();
+
+    let amount_to_keep = usize::try_from(amount_to_keep).unwrap_or(usize::MAX);
+    let mut removed_size = 0;
+
+    for (repo, mut revisions) in checkouts_cache.revisions_by_repo() {
+        // sort from newest to oldest
+        revisions.sort_by_cached_key(|revision| revision_timestamp(revision, order));
+        revisions.reverse();
+
+        for revision in revisions.iter().skip(amount_to_keep) {
+            let size = size_of_path(revision);
+            removed_size += size;
+
+            let dryrun_msg = format!(
+                "dry run: not actually deleting {} {} at {}",
+                repo.file_name().unwrap().to_str().unwrap(),
+                revision.file_name().unwrap().to_str().unwrap(),
+                revision.display()
+            );
+            remove_file(
+                revision,
+                dry_run,
+                size_changed,
+                None,
+                &DryRunMessage::Custom(&dryrun_msg),
+                Some(size),
+            );
+        }
+    }
+    println!(
+        "{} {} of git checkouts.",
+        if dry_run {
+            "dry-run: would remove"
+        } else {
+            "Removed"
+        },
+        removed_size.file_size(file_size_opts::DECIMAL).unwrap()
+    );
+}
+
+// the directories of the registries of a cache
+fn registry_paths<T>(caches: &mut T) -> Vec<PathBuf>
+where
+    T: RegistrySuperCache,
+    T::SubCache: RegistrySubCache,
+{
+    caches
+        .caches()
+        .iter()
+        .map(|cache| cache.path().clone())
+        .collect()
+}
+
+/// the paths of the registries, git repos or crate versions a --remove-dir selector refers to
+fn paths_of_selector(
+    selector: &Selector,
+    ccd: &CargoCachePaths,
+    registries: &Registries,
+    bare_repos_cache: &mut git_bare_repos::GitRepoCache,
+    registry_index_caches: &mut registry_index::RegistryIndicesCache,
+    registry_pkgs_cache: &mut registry_pkg_cache::RegistryPkgCaches,
+    registry_sources_caches: &mut registry_sources::RegistrySourceCaches,
+) -> Vec<PathBuf> {
+    match selector {
+        Selector::Item(component, name) => {
+            // match "github.com-1ecc6299db9ec823", "github.com" and the configured name "crates-io"
+            let selected = |paths: Vec<PathBuf>| -> Vec<PathBuf> {
+                paths
+                    .into_iter()
+                    .filter(|dir| {
+                        let dir_name = dir.file_name().and_then(|dir_name| dir_name.to_str());
+                        dir_name.map_or(false, |dir_name| {
+                            dir_name == name
+                                || registries
+                                    .lookup(dir_name)
+                                    .map_or(false, |registry| &registry.name == name)
+                        }) || registry_matches(&get_cache_name(dir), name)
+                    })
+                    .collect()
+            };
+
+            match component {
+                Component::RegistryCrateCache => {
+                    let mut paths = selected(registry_paths(registry_pkgs_cache));
+                    paths.extend(selected(registry_paths(registry_sources_caches)));
+                    paths
+                }
+                Component::RegistrySources => selected(registry_paths(registry_sources_caches)),
+                Component::RegistryIndex => selected(registry_paths(registry_index_caches)),
+                Component::GitDB => {
+                    let mut paths = selected(bare_repos_cache.items().to_vec());
+                    paths.extend(selected(read_dir_paths(&ccd.git_checkouts)));
+                    paths
+                }
+                Component::GitRepos => selected(read_dir_paths(&ccd.git_checkouts)),
+            }
+        }
+        Selector::Crate(name, version_req) => {
+            let mut items = registry_pkgs_cache.files();
+            items.extend_from_slice(registry_sources_caches.items());
+            items
+                .into_iter()
+                .filter(|item| {
+                    parse_version(item).map_or(false, |(crate_name, version)| {
+                        crate_name == *name
+                            && version_req.as_ref().map_or(true, |req| {
+                                Version::parse(&version)
+                                    .map_or(false, |version| req.matches(&version))
+                            })
+                    })
+                })
+                .collect()
+        }
+    }
+}
+
 /// take a list of cache items via cmdline and remove them, invalidate caches too
 #[allow(clippy::too_many_arguments)]
 pub(crate) fn remove_dir_via_cmdline(
@@ -168,7 +352,46 @@ pub(crate) fn remove_dir_via_cmdline(
 ) -> Result<(), Error> {
     // @TODO the passing of the cache is really a mess here... :(
 
-    let dirs_to_remove = components_from_groups(directory)?;
+    let (dirs_to_remove, selectors) = selectors_from_groups(directory)?;
+
+    // resolve all selectors before removing anything so that a typo doesn't leave us half done
+    let registries = Registries::load(&ccd.cargo_home);
+    let mut selected_paths = Vec::new();
+    for (term, selector) in &selectors {
+        let paths = paths_of_selector(
+            selector,
+            ccd,
+            &registries,
+            bare_repos_cache,
+            registry_index_caches,
+            registry_pkgs_cache,
+            registry_sources_caches,
+        );
+        if paths.is_empty() {
+            return Err(Error::RemoveDirNoMatch(term.clone()));
+        }
+        selected_paths.extend(paths);
+    }
+    // items inside of a selected registry or a component that is removed completely go with it
+    let component_dirs: Vec<&PathBuf> = dirs_to_remove
+        .iter()
+        .map(|component| match component {
+            Component::GitDB => &ccd.git_repos_bare,
+            Component::GitRepos => &ccd.git_checkouts,
+            Component::RegistrySources => &ccd.registry_sources,
+            Component::RegistryCrateCache => &ccd.registry_pkg_cache,
+            Component::RegistryIndex => &ccd.registry_index,
+        })
+        .collect();
+    selected_paths.sort();
+    selected_paths.dedup();
+    let all_selected = selected_paths.clone();
+    selected_paths.retain(|path| {
+        !component_dirs.iter().any(|dir| path.starts_with(dir))
+            && !all_selected
+                .iter()
+                .any(|other| other != path && path.starts_with(other))
+    });
 
     let mut size_removed: u64 = 0;
 
@@ -176,6 +399,19 @@ pub(crate) fn remove_dir_via_cmdline(
         println!(); // newline
     }
 
+    for path in &selected_paths {
+        let size = size_of_path(path);
+        size_removed += size;
+        remove_with_default_message(path, dry_run, size_changed, Some(size));
+    }
+    if !dry_run && !selected_paths.is_empty() {
+        registry_pkgs_cache.invalidate();
+        registry_sources_caches.invalidate();
+        registry_index_caches.invalidate();
+        bare_repos_cache.invalidate();
+        checkouts_cache.invalidate();
+    }
+
     for component in dirs_to_remove {
         match component {
             Component::RegistryCrateCache => {
@@ -313,10 +549,41 @@ pub(crate) fn remove_file(
             println!("{}", msg);
         }
 
-        if path.is_file() && fs::remove_file(&path).is_err() {
+        let cargo_home = home::cargo_home().ok();
+        // only items inside the CARGO_HOME go into the audit log
+        let audit_log = cargo_home.as_ref().filter(|home| path.starts_with(home));
+        // the size has to be taken before the item is gone
+        let size = match (audit_log, total_size_from_cache) {
+            (Some(_), None) if path.exists() => size_of_path(path),
+            (_, size) => size.unwrap_or(0),
+        };
+
+        if trash::enabled() {
+            // keep the item if we can't move it, the user asked for a way back
+            match cargo_home
+                .as_ref()
+                .ok_or(Error::GetCargoHomeFailed)
+                .and_then(|home| trash::move_to_trash(home, path))
+            {
+                Ok(()) => {
+                    *size_changed = true;
+                    if let Some(home) = audit_log {
+                        audit::record(home, path, audit::Action::Trash, size);
+                    }
+                }
+                Err(error) => eprintln!("Warning: {}", error),
+            }
+            return;
+        }
+
+        let mut removed = false;
+        // remove symlinks themselves, even if they point to a directory or nowhere
+        let is_file = fs::symlink_metadata(path).map_or(false, |metadata| !metadata.is_dir());
+        if is_file && fs::remove_file(&path).is_err() {
             eprintln!("Warning: failed to remove file \"{}\".", path.display());
         } else {
             *size_changed = true;
+            removed = is_file;
         }
 
         if path.is_dir() {
@@ -328,8 +595,14 @@ pub(crate) fn remove_file(
                 eprintln!("error: {:?}", error);
             } else {
                 *size_changed = true;
+                removed = true;
             }
         }
+        inventory::rescan(path);
+
+        if let (true, Some(home)) = (removed, audit_log) {
+            audit::record(home, path, audit::Action::Remove, size);
+        }
     }
 }
 
@@ -353,4 +626,107 @@ mod libtests {
         assert_eq!(name2, "cargo-cache");
         assert_eq!(version2, "0.4.3");
     }
+
+    #[test]
+    fn test_parse_version_of_source_dir() {
+        let (name, version): (String, String) = parse_version(&PathBuf::from(
+            "registry/src/github.com-1ecc6299db9ec823/foo-1.0.0",
+        ))
+        .unwrap();
+
+        assert_eq!(name, "foo");
+        assert_eq!(version, "1.0.0");
+    }
+
+    #[test]
+    fn test_outdated_crate_versions() {
+        let paths: Vec<PathBuf> = [
+            "reg/foo-0.9.0.crate",
+            "reg/foo-0.10.0.crate",
+            "reg/foo-1.0.0-beta.1.crate",
+            "reg/foo-1.0.0.crate",
+            "reg/foo-1.2.0.crate",
+            "reg/foo-bar-0.1.0.crate",
+            "other/foo-0.1.0.crate",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+
+        // semver ordering: 0.10.0 is newer than 0.9.0, the pre-release is older than 1.0.0
+        let per_crate = outdated_crate_versions(&paths, 2, RetentionScope::Crate).unwrap();
+        assert_eq!(
+            per_crate,
+            vec![
+                &PathBuf::from("reg/foo-0.10.0.crate"),
+                &PathBuf::from("reg/foo-0.9.0.crate"),
+                &PathBuf::from("reg/foo-1.0.0-beta.1.crate"),
+            ]
+        );
+
+        // keep the newest version of each series: 0.9, 0.10 and 1.x
+        let per_major = outdated_crate_versions(&paths, 1, RetentionScope::Major).unwrap();
+        assert_eq!(
+            per_major,
+            vec![
+                &PathBuf::from("reg/foo-1.0.0-beta.1.crate"),
+                &PathBuf::from("reg/foo-1.0.0.crate"),
+            ]
+        );
+
+        // 0 removes everything
+        let everything = outdated_crate_versions(&paths, 0, RetentionScope::Crate).unwrap();
+        assert_eq!(everything.len(), paths.len());
+    }
+
+    #[test]
+    fn test_rm_old_git_checkouts() {
+        // create a fake checkouts dir with three revisions of the same repo
+        let checkouts = PathBuf::from("target/keep_git_revisions_test/checkouts");
+        let repo = checkouts.join("foo-e7ff1db891893a9e");
+        let _ = fs::remove_dir_all("target/keep_git_revisions_test");
+
+        for (revision, seconds) in &[("aaaaaaa", 3000), ("bbbbbbb", 1000), ("ccccccc", 2000)] {
+            let path = repo.join(revision);
+            fs::create_dir_all(&path).unwrap();
+            let git_repo = git2::Repository::init(&path).unwrap();
+            let time = git2::Time::new(*seconds, 0);
+            let sig = git2::Signature::new("test", "test@example.com", &time).unwrap();
+            let tree_id = git_repo.index().unwrap().write_tree().unwrap();
+            let tree = git_repo.find_tree(tree_id).unwrap();
+            let _ = git_repo
+                .commit(Some("HEAD"), &sig, &sig, "msg", &tree, &[])
+                .unwrap();
+        }
+
+        let mut cache = git_checkouts::GitCheckoutCache::new(checkouts);
+        let mut size_changed = false;
+
+        // dry run does not remove anything
+        rm_old_git_checkouts(
+            1,
+            RevisionOrder::Committed,
+            true,
+            &mut cache,
+            &mut size_changed,
+        );
+        assert!(!size_changed);
+        cache.invalidate();
+        assert_eq!(cache.items().len(), 3);
+
+        rm_old_git_checkouts(
+            2,
+            RevisionOrder::Committed,
+            false,
+            &mut cache,
+            &mut size_changed,
+        );
+        assert!(size_changed);
+        cache.invalidate();
+        // the revision with the oldest commit is gone
+        assert_eq!(
+            cache.items_sorted(),
+            &[repo.join("aaaaaaa"), repo.join("ccccccc")]
+        );
+    }
 }
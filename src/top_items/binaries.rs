@@ -7,11 +7,11 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fs;
 use std::path::Path;
 
 use crate::cache::caches::Cache;
 use crate::cache::*;
+use crate::library::size_of_file;
 use crate::tables::format_table;
 use crate::top_items::common::*;
 
@@ -27,9 +27,7 @@ struct BinInfo {
 impl BinInfo {
     fn new(path: &Path) -> Self {
         let name = path.file_name().unwrap().to_str().unwrap().to_string();
-        let size = fs::metadata(&path)
-            .unwrap_or_else(|_| panic!("Failed to get metadata of file '{}'", &path.display()))
-            .len();
+        let size = size_of_file(path);
         Self { name, size }
     }
 
@@ -0,0 +1,224 @@
+// Copyright 2017-2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// top items grouped by crate: all versions of a crate, archives and extracted sources, in one row
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::cache::caches::RegistrySuperCache;
+use crate::cache::*;
+use crate::commands::trim::get_last_access_of_item;
+use crate::library::size_of_path;
+use crate::remove::parse_version;
+use crate::tables::format_table;
+use crate::top_items::common::dir_exists;
+
+use cargo_metadata::Version;
+use chrono::prelude::*;
+use humansize::{file_size_opts, FileSize};
+use rayon::prelude::*;
+
+/// a single version of a crate, either a .crate archive or an extracted source directory
+#[derive(Debug, Clone)]
+pub(crate) struct CrateItem {
+    name: String,
+    version: String,
+    size: u64,
+    last_use: SystemTime,
+}
+
+impl CrateItem {
+    fn new(path: &Path) -> Self {
+        let (name, version) = parse_version(path).unwrap_or_default();
+        Self {
+            name,
+            version,
+            size: size_of_path(path),
+            last_use: get_last_access_of_item(path),
+        }
+    }
+}
+
+/// summary of all cached versions of a crate
+#[derive(Debug)]
+pub(crate) struct CrateInfo {
+    name: String,
+    versions: usize,
+    total_size: u64, // sort by this
+    newest: String,
+    oldest: String,
+    last_use: SystemTime,
+}
+
+// sort versions by semver, fall back to lexicographic order if a version can't be parsed
+fn version_key(version: &str) -> (Option<Version>, String) {
+    (Version::parse(version).ok(), version.to_string())
+}
+
+fn crate_infos_from_items(items: Vec<CrateItem>) -> Vec<CrateInfo> {
+    let mut crates: BTreeMap<String, Vec<CrateItem>> = BTreeMap::new();
+    for item in items {
+        crates.entry(item.name.clone()).or_default().push(item);
+    }
+
+    crates
+        .into_iter()
+        .map(|(name, mut crate_items)| {
+            crate_items.sort_by_cached_key(|item| version_key(&item.version));
+            let mut versions = crate_items
+                .iter()
+                .map(|item| item.version.as_str())
+                .collect::<Vec<_>>();
+            // archive and source of the same version count as one version
+            versions.dedup();
+
+            CrateInfo {
+                versions: versions.len(),
+                total_size: crate_items.iter().map(|item| item.size).sum(),
+                newest: (*versions.last().unwrap()).to_string(),
+                oldest: (*versions.first().unwrap()).to_string(),
+                last_use: crate_items.iter().map(|item| item.last_use).max().unwrap(),
+                name,
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn crate_list_to_string(limit: u32, mut collections_vec: Vec<CrateInfo>) -> String {
+    if collections_vec.is_empty() {
+        return String::new();
+    }
+
+    // sort in reverse, biggest item first
+    collections_vec.par_sort_by_key(|info| info.total_size);
+    collections_vec.reverse();
+    let mut table_matrix: Vec<Vec<String>> = Vec::with_capacity(collections_vec.len() + 1);
+
+    table_matrix.push(vec![
+        String::from("Name"),
+        String::from("Versions"),
+        String::from("Newest"),
+        String::from("Oldest"),
+        String::from("Last use"),
+        String::from("Total"),
+    ]);
+
+    for info in collections_vec.into_iter().take(limit as usize) {
+        let last_use = DateTime::<Local>::from(info.last_use)
+            .format("%Y-%m-%d")
+            .to_string();
+        let total_size = info.total_size.file_size(file_size_opts::DECIMAL).unwrap();
+
+        table_matrix.push(vec![
+            info.name,
+            info.versions.to_string(),
+            info.newest,
+            info.oldest,
+            last_use,
+            total_size,
+        ]);
+    }
+    format_table(&table_matrix, 0)
+}
+
+// registry cache and registry sources, grouped by crate
+pub(crate) fn crate_stats(
+    registry_pkg_cache_path: &Path,
+    registry_sources_path: &Path,
+    limit: u32,
+    registry_pkg_caches: &mut registry_pkg_cache::RegistryPkgCaches,
+    registry_sources_caches: &mut registry_sources::RegistrySourceCaches,
+) -> String {
+    // don't crash if neither of the directories exists (issue #9)
+    if !registry_pkg_cache_path.exists() && !dir_exists(registry_sources_path) {
+        return String::new();
+    }
+
+    let mut stdout = format!(
+        "\nSummary of: crates in {} and {} ({} total)\n",
+        registry_pkg_cache_path.display(),
+        registry_sources_path.display(),
+        (registry_pkg_caches.total_size() + registry_sources_caches.total_size())
+            .file_size(file_size_opts::DECIMAL)
+            .unwrap()
+    );
+
+    let mut paths: Vec<PathBuf> = registry_pkg_caches.items().to_vec();
+    paths.extend_from_slice(registry_sources_caches.items());
+
+    let items = paths
+        .par_iter()
+        .map(|path| CrateItem::new(path))
+        .collect::<Vec<CrateItem>>();
+    let summary = crate_infos_from_items(items);
+    stdout.push_str(&crate_list_to_string(limit, summary));
+
+    stdout
+}
+
+#[cfg(test)]
+mod top_crates_grouped {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn item(name: &str, version: &str, size: u64, last_use: SystemTime) -> CrateItem {
+        CrateItem {
+            name: name.to_string(),
+            version: version.to_string(),
+            size,
+            last_use,
+        }
+    }
+
+    #[test]
+    fn crate_infos_none() {
+        let infos = crate_infos_from_items(Vec::new());
+        assert_eq!(crate_list_to_string(4, infos), String::new());
+    }
+
+    #[test]
+    fn crate_infos_grouped() {
+        let old = SystemTime::from(Local.ymd(2019, 3, 14).and_hms(12, 0, 0));
+        let new = SystemTime::from(Local.ymd(2020, 6, 15).and_hms(12, 0, 0));
+
+        let items = vec![
+            // archive and source of foo 0.9.0
+            item("foo", "0.9.0", 10, old),
+            item("foo", "0.9.0", 40, old),
+            item("foo", "0.10.0", 20, new),
+            item("foo", "0.10.0-beta.1", 5, old),
+            item("bar", "1.0.0", 7, old),
+            item("foo-bar", "2.0.0", 100, old),
+        ];
+
+        let infos = crate_infos_from_items(items);
+        let is = crate_list_to_string(10, infos);
+
+        let mut wanted = String::new();
+        for i in &[
+            "Name    Versions Newest Oldest Last use   Total\n",
+            "foo-bar 1        2.0.0  2.0.0  2019-03-14 100 B\n",
+            "foo     3        0.10.0 0.9.0  2020-06-15 75 B\n",
+            "bar     1        1.0.0  1.0.0  2019-03-14 7 B\n",
+        ] {
+            wanted.push_str(i);
+        }
+        assert_eq!(is, wanted);
+
+        // limit the number of rows
+        let two_crates =
+            crate_infos_from_items(vec![item("a", "1.0.0", 1, old), item("b", "1.0.0", 2, old)]);
+        assert_eq!(
+            crate_list_to_string(1, two_crates),
+            "Name Versions Newest Oldest Last use   Total\nb    1        1.0.0  1.0.0  2019-03-14 2 B\n"
+        );
+    }
+}
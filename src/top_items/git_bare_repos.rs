@@ -7,17 +7,16 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fs;
 use std::path::{Path, PathBuf};
 
 use crate::cache::caches::Cache;
 use crate::cache::*;
+use crate::library::{size_of_file, size_of_path};
 use crate::tables::format_table;
 use crate::top_items::common::{dir_exists, FileDesc, Pair};
 
 use humansize::{file_size_opts, FileSize};
 use rayon::prelude::*;
-use walkdir::WalkDir;
 
 #[inline]
 fn name_from_path(path: &Path) -> String {
@@ -33,21 +32,7 @@ fn name_from_path(path: &Path) -> String {
 impl FileDesc {
     fn new_from_git_bare(path: &Path) -> Self {
         let name = name_from_path(path);
-        let walkdir = WalkDir::new(path.display().to_string());
-        let size = walkdir
-            .into_iter()
-            .map(|e| e.unwrap().path().to_owned())
-            .filter(|f| f.exists())
-            .collect::<Vec<_>>()
-            .par_iter()
-            .map(|f| {
-                fs::metadata(f)
-                    .unwrap_or_else(|_| {
-                        panic!("Failed to get metadata of file '{}'", &path.display())
-                    })
-                    .len()
-            })
-            .sum();
+        let size = size_of_path(path);
 
         Self {
             path: path.into(),
@@ -77,9 +62,7 @@ impl RepoInfo {
             let mut tmp_name = name_tmp.split('-').collect::<Vec<_>>();
             let _ = tmp_name.pop(); // remove the hash
             name = tmp_name.join("-"); // rejoin with "-"
-            size = fs::metadata(&path)
-                .unwrap_or_else(|_| panic!("Failed to get metadata of file '{}'", &path.display()))
-                .len();
+            size = size_of_file(path);
         } else {
             // tests
             name = path.file_name().unwrap().to_str().unwrap().to_string();
@@ -8,16 +8,15 @@
 // except according to those terms.
 
 use crate::cache::*;
-use std::fs;
 use std::path::{Path, PathBuf};
 
 use crate::cache::caches::Cache;
+use crate::library::{size_of_file, size_of_path};
 use crate::tables::format_table;
 use crate::top_items::common::{dir_exists, FileDesc, Pair};
 
 use humansize::{file_size_opts, FileSize};
 use rayon::prelude::*;
-use walkdir::WalkDir;
 
 #[inline]
 fn name_from_path(path: &Path) -> String {
@@ -42,22 +41,7 @@ impl FileDesc {
     fn new_from_git_checkouts(path: &Path) -> Self {
         let name = name_from_path(path);
 
-        let walkdir = WalkDir::new(path.display().to_string());
-
-        let size = walkdir
-            .into_iter()
-            .map(|e| e.unwrap().path().to_owned())
-            .filter(|f| f.exists())
-            .collect::<Vec<_>>()
-            .par_iter()
-            .map(|f| {
-                fs::metadata(f)
-                    .unwrap_or_else(|_| {
-                        panic!("Failed to get metadata of file '{}'", &path.display())
-                    })
-                    .len()
-            })
-            .sum();
+        let size = size_of_path(path);
 
         Self {
             name,
@@ -83,9 +67,7 @@ impl ChkInfo {
         let name: String;
         let size: u64;
         if path.exists() {
-            size = fs::metadata(&path)
-                .unwrap_or_else(|_| panic!("Failed to get metadata of file '{}'", &path.display()))
-                .len();
+            size = size_of_file(path);
             let mut p = path.to_path_buf();
             let _ = p.pop();
             let name_tmp = p.file_name().unwrap().to_str().unwrap().to_string();
@@ -9,6 +9,7 @@
 
 pub(crate) mod binaries;
 pub(crate) mod common;
+pub(crate) mod crates;
 pub(crate) mod git_bare_repos;
 pub(crate) mod git_checkouts;
 pub(crate) mod registry_pkg_cache;
@@ -7,11 +7,11 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fs;
 use std::path::{Path, PathBuf};
 
 use crate::cache::caches::RegistrySuperCache;
 use crate::cache::registry_pkg_cache;
+use crate::library::size_of_file;
 use crate::tables::format_table;
 use crate::top_items::common::{dir_exists, FileDesc, Pair};
 
@@ -32,9 +32,7 @@ fn name_from_path(path: &Path) -> String {
 impl FileDesc {
     pub(crate) fn new_from_reg_cache(path: &Path) -> Self {
         let name = name_from_path(path);
-        let size = fs::metadata(&path)
-            .unwrap_or_else(|_| panic!("Failed to get metadata of file '{}'", &path.display()))
-            .len();
+        let size = size_of_file(path);
 
         Self {
             path: path.into(),
@@ -58,9 +56,7 @@ impl RgchInfo {
         let name: String;
         let size: u64;
         if path.exists() {
-            size = fs::metadata(&path)
-                .unwrap_or_else(|_| panic!("Failed to get metadata of file '{}'", &path.display()))
-                .len();
+            size = size_of_file(path);
             let n = path.file_name().unwrap().to_str().unwrap().to_string();
             let mut v = n.split('-').collect::<Vec<_>>();
             let _ = v.pop();
@@ -7,17 +7,16 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fs;
 use std::path::{Path, PathBuf};
 
 use crate::cache::caches::RegistrySuperCache;
 use crate::cache::*;
+use crate::library::{size_of_file, size_of_path};
 use crate::tables::format_table;
 use crate::top_items::common::{dir_exists, FileDesc, Pair};
 
 use humansize::{file_size_opts, FileSize};
 use rayon::prelude::*;
-use walkdir::WalkDir;
 
 #[inline]
 fn name_from_path(path: &Path) -> String {
@@ -34,22 +33,7 @@ fn name_from_path(path: &Path) -> String {
 impl FileDesc {
     pub(crate) fn new_from_reg_src(path: &Path) -> Self {
         let name = name_from_path(path);
-        let walkdir = WalkDir::new(path.display().to_string());
-
-        let size = walkdir
-            .into_iter()
-            .map(|e| e.unwrap().path().to_owned())
-            .filter(|f| f.exists())
-            .collect::<Vec<_>>()
-            .par_iter()
-            .map(|f| {
-                fs::metadata(f)
-                    .unwrap_or_else(|_| {
-                        panic!("Failed to get metadata of file '{}'", &path.display())
-                    })
-                    .len()
-            })
-            .sum();
+        let size = size_of_path(path);
 
         Self {
             name,
@@ -73,9 +57,7 @@ impl RgSrcInfo {
         let name: String;
         let size: u64;
         if path.exists() {
-            size = fs::metadata(&path)
-                .unwrap_or_else(|_| panic!("Failed to get metadata of file '{}'", &path.display()))
-                .len();
+            size = size_of_file(path);
             let n = path.file_name().unwrap().to_str().unwrap().to_string();
             let mut v = n.split('-').collect::<Vec<_>>();
             let _ = v.pop();
@@ -10,6 +10,7 @@
 use crate::cache::*;
 use crate::library::CargoCachePaths;
 use crate::top_items::binaries::*;
+use crate::top_items::crates::*;
 use crate::top_items::git_bare_repos::*;
 use crate::top_items::git_checkouts::*;
 use crate::top_items::registry_pkg_cache::*;
@@ -18,6 +19,7 @@ use crate::top_items::registry_sources::*;
 #[allow(clippy::complexity)]
 pub(crate) fn get_top_crates(
     limit: u32,
+    group_by_crate: bool,
     ccd: &CargoCachePaths,
     bin_cache: &mut bin::BinaryCache,
     checkouts_cache: &mut git_checkouts::GitCheckoutCache,
@@ -32,14 +34,28 @@ pub(crate) fn get_top_crates(
     let mut binaries = String::new();
 
     rayon::scope(|s| {
-        s.spawn(|_| {
-            reg_src = registry_source_stats(&ccd.registry_sources, limit, registry_sources_caches);
-        });
+        if group_by_crate {
+            // one table for archives and sources, reg_cache stays empty
+            s.spawn(|_| {
+                reg_src = crate_stats(
+                    &ccd.registry_pkg_cache,
+                    &ccd.registry_sources,
+                    limit,
+                    registry_pkg_caches,
+                    registry_sources_caches,
+                );
+            });
+        } else {
+            s.spawn(|_| {
+                reg_src =
+                    registry_source_stats(&ccd.registry_sources, limit, registry_sources_caches);
+            });
 
-        s.spawn(|_| {
-            reg_cache =
-                registry_pkg_cache_stats(&ccd.registry_pkg_cache, limit, registry_pkg_caches);
-        });
+            s.spawn(|_| {
+                reg_cache =
+                    registry_pkg_cache_stats(&ccd.registry_pkg_cache, limit, registry_pkg_caches);
+            });
+        }
 
         s.spawn(|_| {
             bare_repos = git_repos_bare_stats(&ccd.git_repos_bare, limit, bare_repos_cache);
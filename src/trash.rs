@@ -0,0 +1,285 @@
+// Copyright 2017-2020 Matthias Krüger. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// With --trash, remove_file() does not delete anything but moves it into
+// $CARGO_HOME/.cargo-cache-trash/<run>/, keeping the path relative to the CARGO_HOME.
+// "cargo cache undo" moves the items of the last run back, "cargo cache empty-trash" deletes runs.
+// remove_file() is called from a lot of places, so instead of passing the trash setting through
+// all of them, it is enabled once for the whole process.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::Duration;
+
+use crate::cache::inventory;
+use crate::library::{size_of_path, CargoCachePaths, Error};
+
+use chrono::prelude::*;
+use humansize::{file_size_opts, FileSize};
+
+static TRASH_ENABLED: AtomicBool = AtomicBool::new(false);
+// unix timestamp of the start of this run, used to name the trash directory of the run
+static RUN_STARTED: AtomicI64 = AtomicI64::new(0);
+
+const RUN_TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// move removed items into the trash instead of deleting them for the rest of this run
+pub(crate) fn enable() {
+    RUN_STARTED.store(Utc::now().timestamp(), Ordering::SeqCst);
+    TRASH_ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub(crate) fn enabled() -> bool {
+    TRASH_ENABLED.load(Ordering::SeqCst)
+}
+
+/// the directory that holds the trash of all runs
+pub(crate) fn trash_dir(cargo_home: &Path) -> PathBuf {
+    cargo_home.join(".cargo-cache-trash")
+}
+
+// trash directory of the current run: 20201231T235959Z-1234 (time and pid)
+fn run_dir(cargo_home: &Path) -> PathBuf {
+    let started = Utc.timestamp(RUN_STARTED.load(Ordering::SeqCst), 0);
+    trash_dir(cargo_home).join(format!(
+        "{}-{}",
+        started.format(RUN_TIME_FORMAT),
+        std::process::id()
+    ))
+}
+
+// when was the trash run created
+fn run_started(run: &Path) -> Option<DateTime<Utc>> {
+    let name = run.file_name()?.to_str()?;
+    let time = name.split('-').next()?;
+    NaiveDateTime::parse_from_str(time, RUN_TIME_FORMAT)
+        .ok()
+        .map(|naive| DateTime::<Utc>::from_utc(naive, Utc))
+}
+
+/// where `path` inside `cargo_home` goes in the trash of the current run
+pub(crate) fn trash_path(cargo_home: &Path, path: &Path) -> Result<PathBuf, Error> {
+    let relative = path
+        .strip_prefix(cargo_home)
+        .map_err(|_| Error::TrashOutsideCargoHome(path.to_path_buf()))?;
+    Ok(run_dir(cargo_home).join(relative))
+}
+
+/// move a file or directory inside `cargo_home` into the trash of the current run without updating
+/// the inventory, for callers that move many items and rescan once afterwards
+pub(crate) fn move_to_trash_unscanned(cargo_home: &Path, path: &Path) -> Result<PathBuf, Error> {
+    let target = trash_path(cargo_home, path)?;
+    fs::create_dir_all(target.parent().unwrap())
+        .and_then(|()| fs::rename(path, &target))
+        .map_err(|e| Error::TrashMoveFailed(path.to_path_buf(), e))?;
+    Ok(target)
+}
+
+/// move a file or directory inside `cargo_home` into the trash of the current run
+pub(crate) fn move_to_trash(cargo_home: &Path, path: &Path) -> Result<(), Error> {
+    let target = move_to_trash_unscanned(cargo_home, path)?;
+    inventory::rescan(path);
+    inventory::rescan(&target);
+    Ok(())
+}
+
+/// all trash runs, oldest first
+pub(crate) fn runs(cargo_home: &Path) -> Vec<PathBuf> {
+    let mut runs = fs::read_dir(trash_dir(cargo_home))
+        .map(|dir| {
+            dir.filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .filter(|path| path.is_dir())
+                .collect::<Vec<PathBuf>>()
+        })
+        .unwrap_or_default();
+    runs.sort();
+    runs
+}
+
+/// total size of the trash
+pub(crate) fn trash_size(cargo_home: &Path) -> u64 {
+    let trash = trash_dir(cargo_home);
+    if trash.is_dir() {
+        size_of_path(&trash)
+    } else {
+        0
+    }
+}
+
+// don't leave an empty trash behind, fails harmlessly if there are still runs in it
+fn remove_trash_dir_if_empty(cargo_home: &Path) {
+    let _ = fs::remove_dir(trash_dir(cargo_home));
+}
+
+// move "from" back to "to", merge directories that have been recreated in the meantime
+// returns whether everything could be restored
+fn restore(from: &Path, to: &Path) -> Result<bool, Error> {
+    if !to.exists() {
+        fs::create_dir_all(to.parent().unwrap())
+            .and_then(|()| fs::rename(from, to))
+            .map_err(|e| Error::TrashMoveFailed(from.to_path_buf(), e))?;
+        Ok(true)
+    } else if from.is_dir() && to.is_dir() {
+        let mut restored_all = true;
+        for entry in fs::read_dir(from).map_err(|e| Error::TrashMoveFailed(from.into(), e))? {
+            let entry = entry.map_err(|e| Error::TrashMoveFailed(from.into(), e))?;
+            restored_all &= restore(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        if restored_all {
+            let _ = fs::remove_dir(from);
+        }
+        Ok(restored_all)
+    } else {
+        eprintln!(
+            "Warning: not restoring \"{}\", \"{}\" already exists.",
+            from.display(),
+            to.display()
+        );
+        Ok(false)
+    }
+}
+
+/// move everything of the last trash run back to where it was
+pub(crate) fn undo(ccd: &CargoCachePaths, size_changed: &mut bool) -> Result<(), Error> {
+    let cargo_home = &ccd.cargo_home;
+    let run = runs(cargo_home).pop().ok_or(Error::TrashEmpty)?;
+
+    println!("Restoring items removed at {}", run_label(&run));
+
+    let restored_all = restore(&run, cargo_home)?;
+    inventory::rescan(cargo_home);
+    *size_changed = true;
+    remove_trash_dir_if_empty(cargo_home);
+
+    if !restored_all {
+        eprintln!(
+            "Warning: some items could not be restored and are still in \"{}\".",
+            run.display()
+        );
+    }
+    Ok(())
+}
+
+// "2020-12-31 23:59:59" in local time, or the directory name if it does not contain a date
+fn run_label(run: &Path) -> String {
+    run_started(run).map_or_else(
+        || run.file_name().unwrap().to_string_lossy().to_string(),
+        |started| {
+            DateTime::<Local>::from(started)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        },
+    )
+}
+
+/// remove trash runs permanently, all of them or only those older than `older_than`
+pub(crate) fn empty_trash(
+    ccd: &CargoCachePaths,
+    older_than: Option<Duration>,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let now = Utc::now();
+    let mut removed_size = 0;
+
+    for run in runs(&ccd.cargo_home) {
+        if let Some(older_than) = older_than {
+            let age = run_started(&run)
+                .and_then(|started| (now - started).to_std().ok())
+                .unwrap_or_default();
+            if age < older_than {
+                continue;
+            }
+        }
+
+        let size = size_of_path(&run);
+        removed_size += size;
+        if dry_run {
+            println!(
+                "dry-run: would remove items removed at {} ({})",
+                run_label(&run),
+                size.file_size(file_size_opts::DECIMAL).unwrap()
+            );
+        } else {
+            remove_dir_all::remove_dir_all(&run)
+                .map_err(|e| Error::TrashMoveFailed(run.clone(), e))?;
+            inventory::rescan(&run);
+        }
+    }
+    remove_trash_dir_if_empty(&ccd.cargo_home);
+
+    println!(
+        "{} {} from the trash.",
+        if dry_run { "Would remove" } else { "Removed" },
+        removed_size.file_size(file_size_opts::DECIMAL).unwrap()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod trash_tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn trash_move_and_restore() {
+        let cargo_home = PathBuf::from("target/trash_test")
+            .canonicalize()
+            .unwrap_or_else(|_| {
+                fs::create_dir_all("target/trash_test").unwrap();
+                PathBuf::from("target/trash_test").canonicalize().unwrap()
+            });
+        let _ = fs::remove_dir_all(trash_dir(&cargo_home));
+        let src = cargo_home.join("registry/src/github.com-1ecc6299db9ec823");
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(src.join("foo-1.0.0")).unwrap();
+        fs::write(src.join("foo-1.0.0/lib.rs"), "fn foo() {}").unwrap();
+        fs::create_dir_all(src.join("bar-1.0.0")).unwrap();
+
+        move_to_trash(&cargo_home, &src.join("foo-1.0.0")).unwrap();
+        move_to_trash(&cargo_home, &src.join("bar-1.0.0")).unwrap();
+        assert!(!src.join("foo-1.0.0").exists());
+        assert!(move_to_trash(&cargo_home, Path::new("/outside/of/cargo/home")).is_err());
+
+        let trash_runs = runs(&cargo_home);
+        assert_eq!(trash_runs.len(), 1);
+        assert!(run_started(&trash_runs[0]).is_some());
+        assert!(trash_runs[0]
+            .join("registry/src/github.com-1ecc6299db9ec823/foo-1.0.0/lib.rs")
+            .is_file());
+
+        // cargo extracted bar again in the meantime, it must not be overwritten
+        fs::create_dir_all(src.join("bar-1.0.0")).unwrap();
+        fs::write(src.join("bar-1.0.0/lib.rs"), "fn bar() {}").unwrap();
+
+        let restored_all = restore(&trash_runs[0], &cargo_home).unwrap();
+        // the empty bar-1.0.0 directory in the trash was merged into the new one
+        assert!(restored_all);
+        assert_eq!(
+            fs::read_to_string(src.join("foo-1.0.0/lib.rs")).unwrap(),
+            "fn foo() {}"
+        );
+        assert_eq!(
+            fs::read_to_string(src.join("bar-1.0.0/lib.rs")).unwrap(),
+            "fn bar() {}"
+        );
+        assert!(runs(&cargo_home).is_empty());
+    }
+
+    #[test]
+    fn trash_run_started() {
+        let run = PathBuf::from(".cargo-cache-trash/20201231T235959Z-1234");
+        assert_eq!(
+            run_started(&run),
+            Some(Utc.ymd(2020, 12, 31).and_hms(23, 59, 59))
+        );
+        assert_eq!(run_started(&PathBuf::from("foo")), None);
+    }
+}